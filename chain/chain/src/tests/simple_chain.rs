@@ -8,6 +8,7 @@ use near_primitives::time::{Clock, MockClockGuard};
 use near_primitives::version::PROTOCOL_VERSION;
 use num_rational::Rational;
 use std::str::FromStr;
+use std::time::Duration;
 
 #[test]
 fn empty_chain() {
@@ -33,10 +34,12 @@ fn empty_chain() {
 #[test]
 fn build_chain() {
     init_test_logger();
-    let _mock_clock_guard = MockClockGuard::default();
-    for i in 0..5 {
-        Clock::add_utc(chrono::Utc.ymd(2020, 10, 1).and_hms_milli(0, 0, 3, 444 + i));
-    }
+    let mock_clock_guard = MockClockGuard::default();
+    mock_clock_guard.add_utc_sequence(
+        chrono::Utc.ymd(2020, 10, 1).and_hms_milli(0, 0, 3, 444),
+        Duration::from_millis(1),
+        5,
+    );
 
     let (mut chain, _, signer) = setup();
 
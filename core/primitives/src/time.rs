@@ -5,32 +5,92 @@ use chrono;
 pub use chrono::Utc;
 pub use std::time::{Duration, Instant};
 
-use chrono::DateTime;
-use std::cell::RefCell;
-use std::collections::VecDeque;
+use chrono::{DateTime, TimeZone};
+use once_cell::sync::Lazy;
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Mock clock state, either of one thread or the one shared by the threads spawned
+/// with `Clock::spawn`, see `MockClockGuard::shared`.
 struct MockClockPerThread {
     utc: VecDeque<DateTime<Utc>>,
+    /// Returned by `Clock::utc()` once `utc` runs out of samples.
+    current_utc: Option<DateTime<Utc>>,
     durations: VecDeque<Duration>,
+    /// Returned by `Clock::instant()` once `durations` runs out of samples.
+    current_instant: Option<Instant>,
+    /// Last value returned by `Clock::instant()`.
+    last_instant: Option<Instant>,
+    drift: Option<Drift>,
+    /// Pause windows registered with `Clock::mark_pause` while mocked.
+    pauses: Vec<(Instant, Instant)>,
+    mark: Option<Mark>,
     utc_call_count: u64,
     instant_call_count: u64,
     instant: Instant,
     is_mock: bool,
 }
 
+/// Makes the mocked UTC time follow the mocked instants at a skewed rate, see
+/// `MockClockGuard::set_drift`.
+struct Drift {
+    rate: f64,
+    since_instant: Instant,
+    since_utc: DateTime<Utc>,
+}
+
+/// Longest span between the instant of `MockClockGuard::mark` and the
+/// instants returned since, see `MockClockGuard::max_span_since_mark`.
+struct Mark {
+    start: Instant,
+    max_span: Duration,
+}
+
 pub struct Clock {}
 
 impl MockClockPerThread {
     pub fn reset(&mut self) {
         self.utc.clear();
+        self.current_utc = None;
         self.durations.clear();
+        self.current_instant = None;
+        self.last_instant = None;
+        self.drift = None;
+        self.pauses.clear();
+        self.mark = None;
         self.utc_call_count = 0;
         self.instant_call_count = 0;
         self.instant = Instant::now();
         self.is_mock = false;
     }
 
+    /// Calls `f` with the mock clock used by this thread: its thread-local one
+    /// if it is mocked, otherwise the shared one if that is mocked and this
+    /// thread uses it (see `MockClockGuard::shared`), otherwise the (not
+    /// mocked) thread-local one.
     fn with<F, T>(f: F) -> T
+    where
+        F: FnOnce(&mut MockClockPerThread) -> T,
+    {
+        Self::with_local(|local| {
+            if local.is_mock || !Self::uses_shared() {
+                return f(local);
+            }
+            let mut shared = Self::lock_shared();
+            if shared.is_mock {
+                f(&mut shared)
+            } else {
+                f(local)
+            }
+        })
+    }
+
+    fn with_local<F, T>(f: F) -> T
     where
         F: FnOnce(&mut MockClockPerThread) -> T,
     {
@@ -40,17 +100,118 @@ impl MockClockPerThread {
         INSTANCE.with(|it| f(&mut *it.borrow_mut()))
     }
 
+    fn lock_shared() -> MutexGuard<'static, MockClockPerThread> {
+        SHARED_MOCK.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Whether this thread uses the shared mock, which requires it to have
+    /// joined the one currently active.
+    fn uses_shared() -> bool {
+        let joined = JOINED_SHARED_MOCK.with(Cell::get);
+        joined != 0 && joined == SHARED_MOCK_ACTIVE.load(Ordering::Acquire)
+    }
+
     fn pop_utc(&mut self) -> Option<DateTime<chrono::Utc>> {
         self.utc_call_count += 1;
-        self.utc.pop_front()
+        self.utc.pop_front().or_else(|| self.unqueued_utc())
+    }
+
+    /// Returns what the next `pop_utc` will return, without consuming it.
+    fn peek_utc(&self) -> Option<DateTime<Utc>> {
+        self.utc.front().copied().or_else(|| self.unqueued_utc())
+    }
+
+    /// The UTC time returned when no samples are queued.
+    fn unqueued_utc(&self) -> Option<DateTime<Utc>> {
+        let drift = match &self.drift {
+            Some(drift) => drift,
+            None => return self.current_utc,
+        };
+        let elapsed = self
+            .last_instant
+            .unwrap_or(self.instant)
+            .saturating_duration_since(drift.since_instant);
+        Some(utc_saturating_add(drift.since_utc, elapsed.mul_f64(1.0 + drift.rate)))
+    }
+
+    /// Returns what the next `pop_instant` will return, without consuming it.
+    fn peek_instant(&self) -> Option<Instant> {
+        match self.durations.front() {
+            Some(t) => self.instant.checked_add(*t),
+            None => self.current_instant,
+        }
     }
+
     fn pop_instant(&mut self) -> Option<Instant> {
         self.instant_call_count += 1;
         let x = self.durations.pop_front();
-        match x {
+        let instant = match x {
             Some(t) => self.instant.checked_add(t),
-            None => None,
+            None => self.current_instant,
+        };
+        self.last_instant = instant.or(self.last_instant);
+        if let (Some(mark), Some(instant)) = (&mut self.mark, instant) {
+            mark.max_span = mark.max_span.max(instant.saturating_duration_since(mark.start));
+        }
+        instant
+    }
+}
+
+impl MockClockPerThread {
+    /// Multi-line summary of the state, see `MockClockGuard::debug_dump`.
+    /// Instants are shown relative to the start of the mock.
+    fn describe(&self) -> String {
+        let since_start = |instant: Instant| instant.saturating_duration_since(self.instant);
+        let mut out = String::new();
+        let utc_mode = match (self.utc.is_empty(), &self.drift, self.current_utc) {
+            (false, _, _) => "queue",
+            (true, Some(_), _) => "drift",
+            (true, None, Some(_)) => "frozen",
+            (true, None, None) => "exhausted",
+        };
+        let _ = write!(
+            out,
+            "utc: mode {}, {} samples left, read {} times",
+            utc_mode,
+            self.utc.len(),
+            self.utc_call_count
+        );
+        if let Some(next) = self.peek_utc() {
+            let _ = write!(out, ", next {}", next.to_rfc3339());
+        }
+        if let Some(drift) = &self.drift {
+            let _ = write!(out, ", drifting at rate {}", drift.rate);
+        }
+        let instant_mode = match (self.durations.is_empty(), self.current_instant) {
+            (false, _) => "queue",
+            (true, Some(_)) => "frozen",
+            (true, None) => "exhausted",
+        };
+        let _ = write!(
+            out,
+            "\ninstant: mode {}, {} samples left, read {} times",
+            instant_mode,
+            self.durations.len(),
+            self.instant_call_count
+        );
+        if let Some(next) = self.peek_instant() {
+            let _ = write!(out, ", next start + {:?}", since_start(next));
+        }
+        if let Some(last) = self.last_instant {
+            let _ = write!(out, ", last returned start + {:?}", since_start(last));
         }
+        if let Some(mark) = &self.mark {
+            let _ = write!(
+                out,
+                "\nmark: at start + {:?}, max span {:?}",
+                since_start(mark.start),
+                mark.max_span
+            );
+        }
+        if !self.pauses.is_empty() {
+            let _ = write!(out, "\npauses: {}", self.pauses.len());
+        }
+        out
     }
 }
 
@@ -58,7 +219,13 @@ impl Default for MockClockPerThread {
     fn default() -> Self {
         Self {
             utc: VecDeque::with_capacity(16),
+            current_utc: None,
             durations: VecDeque::with_capacity(16),
+            current_instant: None,
+            last_instant: None,
+            drift: None,
+            pauses: Vec::new(),
+            mark: None,
             utc_call_count: 0,
             instant_call_count: 0,
             instant: Instant::now(),
@@ -67,29 +234,238 @@ impl Default for MockClockPerThread {
     }
 }
 
-pub struct MockClockGuard {}
+/// Mock clock used by the threads which joined it, see `MockClockGuard::shared`.
+static SHARED_MOCK: Lazy<Mutex<MockClockPerThread>> = Lazy::new(Default::default);
+/// Number of the shared mock currently active, zero if `SHARED_MOCK` isn't
+/// mocked, so that the real clock doesn't need to lock it.
+static SHARED_MOCK_ACTIVE: AtomicU64 = AtomicU64::new(0);
+/// Number of the last shared mock created. Every shared mock gets a new one,
+/// so that threads which joined an earlier one don't use later ones.
+static SHARED_MOCK_COUNT: AtomicU64 = AtomicU64::new(0);
+thread_local! {
+    /// Number of the shared mock this thread uses while it is active, see
+    /// `Clock::spawn`.
+    static JOINED_SHARED_MOCK: Cell<u64> = Cell::new(0);
+}
+/// Pause windows registered with `Clock::mark_pause` while not mocked.
+static PAUSES: Lazy<Mutex<Vec<(Instant, Instant)>>> = Lazy::new(Default::default);
+/// Held by the shared `MockClockGuard`, so that only one test at a time mocks the shared clock.
+static SHARED_MOCK_OWNER: Lazy<Mutex<()>> = Lazy::new(Default::default);
+
+pub struct MockClockGuard {
+    strict: bool,
+    shared: Option<MutexGuard<'static, ()>>,
+}
 
 impl Default for MockClockGuard {
     fn default() -> Self {
         Clock::set_mock();
-        Self {}
+        Self { strict: false, shared: None }
+    }
+}
+
+impl MockClockGuard {
+    /// Like `default`, but the guard panics on drop if some of the queued mock
+    /// samples were never read, i.e. the code under test read the time fewer
+    /// times than the test expected.
+    pub fn strict() -> Self {
+        Clock::set_mock();
+        Self { strict: true, shared: None }
+    }
+
+    /// Mocks the clock shared by the current thread and the threads spawned
+    /// from it with `Clock::spawn`, transitively, e.g. for tests whose code
+    /// under test reads the clock on threads it spawns itself. Samples are
+    /// added with the usual functions, from any of these threads.
+    ///
+    /// This is not a process-global mock: other threads, such as those of
+    /// tests running in parallel or those spawned with `std::thread::spawn`,
+    /// including by tokio's multi-threaded runtime, keep the real clock. A
+    /// thread-local mock takes precedence: threads (including the current one)
+    /// with their own `MockClockGuard` keep using it. Only one shared guard can
+    /// exist at a time: creating another one blocks until the first one is
+    /// dropped.
+    pub fn shared() -> Self {
+        let owner = SHARED_MOCK_OWNER.lock().unwrap_or_else(PoisonError::into_inner);
+        let number = SHARED_MOCK_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+        MockClockPerThread::lock_shared().is_mock = true;
+        SHARED_MOCK_ACTIVE.store(number, Ordering::Release);
+        JOINED_SHARED_MOCK.with(|joined| joined.set(number));
+        Self { strict: false, shared: Some(owner) }
+    }
+
+    /// Mocks the clock of the current thread so that time never moves:
+    /// `Clock::utc()` always returns `utc` and `Clock::instant()` always returns
+    /// `instant`, unless samples are queued on top.
+    pub fn freeze_at(utc: DateTime<Utc>, instant: Instant) -> Self {
+        let guard = Self::default();
+        MockClockPerThread::with_local(|clock| {
+            clock.current_utc = Some(utc);
+            clock.current_instant = Some(instant);
+        });
+        guard
+    }
+
+    /// Makes `Clock::utc()` return `utc` whenever there are no queued samples.
+    ///
+    /// Unlike `Instant`, UTC time may move backwards (e.g. after an NTP
+    /// correction), so `utc` may be earlier than previously returned values;
+    /// this lets tests exercise clock skew handling.
+    pub fn set_utc(&self, utc: DateTime<Utc>) {
+        MockClockPerThread::with(|clock| clock.current_utc = Some(utc));
+    }
+
+    /// Makes the UTC time drift relative to the instants: from now on, when
+    /// there are no queued samples, `Clock::utc()` returns the current UTC
+    /// time (see `set_utc`) advanced by `(1 + rate)` times the amount the last
+    /// returned `Clock::instant()` moved since this call. E.g. a `rate` of
+    /// 0.01 gains 10ms per second. Panics if no UTC time is set.
+    pub fn set_drift(&self, rate: f64) {
+        assert!(rate > -1.0);
+        MockClockPerThread::with(|clock| {
+            let since_utc = clock.peek_utc().expect("set_drift needs the UTC time to drift from");
+            let since_instant = clock.last_instant.unwrap_or(clock.instant);
+            clock.drift = Some(Drift { rate, since_instant, since_utc });
+        });
+    }
+
+    /// Makes `Clock::instant()` return `instant` whenever there are no queued
+    /// samples, see `set_utc`. Like real instants, `instant` should not be
+    /// earlier than previously returned ones.
+    pub fn set_instant(&self, instant: Instant) {
+        MockClockPerThread::with(|clock| clock.current_instant = Some(instant));
+    }
+
+    /// Returns the value the next `Clock::utc()` will return, without
+    /// consuming it, or `None` if that call would run out of samples.
+    pub fn current_utc(&self) -> Option<DateTime<Utc>> {
+        MockClockPerThread::with(|clock| clock.peek_utc())
+    }
+
+    /// Returns the value the next `Clock::instant()` will return, see `current_utc`.
+    pub fn current_instant(&self) -> Option<Instant> {
+        MockClockPerThread::with(|clock| clock.peek_instant())
+    }
+
+    /// Starts measuring how far `Clock::instant()` moves from the last instant
+    /// it returned (or from the start of the mock if it wasn't read yet), see
+    /// `max_span_since_mark`. Marking again restarts the measurement.
+    pub fn mark(&self) {
+        MockClockPerThread::with(|clock| {
+            let start = clock.last_instant.unwrap_or(clock.instant);
+            clock.mark = Some(Mark { start, max_span: Duration::ZERO });
+        });
+    }
+
+    /// Returns the largest span between the instant of the last `mark` and the
+    /// instants `Clock::instant()` returned since, e.g. to assert that an
+    /// operation reading the clock as it progresses met its deadline. Panics
+    /// if `mark` wasn't called.
+    pub fn max_span_since_mark(&self) -> Duration {
+        MockClockPerThread::with(|clock| {
+            clock.mark.as_ref().expect("max_span_since_mark needs a mark").max_span
+        })
+    }
+
+    /// Queues `base + d` to be returned by `Clock::utc()`.
+    /// The result is clamped to the latest representable time, see `utc_saturating_add`.
+    pub fn add_utc_after(&self, base: DateTime<Utc>, d: Duration) {
+        Clock::add_utc(utc_saturating_add(base, d));
+    }
+
+    /// Queues `n` timestamps to be returned by `Clock::utc()`: `base`,
+    /// `base + step`, ..., `base + (n - 1) * step`.
+    pub fn add_utc_sequence(&self, base: DateTime<Utc>, step: Duration, n: u32) {
+        for i in 0..n {
+            self.add_utc_after(base, step.checked_mul(i).unwrap_or(Duration::MAX));
+        }
+    }
+
+    /// Describes the state of the mock for debugging a failing test: for both
+    /// clocks the mode (`queue` while samples are queued, then `frozen` at the
+    /// value set with `set_*` or `freeze_at`, `drift` for drifting UTC time
+    /// and `exhausted` once reading would panic), the number of samples left,
+    /// how often the clock was read and the next value, plus the last instant
+    /// returned, the `mark` and the registered pauses. Instants are shown
+    /// relative to the start of the mock. Reading the dump doesn't consume
+    /// samples.
+    pub fn debug_dump(&self) -> String {
+        let scope = if self.shared.is_some() { "shared" } else { "thread-local" };
+        let strict = if self.strict { ", strict" } else { "" };
+        let state = if self.shared.is_some() {
+            MockClockPerThread::lock_shared().describe()
+        } else {
+            MockClockPerThread::with_local(|clock| clock.describe())
+        };
+        format!("mock clock ({}{})\n{}", scope, strict, state)
+    }
+}
+
+impl fmt::Debug for MockClockGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.debug_dump())
+    }
+}
+
+impl MockClockGuard {
+    /// Tears down all mock state set up through this guard: the shared mock
+    /// if the guard holds it, the mock of the current thread otherwise. It
+    /// runs on drop, including when a test panics, so that later tests see the
+    /// real clock. (`Clock::reset` can also tear down the mock of the current
+    /// thread earlier, which makes this a no-op for it.) Returns the numbers of
+    /// unused UTC and instant samples.
+    fn reset_all(&mut self) -> (usize, usize) {
+        if self.shared.is_some() {
+            let mut shared = MockClockPerThread::lock_shared();
+            let unused = (shared.utc.len(), shared.durations.len());
+            SHARED_MOCK_ACTIVE.store(0, Ordering::Release);
+            shared.reset();
+            unused
+        } else {
+            MockClockPerThread::with_local(|clock| {
+                let unused = (clock.utc.len(), clock.durations.len());
+                clock.reset();
+                unused
+            })
+        }
     }
 }
 
 impl Drop for MockClockGuard {
     fn drop(&mut self) {
-        Clock::reset();
+        let (unused_utc, unused_instants) = self.reset_all();
+        if self.strict && (unused_utc > 0 || unused_instants > 0) && !std::thread::panicking() {
+            panic!(
+                "Mock clock has {} unused utc and {} unused instant samples",
+                unused_utc, unused_instants
+            );
+        }
     }
 }
 
 impl Clock {
+    /// Spawns a thread like `std::thread::spawn`, which uses the shared mock
+    /// clock if the current thread does (see `MockClockGuard::shared`), and
+    /// otherwise the real clock until it is mocked itself.
+    pub fn spawn<F, T>(f: F) -> std::thread::JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let joined = JOINED_SHARED_MOCK.with(Cell::get);
+        std::thread::spawn(move || {
+            JOINED_SHARED_MOCK.with(|it| it.set(joined));
+            f()
+        })
+    }
+
     pub fn set_mock() {
-        MockClockPerThread::with(|clock| {
+        MockClockPerThread::with_local(|clock| {
             clock.is_mock = true;
         });
     }
     pub fn reset() {
-        MockClockPerThread::with(|clock| {
+        MockClockPerThread::with_local(|clock| {
             clock.reset();
         });
     }
@@ -124,11 +500,47 @@ impl Clock {
                     }
                 }
             } else {
-                chrono::Utc::now()
+                SystemTimeSource.now_utc()
             }
         })
     }
 
+    /// Time elapsed since the Unix epoch according to `Clock::utc()`, or zero
+    /// if the (possibly mocked) current time is before the epoch.
+    pub fn since_epoch() -> Duration {
+        Clock::utc().signed_duration_since(Utc.timestamp(0, 0)).to_std().unwrap_or(Duration::ZERO)
+    }
+
+    /// `Clock::utc()` truncated to a multiple of `resolution` since the Unix
+    /// epoch, e.g. to whole milliseconds, so that times which fall into the
+    /// same bucket compare equal. Times before the epoch are truncated towards
+    /// the past as well, keeping the buckets equally long. A truncated time
+    /// before `chrono::MIN_DATETIME` is clamped to it.
+    pub fn utc_truncated(resolution: Duration) -> DateTime<chrono::Utc> {
+        let resolution = resolution.as_nanos() as i128;
+        assert!(resolution > 0, "resolution must not be zero");
+        let utc = Clock::utc();
+        let nanos =
+            i128::from(utc.timestamp()) * 1_000_000_000 + i128::from(utc.timestamp_subsec_nanos());
+        let truncated = nanos - nanos.rem_euclid(resolution);
+        i64::try_from(truncated.div_euclid(1_000_000_000))
+            .ok()
+            .and_then(|secs| {
+                Utc.timestamp_opt(secs, truncated.rem_euclid(1_000_000_000) as u32).single()
+            })
+            .unwrap_or(chrono::MIN_DATETIME)
+    }
+
+    /// Current time in the given time zone, derived from the (possibly mocked) `Clock::utc()`.
+    ///
+    /// Accepts any `chrono::TimeZone`, e.g. `chrono::FixedOffset` or `chrono::Local`.
+    pub fn now_in<Tz: TimeZone>(tz: &Tz) -> DateTime<Tz> {
+        Clock::utc().with_timezone(tz)
+    }
+
+    /// With the `tokio` feature, the real (non-mocked) time is taken from
+    /// tokio's clock, which follows `tokio::time::pause` and `advance` when
+    /// called from within a runtime.
     pub fn instant() -> Instant {
         MockClockPerThread::with(|clock| {
             if clock.is_mock {
@@ -140,11 +552,139 @@ impl Clock {
                     }
                 }
             } else {
-                Instant::now()
+                SystemTimeSource.now_instant()
             }
         })
     }
 
+    /// Waits for `duration` using tokio's timer, so a paused tokio clock makes
+    /// it return as soon as the runtime has nothing else to do.
+    ///
+    /// `MockClockGuard` takes precedence over tokio: with the mock active
+    /// `sleep` returns immediately, moving frozen time (see
+    /// `MockClockGuard::freeze_at`) forward by `duration`. Queued samples are
+    /// left alone. The mock is per thread, so this is only reliable on a
+    /// current-thread runtime.
+    #[cfg(feature = "tokio")]
+    pub async fn sleep(duration: Duration) {
+        let mocked = MockClockPerThread::with(|clock| {
+            if clock.is_mock {
+                clock.current_instant = clock.current_instant.map(|instant| instant + duration);
+                clock.current_utc = clock.current_utc.map(|utc| utc_saturating_add(utc, duration));
+            }
+            clock.is_mock
+        });
+        if mocked {
+            return;
+        }
+        tokio::time::sleep(duration).await;
+    }
+
+    /// Waits until `Clock::utc()` reaches `target`, e.g. to run a task at the
+    /// top of the hour. Returns immediately if `target` has passed already.
+    ///
+    /// The time left is recomputed from `Clock::utc()` after every wait, so
+    /// the wait also ends once the mock is moved past `target`: frozen mock
+    /// time (see `MockClockGuard::freeze_at`) moves to `target` right away,
+    /// like in `sleep`, and each queued UTC sample is read as the next time.
+    /// Wall-clock time which jumps back while waiting makes the wait longer.
+    #[cfg(feature = "tokio")]
+    pub async fn sleep_until_utc(target: DateTime<Utc>) {
+        loop {
+            let now = Clock::utc();
+            if now >= target {
+                return;
+            }
+            Clock::sleep((target - now).to_std().unwrap_or(Duration::ZERO)).await;
+        }
+    }
+
+    /// Blocks the current thread until `Clock::instant()` reaches `deadline`.
+    ///
+    /// With the mock clock active it doesn't block: it moves the mock forward
+    /// instead, so that the next `Clock::instant()` returns at least
+    /// `deadline`. Samples queued after a later one are left alone.
+    pub fn wait_until(deadline: Instant) {
+        let mocked = MockClockPerThread::with(|clock| {
+            if !clock.is_mock {
+                return false;
+            }
+            if clock.peek_instant().map_or(true, |next| next < deadline) {
+                if clock.durations.is_empty() && clock.current_instant.is_some() {
+                    clock.current_instant = Some(deadline);
+                } else {
+                    clock.durations.push_front(deadline.saturating_duration_since(clock.instant));
+                }
+            }
+            true
+        });
+        if mocked {
+            return;
+        }
+        loop {
+            let now = Clock::instant();
+            if now >= deadline {
+                return;
+            }
+            std::thread::sleep(deadline - now);
+        }
+    }
+
+    /// Registers the time between `start` and `end` as a pause, e.g. while
+    /// the node was intentionally suspended, to be left out by
+    /// `active_duration_since`. Pauses may overlap.
+    ///
+    /// Pauses are process-wide and never forgotten, so they should be rare.
+    /// With the mock clock active they are registered with the mock only and
+    /// dropped with it.
+    pub fn mark_pause(start: Instant, end: Instant) {
+        assert!(start <= end);
+        let mocked = MockClockPerThread::with(|clock| {
+            if clock.is_mock {
+                clock.pauses.push((start, end));
+            }
+            clock.is_mock
+        });
+        if !mocked {
+            PAUSES.lock().unwrap_or_else(PoisonError::into_inner).push((start, end));
+        }
+    }
+
+    /// Time elapsed since `start` according to `Clock::instant()`, excluding
+    /// the pauses registered with `mark_pause`.
+    pub fn active_duration_since(start: Instant) -> Duration {
+        let now = Clock::instant();
+        let mut pauses =
+            MockClockPerThread::with(|clock| clock.is_mock.then(|| clock.pauses.clone()))
+                .unwrap_or_else(|| PAUSES.lock().unwrap_or_else(PoisonError::into_inner).clone());
+        pauses.retain(|&(pause_start, pause_end)| pause_start < now && pause_end > start);
+        pauses.sort();
+        let mut paused = Duration::ZERO;
+        // End of the pauses accounted for so far.
+        let mut covered_until = start;
+        for (pause_start, pause_end) in pauses {
+            let pause_start = pause_start.max(covered_until);
+            let pause_end = pause_end.min(now);
+            if pause_end > pause_start {
+                paused += pause_end - pause_start;
+                covered_until = pause_end;
+            }
+        }
+        now.saturating_duration_since(start) - paused
+    }
+
+    /// Returns `Clock::instant` as a closure, for components which take their
+    /// time source as a `Fn() -> Instant`. The mock clock is per thread, so the
+    /// closure returns mocked values on threads using `MockClockGuard`.
+    pub fn as_instant_fn() -> impl Fn() -> Instant + Send + Sync + Copy {
+        Clock::instant
+    }
+
+    /// Returns `Clock::utc` as a closure, see `as_instant_fn`.
+    pub fn as_utc_fn() -> impl Fn() -> DateTime<chrono::Utc> + Send + Sync + Copy {
+        Clock::utc
+    }
+
     pub fn instant_call_count() -> u64 {
         MockClockPerThread::with(|clock| clock.instant_call_count)
     }
@@ -153,3 +693,996 @@ impl Clock {
         MockClockPerThread::with(|clock| clock.utc_call_count)
     }
 }
+
+/// Source of the current time, for components which get their clock injected
+/// rather than reading the global `Clock`.
+///
+/// `Clock` itself implements it (including the thread-local mocking), so
+/// production code can pass `Clock {}` while unit tests pass a
+/// `MockTimeSource` without touching any global state.
+pub trait TimeSource {
+    fn now_instant(&self) -> Instant;
+    fn now_utc(&self) -> DateTime<Utc>;
+}
+
+impl TimeSource for Clock {
+    fn now_instant(&self) -> Instant {
+        Clock::instant()
+    }
+
+    fn now_utc(&self) -> DateTime<Utc> {
+        Clock::utc()
+    }
+}
+
+impl<T: TimeSource + ?Sized> TimeSource for &T {
+    fn now_instant(&self) -> Instant {
+        (**self).now_instant()
+    }
+
+    fn now_utc(&self) -> DateTime<Utc> {
+        (**self).now_utc()
+    }
+}
+
+/// The real system time, used by `Clock` when it is not mocked.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemTimeSource;
+
+impl TimeSource for SystemTimeSource {
+    /// With the `tokio` feature this is tokio's clock, see `Clock::instant`.
+    #[cfg(feature = "tokio")]
+    fn now_instant(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    #[cfg(not(feature = "tokio"))]
+    fn now_instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn now_utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Time source whose time only changes when told to. Unlike `MockClockGuard`
+/// it holds no queue of samples: every read returns the current value.
+pub struct MockTimeSource {
+    now: Mutex<(Instant, DateTime<Utc>)>,
+}
+
+impl MockTimeSource {
+    pub fn new(instant: Instant, utc: DateTime<Utc>) -> Self {
+        Self { now: Mutex::new((instant, utc)) }
+    }
+
+    pub fn set_instant(&self, instant: Instant) {
+        self.lock().0 = instant;
+    }
+
+    pub fn set_utc(&self, utc: DateTime<Utc>) {
+        self.lock().1 = utc;
+    }
+
+    /// Moves both the instant and the UTC time forward by `d`.
+    pub fn advance(&self, d: Duration) {
+        let mut now = self.lock();
+        now.0 += d;
+        now.1 = utc_saturating_add(now.1, d);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, (Instant, DateTime<Utc>)> {
+        self.now.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl TimeSource for MockTimeSource {
+    fn now_instant(&self) -> Instant {
+        self.lock().0
+    }
+
+    fn now_utc(&self) -> DateTime<Utc> {
+        self.lock().1
+    }
+}
+
+/// Tasks scheduled to run once `Clock::instant()` reaches their deadlines.
+///
+/// Nothing runs by itself: the owner calls `run_due` periodically, or, in tests
+/// with a mock clock, `auto_advance_to_next_deadline` to jump straight to the
+/// next deadline.
+#[derive(Default)]
+pub struct Timers {
+    /// Deadlines with the ids of their tasks; ids break ties in schedule order.
+    queue: BinaryHeap<Reverse<(Instant, u64)>>,
+    tasks: HashMap<u64, Box<dyn FnOnce()>>,
+    next_id: u64,
+}
+
+impl Timers {
+    pub fn schedule(&mut self, deadline: Instant, task: impl FnOnce() + 'static) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.queue.push(Reverse((deadline, id)));
+        self.tasks.insert(id, Box::new(task));
+    }
+
+    pub fn schedule_after(&mut self, delay: Duration, task: impl FnOnce() + 'static) {
+        self.schedule(Clock::instant() + delay, task);
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.queue.peek().map(|Reverse((deadline, _))| *deadline)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Runs the tasks whose deadlines have passed, earliest first. Returns the
+    /// number of tasks run.
+    pub fn run_due(&mut self) -> usize {
+        let now = Clock::instant();
+        let mut ran = 0;
+        while self.next_deadline().map_or(false, |deadline| deadline <= now) {
+            let Reverse((_, id)) = self.queue.pop().unwrap();
+            (self.tasks.remove(&id).unwrap())();
+            ran += 1;
+        }
+        ran
+    }
+}
+
+/// Moves the mock clock forward to the next deadline of `timers` and runs the
+/// tasks which became due. Returns false if there was nothing scheduled.
+///
+/// Calling it in a loop runs all scheduled work in deadline order without
+/// waiting for real time to pass:
+/// `while auto_advance_to_next_deadline(&mut timers) {}`.
+pub fn auto_advance_to_next_deadline(timers: &mut Timers) -> bool {
+    let deadline = match timers.next_deadline() {
+        Some(deadline) => deadline,
+        None => return false,
+    };
+    MockClockPerThread::with(|clock| {
+        if !clock.is_mock {
+            panic!("Use MockClockGuard in your test");
+        }
+        // Make the deadline the very next instant sample.
+        clock.durations.push_front(deadline.saturating_duration_since(clock.instant));
+    });
+    timers.run_due();
+    true
+}
+
+/// Exponential backoff between retries, see `retry_with_backoff`.
+///
+/// The `n`-th delay (counting from zero) is `initial * multiplier^n`, capped at
+/// `max` and then scaled by a random factor from `[1 - jitter, 1 + jitter]`
+/// drawn from `rng`, which tests can seed or replace with a mock.
+pub struct Backoff<R> {
+    pub initial: Duration,
+    pub multiplier: f64,
+    pub max: Duration,
+    pub jitter: f64,
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    pub rng: R,
+}
+
+impl<R: rand::Rng> Backoff<R> {
+    /// Returns the delay before retrying after the failed attempt `attempt`
+    /// (counting from zero).
+    pub fn delay(&mut self, attempt: u32) -> Duration {
+        let base = self.initial.as_secs_f64() * self.multiplier.powi(attempt as i32);
+        let base = base.min(self.max.as_secs_f64());
+        let factor = 1.0 - self.jitter + 2.0 * self.jitter * self.rng.gen::<f64>();
+        Duration::from_secs_f64(base * factor.max(0.0))
+    }
+}
+
+/// Calls `f` until it succeeds or `policy.max_attempts` attempts failed,
+/// sleeping with `Clock::sleep` between attempts. Returns the error of the
+/// last attempt if all of them failed.
+///
+/// With the mock clock frozen, sleeps only move frozen time forward, so tests
+/// can check the exact delays by reading `Clock::instant()` in `f`.
+#[cfg(feature = "tokio")]
+pub async fn retry_with_backoff<F, Fut, T, E, R>(mut f: F, mut policy: Backoff<R>) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, E>>,
+    R: rand::Rng,
+{
+    assert!(policy.max_attempts > 0);
+    let mut attempt = 0;
+    loop {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt + 1 >= policy.max_attempts => return Err(err),
+            Err(_) => {}
+        }
+        Clock::sleep(policy.delay(attempt)).await;
+        attempt += 1;
+    }
+}
+
+/// Timestamp of a hybrid logical clock, see `Hlc`. Ordered by wall time, then
+/// by the logical counter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HlcTimestamp {
+    pub wall: DateTime<Utc>,
+    pub logical: u32,
+}
+
+impl HlcTimestamp {
+    /// The smallest timestamp after this one. Once the logical counter is
+    /// exhausted it carries into the wall time, which moves by a nanosecond.
+    fn successor(self) -> Self {
+        match self.logical.checked_add(1) {
+            Some(logical) => Self { wall: self.wall, logical },
+            None => Self { wall: self.wall + chrono::Duration::nanoseconds(1), logical: 0 },
+        }
+    }
+}
+
+/// Hybrid logical clock: produces timestamps which follow `Clock::utc()` but
+/// never go backwards and respect causality between nodes.
+///
+/// Timestamps increase even if the UTC time stalls or goes back (the logical
+/// counter grows instead), and merging a timestamp received from another node
+/// with `update` makes all later local timestamps larger than it.
+#[derive(Default)]
+pub struct Hlc {
+    last: Option<HlcTimestamp>,
+}
+
+impl Hlc {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a timestamp for a local event, larger than all previous ones.
+    pub fn now(&mut self) -> HlcTimestamp {
+        let wall = Clock::utc();
+        let next = match self.last {
+            Some(last) if last.wall >= wall => last.successor(),
+            _ => HlcTimestamp { wall, logical: 0 },
+        };
+        self.last = Some(next);
+        next
+    }
+
+    /// Merges a timestamp received from another node. Returns a timestamp for
+    /// the receive event, larger than both `remote` and all previous local ones.
+    pub fn update(&mut self, remote: HlcTimestamp) -> HlcTimestamp {
+        let wall = Clock::utc();
+        let last = self.last.unwrap_or(HlcTimestamp { wall, logical: 0 });
+        let max_wall = wall.max(last.wall).max(remote.wall);
+        let next = if max_wall == last.wall && max_wall == remote.wall {
+            last.max(remote).successor()
+        } else if max_wall == last.wall && self.last.is_some() {
+            last.successor()
+        } else if max_wall == remote.wall {
+            remote.successor()
+        } else {
+            HlcTimestamp { wall: max_wall, logical: 0 }
+        };
+        self.last = Some(next);
+        next
+    }
+}
+
+/// Token bucket rate limiter refilled based on the time measured by `Clock::instant()`.
+pub struct RateLimiter {
+    capacity: f64,
+    tokens_per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a full bucket holding at most `capacity` tokens and refilled
+    /// with `tokens_per_sec` tokens per second.
+    pub fn new(capacity: u64, tokens_per_sec: f64) -> Self {
+        assert!(tokens_per_sec >= 0.0);
+        let capacity = capacity as f64;
+        Self { capacity, tokens_per_sec, tokens: capacity, last_refill: Clock::instant() }
+    }
+
+    /// Takes `n` tokens from the bucket if it holds at least that many.
+    pub fn try_acquire(&mut self, n: u64) -> bool {
+        let now = Clock::instant();
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.tokens_per_sec).min(self.capacity);
+        self.last_refill = now;
+        if self.tokens < n as f64 {
+            return false;
+        }
+        self.tokens -= n as f64;
+        true
+    }
+}
+
+/// Lets a periodic action (e.g. sampling metrics) through at most once per
+/// `interval`, as measured by `Clock::instant()`.
+pub struct SampleGate {
+    interval: Duration,
+    last_sample: Option<Instant>,
+}
+
+impl SampleGate {
+    pub fn new(interval: Duration) -> Self {
+        Self { interval, last_sample: None }
+    }
+
+    /// Returns true on the first call and then whenever `interval` has passed
+    /// since the last call which returned true.
+    pub fn should_sample(&mut self) -> bool {
+        let now = Clock::instant();
+        match self.last_sample {
+            Some(last_sample) if now.saturating_duration_since(last_sample) < self.interval => {
+                false
+            }
+            _ => {
+                self.last_sample = Some(now);
+                true
+            }
+        }
+    }
+}
+
+/// Returns `dt + d`, or the latest time chrono can represent if the sum doesn't fit.
+pub fn utc_saturating_add(dt: DateTime<Utc>, d: Duration) -> DateTime<Utc> {
+    chrono::Duration::from_std(d)
+        .ok()
+        .and_then(|d| dt.checked_add_signed(d))
+        .unwrap_or(chrono::MAX_DATETIME)
+}
+
+/// Converts a UTC timestamp into `SystemTime`, including timestamps before the Unix epoch.
+pub fn to_system_time(dt: DateTime<Utc>) -> SystemTime {
+    // `timestamp` rounds towards negative infinity, so the sub-second part is
+    // always a non-negative offset from it.
+    let secs = dt.timestamp();
+    let nanos = Duration::from_nanos(u64::from(dt.timestamp_subsec_nanos()));
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(secs as u64) + nanos
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + nanos
+    }
+}
+
+/// Converts a `SystemTime` into a UTC timestamp, including times before the Unix epoch.
+/// Times outside of chrono's range saturate to `chrono::MIN_DATETIME` or
+/// `chrono::MAX_DATETIME`.
+pub fn from_system_time(st: SystemTime) -> DateTime<Utc> {
+    match st.duration_since(UNIX_EPOCH) {
+        Ok(since) => i64::try_from(since.as_secs())
+            .ok()
+            .and_then(|secs| Utc.timestamp_opt(secs, since.subsec_nanos()).single())
+            .unwrap_or(chrono::MAX_DATETIME),
+        Err(err) => {
+            let before = err.duration();
+            let timestamp = i64::try_from(before.as_secs()).ok().and_then(|secs| {
+                match before.subsec_nanos() {
+                    0 => Utc.timestamp_opt(-secs, 0),
+                    nanos => Utc.timestamp_opt(-secs - 1, 1_000_000_000 - nanos),
+                }
+                .single()
+            });
+            timestamp.unwrap_or(chrono::MIN_DATETIME)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_now_in_time_zone() {
+        let _mock_clock_guard = MockClockGuard::default();
+        Clock::add_utc(Utc.ymd(2021, 10, 14).and_hms(22, 30, 0));
+        let tokyo = chrono::FixedOffset::east(9 * 3600);
+        let now = Clock::now_in(&tokyo);
+        assert_eq!(now, tokyo.ymd(2021, 10, 15).and_hms(7, 30, 0));
+        assert_eq!(now.to_rfc3339(), "2021-10-15T07:30:00+09:00");
+    }
+
+    #[test]
+    fn test_add_utc_sequence() {
+        let mock_clock_guard = MockClockGuard::default();
+        let base = Utc.ymd(2021, 10, 14).and_hms(12, 0, 0);
+        mock_clock_guard.add_utc_after(base, Duration::from_secs(3600));
+        mock_clock_guard.add_utc_sequence(base, Duration::from_millis(1500), 3);
+        assert_eq!(Clock::utc(), Utc.ymd(2021, 10, 14).and_hms(13, 0, 0));
+        assert_eq!(Clock::utc(), base);
+        assert_eq!(Clock::utc(), Utc.ymd(2021, 10, 14).and_hms_milli(12, 0, 1, 500));
+        assert_eq!(Clock::utc(), Utc.ymd(2021, 10, 14).and_hms(12, 0, 3));
+        assert_eq!(Clock::utc_call_count(), 4);
+    }
+
+    #[test]
+    fn test_auto_advance_to_next_deadline() {
+        use std::rc::Rc;
+
+        let _mock_clock_guard = MockClockGuard::strict();
+        Clock::add_instant(Duration::ZERO);
+        let start = Clock::instant();
+        let fired = Rc::new(RefCell::new(Vec::new()));
+        let mut timers = Timers::default();
+        for (name, delay) in [("c", 30), ("a", 10), ("b1", 20), ("b2", 20)] {
+            let fired = fired.clone();
+            timers.schedule(start + Duration::from_millis(delay), move || {
+                fired.borrow_mut().push((name, Clock::instant_call_count()))
+            });
+        }
+
+        let mut steps = 0;
+        while auto_advance_to_next_deadline(&mut timers) {
+            steps += 1;
+        }
+        assert_eq!(steps, 3);
+        assert!(timers.is_empty());
+        // One clock read per step: "b1" and "b2" became due at the same time.
+        assert_eq!(*fired.borrow(), vec![("a", 2), ("b1", 3), ("b2", 3), ("c", 4)]);
+    }
+
+    #[test]
+    fn test_shared_mock() {
+        let shared_guard = MockClockGuard::shared();
+        let shared_utc = Utc.ymd(2021, 10, 14).and_hms(12, 0, 0);
+        Clock::add_utc(shared_utc);
+        {
+            // The thread-local mock wins over the shared one.
+            let _local_guard = MockClockGuard::default();
+            let local_utc = Utc.ymd(2000, 1, 1).and_hms(0, 0, 0);
+            Clock::add_utc(local_utc);
+            assert_eq!(Clock::spawn(Clock::utc).join().unwrap(), shared_utc);
+            assert_eq!(Clock::utc(), local_utc);
+        }
+        // Threads which weren't spawned with `Clock::spawn` keep the real clock.
+        let before = Utc::now();
+        assert!(std::thread::spawn(Clock::utc).join().unwrap() >= before);
+        // Threads spawned from those which were inherit the shared mock.
+        Clock::add_utc(shared_utc);
+        let nested = Clock::spawn(|| Clock::spawn(Clock::utc).join().unwrap());
+        assert_eq!(nested.join().unwrap(), shared_utc);
+        Clock::add_utc(shared_utc);
+        assert_eq!(Clock::utc(), shared_utc);
+        drop(shared_guard);
+        assert!(!MockClockPerThread::with(|clock| clock.is_mock));
+    }
+
+    #[test]
+    fn test_since_epoch() {
+        let _mock_clock_guard = MockClockGuard::default();
+        Clock::add_utc(Utc.ymd(2021, 10, 14).and_hms_milli(12, 0, 0, 250));
+        Clock::add_utc(Utc.ymd(1969, 12, 31).and_hms(23, 59, 59));
+        assert_eq!(Clock::since_epoch(), Duration::from_millis(1_634_212_800_250));
+        assert_eq!(Clock::since_epoch(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_debug_dump() {
+        let mock_clock_guard = MockClockGuard::strict();
+        Clock::add_utc(Utc.ymd(2021, 10, 14).and_hms(12, 0, 0));
+        Clock::add_utc(Utc.ymd(2021, 10, 14).and_hms(12, 0, 1));
+        Clock::add_instant(Duration::from_millis(5));
+        Clock::utc();
+        let last = Clock::instant();
+        let dump = mock_clock_guard.debug_dump();
+        assert_eq!(
+            dump,
+            "mock clock (thread-local, strict)\n\
+             utc: mode queue, 1 samples left, read 1 times, next 2021-10-14T12:00:01+00:00\n\
+             instant: mode exhausted, 0 samples left, read 1 times, last returned start + 5ms"
+        );
+        assert_eq!(format!("{:?}", mock_clock_guard), dump);
+
+        mock_clock_guard.set_instant(last + Duration::from_secs(1));
+        assert!(mock_clock_guard.debug_dump().ends_with(
+            "instant: mode frozen, 0 samples left, read 1 times, next start + 1.005s, \
+             last returned start + 5ms"
+        ));
+        Clock::utc();
+    }
+
+    #[test]
+    fn test_utc_truncated() {
+        let _mock_clock_guard = MockClockGuard::default();
+        let time = Utc.ymd(2021, 10, 14).and_hms_nano(12, 0, 0, 250_999_999);
+        for _ in 0..3 {
+            Clock::add_utc(time);
+        }
+        for _ in 0..2 {
+            Clock::add_utc(Utc.ymd(1969, 12, 31).and_hms_nano(23, 59, 59, 999_999_999));
+        }
+        assert_eq!(
+            Clock::utc_truncated(Duration::from_millis(1)),
+            Utc.ymd(2021, 10, 14).and_hms_milli(12, 0, 0, 250)
+        );
+        assert_eq!(
+            Clock::utc_truncated(Duration::from_secs(1)),
+            Utc.ymd(2021, 10, 14).and_hms(12, 0, 0)
+        );
+        assert_eq!(Clock::utc_truncated(Duration::from_nanos(1)), time);
+        assert_eq!(
+            Clock::utc_truncated(Duration::from_millis(10)),
+            Utc.ymd(1969, 12, 31).and_hms_milli(23, 59, 59, 990)
+        );
+        assert_eq!(Clock::utc_truncated(Duration::MAX), chrono::MIN_DATETIME);
+    }
+
+    #[test]
+    fn test_utc_saturating_add() {
+        let base = Utc.ymd(2021, 10, 14).and_hms(12, 0, 0);
+        assert_eq!(
+            utc_saturating_add(base, Duration::from_secs(60)),
+            base + chrono::Duration::minutes(1)
+        );
+        let near_max = chrono::MAX_DATETIME - chrono::Duration::days(1);
+        assert_eq!(
+            utc_saturating_add(near_max, Duration::from_secs(2 * 86400)),
+            chrono::MAX_DATETIME
+        );
+        assert_eq!(utc_saturating_add(base, Duration::MAX), chrono::MAX_DATETIME);
+
+        let mock_clock_guard = MockClockGuard::default();
+        mock_clock_guard.add_utc_sequence(near_max, Duration::from_secs(2 * 86400), 3);
+        assert_eq!(Clock::utc(), near_max);
+        assert_eq!(Clock::utc(), chrono::MAX_DATETIME);
+        assert_eq!(Clock::utc(), chrono::MAX_DATETIME);
+    }
+
+    #[test]
+    #[should_panic(expected = "Mock clock has 0 unused utc and 1 unused instant samples")]
+    fn test_strict_guard_detects_unused_samples() {
+        let _mock_clock_guard = MockClockGuard::strict();
+        for i in 0..3 {
+            Clock::add_instant(Duration::from_secs(i));
+        }
+        Clock::instant();
+        Clock::instant();
+    }
+
+    #[test]
+    fn test_strict_guard_all_samples_used() {
+        let _mock_clock_guard = MockClockGuard::strict();
+        Clock::add_utc(Utc.timestamp(0, 0));
+        Clock::utc();
+    }
+
+    #[test]
+    fn test_reset_after_panic() {
+        fn is_mocked() -> bool {
+            MockClockPerThread::with(|clock| clock.is_mock)
+        }
+
+        let result = std::panic::catch_unwind(|| {
+            let _mock_clock_guard = MockClockGuard::default();
+            Clock::add_instant(Duration::from_secs(3600));
+            panic!("test failure while mocked");
+        });
+        assert!(result.is_err());
+        assert!(!is_mocked());
+        assert_eq!(MockClockPerThread::with(|clock| clock.durations.len()), 0);
+
+        let result = std::panic::catch_unwind(|| {
+            let _mock_clock_guard = MockClockGuard::shared();
+            let _ = Clock::spawn(|| Clock::add_instant(Duration::from_secs(3600))).join();
+            panic!("test failure while mocked for a thread tree");
+        });
+        assert!(result.is_err());
+        assert!(!is_mocked());
+        // The real clock is back, and the next shared guard can be created.
+        let before = Instant::now();
+        assert!(Clock::spawn(move || Clock::instant() >= before).join().unwrap());
+        drop(MockClockGuard::shared());
+    }
+
+    #[test]
+    fn test_sample_gate() {
+        let _mock_clock_guard = MockClockGuard::strict();
+        let mut gate = SampleGate::new(Duration::from_secs(10));
+        let mut sampled = Vec::new();
+        for millis in [0, 3000, 9999, 10_000, 15_000, 35_000, 36_000, 44_999, 45_000] {
+            Clock::add_instant(Duration::from_millis(millis));
+            if gate.should_sample() {
+                sampled.push(millis);
+            }
+        }
+        assert_eq!(sampled, vec![0, 10_000, 35_000, 45_000]);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let _mock_clock_guard = MockClockGuard::default();
+        Clock::add_instant(Duration::ZERO);
+        let mut rate_limiter = RateLimiter::new(5, 1.0);
+
+        Clock::add_instant(Duration::ZERO);
+        assert!(rate_limiter.try_acquire(5));
+        Clock::add_instant(Duration::from_millis(500));
+        assert!(!rate_limiter.try_acquire(1));
+        Clock::add_instant(Duration::from_secs(3));
+        assert!(rate_limiter.try_acquire(3));
+        Clock::add_instant(Duration::from_secs(3));
+        assert!(!rate_limiter.try_acquire(1));
+        // The bucket never holds more than its capacity.
+        Clock::add_instant(Duration::from_secs(100));
+        assert!(!rate_limiter.try_acquire(6));
+        Clock::add_instant(Duration::from_secs(100));
+        assert!(rate_limiter.try_acquire(5));
+    }
+
+    #[test]
+    fn test_set_utc_rewinds() {
+        let mock_clock_guard = MockClockGuard::default();
+        let start = Utc.ymd(2021, 10, 14).and_hms(12, 0, 0);
+        mock_clock_guard.set_utc(start);
+        Clock::add_utc(start + chrono::Duration::seconds(1));
+        assert_eq!(Clock::utc(), start + chrono::Duration::seconds(1));
+        assert_eq!(Clock::utc(), start);
+        assert_eq!(Clock::utc(), start);
+
+        mock_clock_guard.set_utc(start - chrono::Duration::seconds(5));
+        let elapsed = Clock::utc().signed_duration_since(start);
+        assert_eq!(elapsed, chrono::Duration::seconds(-5));
+        // Converting the negative elapsed time doesn't panic, it just fails.
+        assert!(elapsed.to_std().is_err());
+        assert_eq!(Clock::utc_call_count(), 4);
+    }
+
+    #[test]
+    fn test_mock_time_source() {
+        struct Session<T: TimeSource> {
+            time: T,
+            started: Instant,
+        }
+
+        impl<T: TimeSource> Session<T> {
+            fn new(time: T) -> Self {
+                let started = time.now_instant();
+                Self { time, started }
+            }
+
+            fn expired(&self) -> bool {
+                self.time.now_instant() - self.started >= Duration::from_secs(60)
+            }
+        }
+
+        let utc = Utc.ymd(2021, 10, 14).and_hms(12, 0, 0);
+        let time = MockTimeSource::new(Instant::now(), utc);
+        let session = Session::new(&time);
+        assert!(!session.expired());
+        time.advance(Duration::from_secs(59));
+        assert!(!session.expired());
+        time.advance(Duration::from_secs(1));
+        assert!(session.expired());
+        assert_eq!(time.now_utc(), utc + chrono::Duration::seconds(60));
+
+        time.set_utc(utc);
+        assert_eq!(time.now_utc(), utc);
+        // Mock time sources don't use the mock clock at all.
+        assert_eq!(Clock::instant_call_count(), 0);
+        assert!(Session::new(Clock {}).time.now_utc() > utc);
+    }
+
+    #[test]
+    fn test_wait_until() {
+        let _mock_clock_guard = MockClockGuard::default();
+        let start = MockClockPerThread::with(|clock| clock.instant);
+        let deadline = start + Duration::from_secs(3600);
+        let started = Instant::now();
+        Clock::wait_until(deadline);
+        assert_eq!(Clock::instant(), deadline);
+        // A later queued sample is kept as is.
+        Clock::add_instant(Duration::from_secs(7200));
+        Clock::wait_until(deadline);
+        assert_eq!(Clock::instant(), start + Duration::from_secs(7200));
+        assert!(started.elapsed() < Duration::from_secs(60));
+
+        // Frozen time moves to the deadline for good.
+        let utc = Utc.ymd(2021, 10, 14).and_hms(12, 0, 0);
+        let _mock_clock_guard = MockClockGuard::freeze_at(utc, start);
+        Clock::wait_until(deadline);
+        assert_eq!(Clock::instant(), deadline);
+        assert_eq!(Clock::instant(), deadline);
+    }
+
+    #[test]
+    fn test_wait_until_real_clock() {
+        let deadline = Instant::now() + Duration::from_millis(20);
+        Clock::wait_until(deadline);
+        assert!(Instant::now() >= deadline);
+    }
+
+    #[test]
+    fn test_set_and_query_instant() {
+        let mock_clock_guard = MockClockGuard::default();
+        assert_eq!(mock_clock_guard.current_instant(), None);
+        assert_eq!(mock_clock_guard.current_utc(), None);
+
+        let instant = Instant::now() + Duration::from_secs(3600);
+        mock_clock_guard.set_instant(instant);
+        assert_eq!(mock_clock_guard.current_instant(), Some(instant));
+        assert_eq!(mock_clock_guard.current_instant(), Some(instant));
+        assert_eq!(Clock::instant_call_count(), 0);
+        assert_eq!(Clock::instant(), instant);
+
+        // Queued samples come first.
+        let utc = Utc.ymd(2021, 10, 14).and_hms(12, 0, 0);
+        mock_clock_guard.set_utc(utc);
+        Clock::add_utc(utc + chrono::Duration::seconds(1));
+        assert_eq!(mock_clock_guard.current_utc(), Some(utc + chrono::Duration::seconds(1)));
+        assert_eq!(Clock::utc(), utc + chrono::Duration::seconds(1));
+        assert_eq!(mock_clock_guard.current_utc(), Some(utc));
+    }
+
+    #[test]
+    fn test_max_span_since_mark() {
+        let mock_clock_guard = MockClockGuard::default();
+        Clock::add_instant(Duration::from_secs(10));
+        Clock::instant();
+        mock_clock_guard.mark();
+        assert_eq!(mock_clock_guard.max_span_since_mark(), Duration::ZERO);
+        for secs in [12, 17, 15] {
+            Clock::add_instant(Duration::from_secs(secs));
+            Clock::instant();
+        }
+        assert_eq!(mock_clock_guard.max_span_since_mark(), Duration::from_secs(7));
+
+        // The new mark starts from the last instant, 15s.
+        mock_clock_guard.mark();
+        Clock::add_instant(Duration::from_secs(16));
+        Clock::instant();
+        assert_eq!(mock_clock_guard.max_span_since_mark(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_drift() {
+        let mock_clock_guard = MockClockGuard::default();
+        let start = MockClockPerThread::with(|clock| clock.instant);
+        let utc = Utc.ymd(2021, 10, 14).and_hms(12, 0, 0);
+        mock_clock_guard.set_utc(utc);
+        mock_clock_guard.set_drift(0.01);
+        assert_eq!(Clock::utc(), utc);
+
+        Clock::add_instant(Duration::from_secs(100));
+        assert_eq!(Clock::instant(), start + Duration::from_secs(100));
+        assert_eq!(Clock::utc(), utc + chrono::Duration::seconds(101));
+        assert_eq!(Clock::utc(), utc + chrono::Duration::seconds(101));
+
+        Clock::add_instant(Duration::from_secs(1000));
+        Clock::instant();
+        assert_eq!(Clock::utc(), utc + chrono::Duration::seconds(1010));
+        // Queued samples still come first.
+        Clock::add_utc(utc);
+        assert_eq!(Clock::utc(), utc);
+    }
+
+    #[test]
+    fn test_active_duration_since() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let _mock_clock_guard = MockClockGuard::freeze_at(Utc.timestamp(0, 0), at(100));
+        Clock::mark_pause(at(10), at(30));
+        Clock::mark_pause(at(20), at(40));
+        // Partially before `start`.
+        Clock::mark_pause(at(0), at(6));
+        // After now.
+        Clock::mark_pause(at(150), at(160));
+        assert_eq!(Clock::active_duration_since(at(5)), Duration::from_secs(95 - 1 - 30));
+        assert_eq!(Clock::active_duration_since(at(25)), Duration::from_secs(75 - 15));
+        assert_eq!(Clock::active_duration_since(at(50)), Duration::from_secs(50));
+    }
+
+    #[test]
+    fn test_hlc() {
+        let _mock_clock_guard = MockClockGuard::default();
+        let t = |secs: i64| Utc.timestamp(1_634_000_000 + secs, 0);
+        let mut hlc = Hlc::new();
+        Clock::add_utc(t(10));
+        let first = hlc.now();
+        assert_eq!(first, HlcTimestamp { wall: t(10), logical: 0 });
+        // The wall clock went backwards.
+        Clock::add_utc(t(5));
+        let second = hlc.now();
+        assert_eq!(second, HlcTimestamp { wall: t(10), logical: 1 });
+
+        // A remote timestamp from the past doesn't move the clock back.
+        Clock::add_utc(t(11));
+        let third = hlc.update(HlcTimestamp { wall: t(3), logical: 7 });
+        assert_eq!(third, HlcTimestamp { wall: t(11), logical: 0 });
+        // One from the future is overtaken.
+        let remote = HlcTimestamp { wall: t(20), logical: 4 };
+        Clock::add_utc(t(12));
+        let fourth = hlc.update(remote);
+        assert_eq!(fourth, HlcTimestamp { wall: t(20), logical: 5 });
+        Clock::add_utc(t(13));
+        let fifth = hlc.now();
+        assert_eq!(fifth, HlcTimestamp { wall: t(20), logical: 6 });
+        Clock::add_utc(t(13));
+        assert_eq!(hlc.update(remote), HlcTimestamp { wall: t(20), logical: 7 });
+
+        assert!(first < second && second < third && third < fourth && fourth < fifth);
+        assert!(remote < fourth);
+    }
+
+    #[test]
+    fn test_hlc_logical_overflow() {
+        let _mock_clock_guard = MockClockGuard::default();
+        let wall = Utc.timestamp(1_634_000_000, 0);
+        let carried = wall + chrono::Duration::nanoseconds(1);
+        let mut hlc = Hlc::new();
+        Clock::add_utc(wall);
+        let remote = HlcTimestamp { wall, logical: u32::MAX - 1 };
+        assert_eq!(hlc.update(remote), HlcTimestamp { wall, logical: u32::MAX });
+        // The exhausted counter carries into the wall time.
+        Clock::add_utc(wall);
+        assert_eq!(hlc.now(), HlcTimestamp { wall: carried, logical: 0 });
+        Clock::add_utc(wall);
+        let remote = HlcTimestamp { wall: carried, logical: u32::MAX };
+        assert_eq!(
+            hlc.update(remote),
+            HlcTimestamp { wall: wall + chrono::Duration::nanoseconds(2), logical: 0 }
+        );
+    }
+
+    #[test]
+    fn test_freeze_at() {
+        let utc = Utc.ymd(2021, 10, 14).and_hms(12, 0, 0);
+        let instant = Instant::now();
+        let _mock_clock_guard = MockClockGuard::freeze_at(utc, instant);
+        for _ in 0..5 {
+            assert_eq!(Clock::utc(), utc);
+            assert_eq!(Clock::instant(), instant);
+        }
+        assert_eq!(Clock::since_epoch(), Duration::from_secs(utc.timestamp() as u64));
+    }
+
+    #[test]
+    fn test_time_source_fns() {
+        struct Component<F: Fn() -> Instant> {
+            now: F,
+        }
+
+        let _mock_clock_guard = MockClockGuard::default();
+        let component = Component { now: Clock::as_instant_fn() };
+        let utc_fn = Clock::as_utc_fn();
+        Clock::add_instant(Duration::from_secs(2));
+        Clock::add_instant(Duration::from_secs(7));
+        let utc = Utc.ymd(2021, 10, 14).and_hms(12, 0, 0);
+        Clock::add_utc(utc);
+        let start = (component.now)();
+        assert_eq!((component.now)() - start, Duration::from_secs(5));
+        assert_eq!(utc_fn(), utc);
+        assert_eq!((Clock::instant_call_count(), Clock::utc_call_count()), (2, 1));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_sleep_follows_paused_tokio_clock() {
+        tokio::time::pause();
+        let start = Clock::instant();
+        let real_start = std::time::SystemTime::now();
+        Clock::sleep(Duration::from_secs(3600)).await;
+        assert!(Clock::instant() - start >= Duration::from_secs(3600));
+        assert!(real_start.elapsed().unwrap() < Duration::from_secs(60));
+
+        let _mock_clock_guard = MockClockGuard::default();
+        let tokio_now = tokio::time::Instant::now();
+        Clock::sleep(Duration::from_secs(3600)).await;
+        assert_eq!(tokio::time::Instant::now(), tokio_now);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_sleep_until_utc() {
+        let target = Utc.ymd(2021, 10, 14).and_hms(13, 0, 0);
+        {
+            let _mock_clock_guard = MockClockGuard::strict();
+            Clock::add_utc(Utc.ymd(2021, 10, 14).and_hms(12, 59, 0));
+            Clock::add_utc(Utc.ymd(2021, 10, 14).and_hms(12, 59, 59));
+            Clock::add_utc(Utc.ymd(2021, 10, 14).and_hms(13, 0, 1));
+            Clock::sleep_until_utc(target).await;
+            // A target in the past doesn't wait.
+            Clock::add_utc(Utc.ymd(2021, 10, 14).and_hms(14, 0, 0));
+            Clock::sleep_until_utc(target).await;
+        }
+
+        let _mock_clock_guard =
+            MockClockGuard::freeze_at(Utc.ymd(2021, 10, 14).and_hms(12, 0, 0), Instant::now());
+        Clock::sleep_until_utc(target).await;
+        assert_eq!(Clock::utc(), target);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_retry_with_backoff() {
+        use std::cell::RefCell;
+
+        let start = Instant::now();
+        let _mock_clock_guard = MockClockGuard::freeze_at(Utc.timestamp(0, 0), start);
+        let policy = Backoff {
+            initial: Duration::from_millis(100),
+            multiplier: 2.0,
+            max: Duration::from_secs(1),
+            jitter: 0.5,
+            max_attempts: 5,
+            // Always draws zero, i.e. the lowest jitter factor of 0.5.
+            rng: rand::rngs::mock::StepRng::new(0, 0),
+        };
+        let attempts = RefCell::new(Vec::new());
+        let result: Result<&str, &str> = retry_with_backoff(
+            || {
+                attempts.borrow_mut().push(Clock::instant() - start);
+                let failed = attempts.borrow().len() <= 2;
+                async move {
+                    if failed {
+                        Err("unavailable")
+                    } else {
+                        Ok("done")
+                    }
+                }
+            },
+            policy,
+        )
+        .await;
+        assert_eq!(result, Ok("done"));
+        assert_eq!(
+            *attempts.borrow(),
+            vec![Duration::ZERO, Duration::from_millis(50), Duration::from_millis(150)]
+        );
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let mut backoff = Backoff {
+            initial: Duration::from_millis(100),
+            multiplier: 10.0,
+            max: Duration::from_secs(5),
+            jitter: 0.0,
+            max_attempts: 3,
+            rng: rand::thread_rng(),
+        };
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(1), Duration::from_secs(1));
+        assert_eq!(backoff.delay(2), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_system_time_round_trip() {
+        for (secs, nanos) in [
+            (0, 0),
+            (1, 0),
+            (1_634_000_000, 123_456_789),
+            (-1, 0),
+            (-1, 999_999_999),
+            (-86_400 * 365, 500_000_000),
+        ] {
+            let dt = Utc.timestamp(secs, nanos);
+            let st = to_system_time(dt);
+            assert_eq!(from_system_time(st), dt);
+        }
+    }
+
+    #[test]
+    fn test_system_time_before_epoch() {
+        let dt = Utc.ymd(1969, 12, 31).and_hms_milli(23, 59, 59, 250);
+        let st = to_system_time(dt);
+        assert_eq!(UNIX_EPOCH.duration_since(st).unwrap(), Duration::from_millis(750));
+        assert_eq!(from_system_time(UNIX_EPOCH - Duration::from_millis(750)), dt);
+    }
+
+    #[test]
+    fn test_system_time_out_of_range() {
+        let far = Duration::from_secs(i64::MAX as u64);
+        assert_eq!(from_system_time(UNIX_EPOCH + far), chrono::MAX_DATETIME);
+        assert_eq!(from_system_time(UNIX_EPOCH - far), chrono::MIN_DATETIME);
+    }
+}
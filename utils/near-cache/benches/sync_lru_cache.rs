@@ -0,0 +1,79 @@
+#[macro_use]
+extern crate bencher;
+
+use bencher::{black_box, Bencher};
+use near_cache::SyncLruCache;
+use near_primitives::time::MockClockGuard;
+
+const CAPACITY: u64 = 1000;
+
+/// Like `Bencher::iter`, but with a strict mock clock without any samples, so
+/// the benchmark panics if the measured code reads `Clock`. This guarantees
+/// that the results depend on the operation only and not on how much real
+/// time passes while it runs. Run without the `op_latency` feature, which
+/// reads the clock on every operation.
+fn iter_without_clock<T, F: FnMut() -> T>(bench: &mut Bencher, f: F) {
+    let _mock_clock_guard = MockClockGuard::strict();
+    bench.iter(f);
+}
+
+/// Cache filled with keys `0..CAPACITY`. Filling it is not part of the
+/// measured region.
+fn full_cache() -> SyncLruCache<u64, u64> {
+    let cache = SyncLruCache::new(CAPACITY as usize);
+    for key in 0..CAPACITY {
+        cache.insert(key, key);
+    }
+    cache
+}
+
+/// Number of keys which `get_hot_keys` keeps looking up.
+const HOT_KEYS: u64 = 16;
+
+fn get_hot_keys(bench: &mut Bencher) {
+    let cache = full_cache();
+    iter_without_clock(bench, || {
+        for key in 0..CAPACITY {
+            black_box(cache.get(&(key % HOT_KEYS)));
+        }
+    });
+}
+
+/// Same lookups as `get_hot_keys`, with the recency updates applied in batches.
+fn get_hot_keys_batched_recency(bench: &mut Bencher) {
+    let cache = full_cache().with_batched_recency(64, None);
+    iter_without_clock(bench, || {
+        for key in 0..CAPACITY {
+            black_box(cache.get(&(key % HOT_KEYS)));
+        }
+    });
+}
+
+fn get_or_insert_hit(bench: &mut Bencher) {
+    let cache = full_cache();
+    iter_without_clock(bench, || {
+        for key in 0..CAPACITY {
+            black_box(cache.get_or_insert(key, |_| unreachable!()));
+        }
+    });
+}
+
+fn get_or_insert_miss(bench: &mut Bencher) {
+    let cache = full_cache();
+    let mut next_key = CAPACITY;
+    iter_without_clock(bench, || {
+        for _ in 0..CAPACITY {
+            black_box(cache.get_or_insert(next_key, |key| *key));
+            next_key += 1;
+        }
+    });
+}
+
+benchmark_group!(
+    benches,
+    get_hot_keys,
+    get_hot_keys_batched_recency,
+    get_or_insert_hit,
+    get_or_insert_miss
+);
+benchmark_main!(benches);
@@ -0,0 +1,205 @@
+use crate::{CacheStats, SyncLruCache};
+use near_primitives::time::Clock;
+use std::hash::Hash;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Parameters of `AutoSizedCache`.
+#[derive(Clone, Debug)]
+pub struct AutoSizeConfig {
+    /// The cache never shrinks below this capacity. It is also the initial one.
+    pub min_capacity: usize,
+    /// The cache never grows above this capacity.
+    pub max_capacity: usize,
+    /// How often the hit ratio is evaluated.
+    pub interval: Duration,
+    /// The capacity is doubled if the hit ratio over the last interval is below this value.
+    pub grow_below_hit_ratio: f64,
+    /// The capacity is halved if the hit ratio over the last interval is above this value.
+    pub shrink_above_hit_ratio: f64,
+}
+
+/// `SyncLruCache` which adjusts its own capacity based on the hit ratio.
+///
+/// Every operation checks, using `Clock::instant()`, whether `interval` has
+/// passed since the last evaluation. If so, the hit ratio over that window
+/// decides whether the capacity is doubled, halved or left untouched.
+pub struct AutoSizedCache<K, V> {
+    cache: SyncLruCache<K, V>,
+    config: AutoSizeConfig,
+    window: Mutex<Window>,
+}
+
+/// Measurement window since the last capacity evaluation.
+struct Window {
+    interval: Duration,
+    started: Instant,
+    stats: CacheStats,
+}
+
+impl<K, V> AutoSizedCache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub fn new(config: AutoSizeConfig) -> Self {
+        assert!(config.min_capacity <= config.max_capacity);
+        assert!(config.grow_below_hit_ratio <= config.shrink_above_hit_ratio);
+        let cache = SyncLruCache::new(config.min_capacity);
+        let window =
+            Window { interval: config.interval, started: Clock::instant(), stats: cache.stats() };
+        Self { cache, config, window: Mutex::new(window) }
+    }
+
+    /// Changes how often the hit ratio is evaluated. Takes effect for the
+    /// current window.
+    pub fn set_interval(&self, interval: Duration) {
+        self.window().interval = interval;
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.cache.capacity()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Underlying cache, for operations which don't need to trigger resizing.
+    pub fn cache(&self) -> &SyncLruCache<K, V> {
+        &self.cache
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.maybe_resize();
+        self.cache.get(key)
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        self.maybe_resize();
+        self.cache.insert(key, value)
+    }
+
+    pub fn get_or_insert<F>(&self, key: K, f: F) -> V
+    where
+        F: FnOnce(&K) -> V,
+    {
+        self.maybe_resize();
+        self.cache.get_or_insert(key, f)
+    }
+
+    fn maybe_resize(&self) {
+        let now = Clock::instant();
+        let mut window = self.window();
+        if now.saturating_duration_since(window.started) < window.interval {
+            return;
+        }
+        let stats = self.cache.stats();
+        // Counters which went backwards were reset during the window, e.g.
+        // with `cache().reset_stats()`, so they count from zero.
+        let was_reset = stats.hits < window.stats.hits || stats.misses < window.stats.misses;
+        let (hits, misses) = if was_reset {
+            (stats.hits, stats.misses)
+        } else {
+            (stats.hits - window.stats.hits, stats.misses - window.stats.misses)
+        };
+        let lookups = hits + misses;
+        window.started = now;
+        window.stats = stats;
+        if lookups == 0 {
+            return;
+        }
+
+        let hit_ratio = hits as f64 / lookups as f64;
+        let capacity = self.cache.capacity();
+        let new_capacity = if hit_ratio < self.config.grow_below_hit_ratio {
+            (capacity * 2).min(self.config.max_capacity)
+        } else if hit_ratio > self.config.shrink_above_hit_ratio {
+            (capacity / 2).max(self.config.min_capacity)
+        } else {
+            capacity
+        };
+        if new_capacity != capacity {
+            self.cache.resize(new_capacity);
+        }
+    }
+
+    fn window(&self) -> MutexGuard<'_, Window> {
+        self.window.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AutoSizeConfig, AutoSizedCache};
+    use near_primitives::time::{Clock, MockClockGuard};
+    use std::time::Duration;
+
+    /// Queues the `Clock::instant()` read of the resize check of the next
+    /// operation.
+    fn advance_to(second: u64) {
+        Clock::add_instant(Duration::from_secs(second));
+    }
+
+    fn config() -> AutoSizeConfig {
+        AutoSizeConfig {
+            min_capacity: 2,
+            max_capacity: 16,
+            interval: Duration::from_secs(10),
+            grow_below_hit_ratio: 0.5,
+            shrink_above_hit_ratio: 0.9,
+        }
+    }
+
+    #[test]
+    fn test_grows_on_low_hit_ratio() {
+        let _mock_clock_guard = MockClockGuard::default();
+        Clock::add_instant(Duration::ZERO);
+        let cache = AutoSizedCache::new(config());
+
+        let mut capacities = vec![];
+        for second in 1..=40 {
+            advance_to(second);
+            // Every key is new, so every lookup misses.
+            cache.get_or_insert(second, |key| *key);
+            if second % 10 == 0 {
+                capacities.push(cache.capacity());
+            }
+        }
+        assert_eq!(capacities, vec![4, 8, 16, 16]);
+    }
+
+    #[test]
+    fn test_stats_reset_during_window() {
+        let _mock_clock_guard = MockClockGuard::default();
+        Clock::add_instant(Duration::ZERO);
+        let cache = AutoSizedCache::new(config());
+
+        for second in 1..=20 {
+            if second == 15 {
+                // Fewer misses are counted afterwards than at the start of
+                // the window.
+                cache.cache().reset_stats();
+            }
+            advance_to(second);
+            cache.get_or_insert(second, |key| *key);
+        }
+        // The misses since the reset grew the cache once more.
+        assert_eq!(cache.capacity(), 8);
+    }
+
+    #[test]
+    fn test_shrinks_on_high_hit_ratio() {
+        let _mock_clock_guard = MockClockGuard::default();
+        Clock::add_instant(Duration::ZERO);
+        let cache = AutoSizedCache::new(AutoSizeConfig { min_capacity: 4, ..config() });
+        cache.cache().resize(16);
+
+        for second in 1..=30 {
+            advance_to(second);
+            // Always the same key, so everything but the first lookup hits.
+            cache.get_or_insert(0, |key| *key);
+        }
+        assert_eq!(cache.capacity(), 4);
+    }
+}
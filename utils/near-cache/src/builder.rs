@@ -0,0 +1,398 @@
+use crate::sharded_cache::{key_hasher, KeyHasher};
+use crate::{
+    CacheStats, EvictDecision, EvictionBatchCallback, EvictionCallback, EvictionSender,
+    ShardedCache, SyncLruCache, TtlCache,
+};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Cache created by `CacheBuilder`, holding whichever concrete cache matches
+/// the requested options.
+pub enum Cache<K, V> {
+    Lru(SyncLruCache<K, V>),
+    Ttl(TtlCache<K, V>),
+    Sharded(ShardedCache<K, V>),
+}
+
+impl<K, V> Cache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub fn len(&self) -> usize {
+        match self {
+            Cache::Lru(cache) => cache.len(),
+            Cache::Ttl(cache) => cache.len(),
+            Cache::Sharded(cache) => cache.len(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        match self {
+            Cache::Lru(cache) => cache.capacity(),
+            Cache::Ttl(cache) => cache.capacity(),
+            Cache::Sharded(cache) => cache.capacity(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Cache::Lru(cache) => cache.is_empty(),
+            Cache::Ttl(cache) => cache.is_empty(),
+            Cache::Sharded(cache) => cache.is_empty(),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        match self {
+            Cache::Lru(cache) => cache.get(key),
+            Cache::Ttl(cache) => cache.get(key),
+            Cache::Sharded(cache) => cache.get(key),
+        }
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        match self {
+            Cache::Lru(cache) => cache.insert(key, value),
+            Cache::Ttl(cache) => cache.insert(key, value),
+            Cache::Sharded(cache) => cache.insert(key, value),
+        }
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        match self {
+            Cache::Lru(cache) => cache.remove(key),
+            Cache::Ttl(cache) => cache.remove(key),
+            Cache::Sharded(cache) => cache.remove(key),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        match self {
+            Cache::Lru(cache) => cache.stats(),
+            Cache::Ttl(cache) => cache.stats(),
+            Cache::Sharded(cache) => cache.stats(),
+        }
+    }
+
+    /// Resizes a shard of a `ShardedCache`.
+    pub(crate) fn resize_shard(&self, capacity: usize) {
+        match self {
+            Cache::Lru(cache) => cache.resize(capacity),
+            Cache::Ttl(cache) => cache.resize(capacity),
+            Cache::Sharded(_) => unreachable!("shards are never sharded"),
+        }
+    }
+
+    /// Moves the entries of a shard whose keys match `f` to the shards `to`
+    /// returns for them, which must be of the same kind, keeping the order in
+    /// which they were used and the expiry of TTL entries.
+    pub(crate) fn move_shard_entries<'a>(
+        &self,
+        f: impl Fn(&K) -> bool,
+        to: impl Fn(&K) -> &'a Cache<K, V>,
+    ) where
+        K: 'a,
+        V: 'a,
+    {
+        match self {
+            Cache::Lru(cache) => {
+                // Least recently used first, so that the recency is kept.
+                for (key, value) in cache.drain_where(|key, _| f(key)).into_iter().rev() {
+                    to(&key).insert(key, value);
+                }
+            }
+            Cache::Ttl(cache) => {
+                for (key, entry) in cache.take_where(f).into_iter().rev() {
+                    match to(&key) {
+                        Cache::Ttl(shard) => shard.insert_entry(key, entry),
+                        _ => unreachable!("shards are all of the same kind"),
+                    }
+                }
+            }
+            Cache::Sharded(_) => unreachable!("shards are never sharded"),
+        }
+    }
+}
+
+/// Options of the caches a `ShardedCache` is made of, so that it can create
+/// more of them when resharding.
+pub(crate) struct ShardFactory<K, V> {
+    ttl: Option<Duration>,
+    on_evict: Option<EvictionCallback<K, V>>,
+    on_evict_batch: Option<EvictionBatchCallback<K, V>>,
+}
+
+impl<K, V> ShardFactory<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub fn build(&self, capacity: usize) -> Cache<K, V> {
+        match self.ttl {
+            Some(ttl) => {
+                let mut cache = TtlCache::new(capacity, ttl);
+                if let Some(on_evict) = &self.on_evict {
+                    cache = cache.with_on_evict(on_evict.clone());
+                }
+                if let Some(on_evict_batch) = &self.on_evict_batch {
+                    cache = cache.with_on_evict_batch(on_evict_batch.clone());
+                }
+                Cache::Ttl(cache)
+            }
+            None => {
+                let mut cache = SyncLruCache::new(capacity);
+                if let Some(on_evict) = &self.on_evict {
+                    cache = cache.with_on_evict(on_evict.clone());
+                }
+                if let Some(on_evict_batch) = &self.on_evict_batch {
+                    cache = cache.with_on_evict_batch(on_evict_batch.clone());
+                }
+                Cache::Lru(cache)
+            }
+        }
+    }
+}
+
+/// Builder for the [`Cache`] structure.
+///
+/// Without further options `build` returns a `SyncLruCache` of
+/// `DEFAULT_CAPACITY` entries; `ttl` makes it a `TtlCache` and `shards` splits
+/// it into a `ShardedCache` of such caches.
+pub struct CacheBuilder<K, V> {
+    capacity: usize,
+    ttl: Option<Duration>,
+    hasher: Option<KeyHasher<K>>,
+    on_evict: Option<EvictionCallback<K, V>>,
+    on_evict_batch: Option<EvictionBatchCallback<K, V>>,
+    shards: usize,
+}
+
+impl<K, V> Default for CacheBuilder<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> CacheBuilder<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    /// Capacity used unless `capacity` is called.
+    pub const DEFAULT_CAPACITY: usize = 1024;
+
+    pub fn new() -> Self {
+        Self {
+            capacity: Self::DEFAULT_CAPACITY,
+            ttl: None,
+            hasher: None,
+            on_evict: None,
+            on_evict_batch: None,
+            shards: 1,
+        }
+    }
+
+    /// Sets the total number of entries the cache may hold. Panics if
+    /// `capacity` is zero.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0);
+        self.capacity = capacity;
+        self
+    }
+
+    /// Makes entries expire `ttl` after they were first inserted.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Sets the hasher assigning keys to shards. Entries within a shard are
+    /// still stored with the standard hasher. Has no effect with a single
+    /// shard.
+    pub fn hasher<S: BuildHasher + Send + Sync + 'static>(mut self, hasher: S) -> Self {
+        self.hasher = Some(key_hasher(hasher));
+        self
+    }
+
+    /// Sets a callback called with every entry about to be evicted to stay
+    /// within the capacity, which decides whether to evict it or keep it and
+    /// evict the next candidate instead, see `EvictDecision`. It runs with the
+    /// (shard of the) cache locked, so it must not call back into the cache.
+    pub fn on_evict<F>(mut self, on_evict: F) -> Self
+    where
+        F: Fn(&K, &V) -> EvictDecision + Send + Sync + 'static,
+    {
+        self.on_evict = Some(Arc::new(on_evict));
+        self
+    }
+
+    /// Sends every entry evicted to stay within the capacity through the
+    /// channel, for eviction side effects which need to be awaited. Replaces
+    /// the `on_evict` callback, see `eviction_channel`.
+    pub fn evict_to(self, sender: EvictionSender<K, V>) -> Self
+    where
+        K: Send + 'static,
+        V: Send + 'static,
+    {
+        self.on_evict(move |key, value| {
+            sender.send(key.clone(), value.clone());
+            EvictDecision::Evict
+        })
+    }
+
+    /// Sets a callback called once with all entries evicted by a single
+    /// operation, in eviction order, which is cheaper than `on_evict` for
+    /// callbacks with a large fixed cost. Also runs with the cache locked,
+    /// after `on_evict` if both are set.
+    pub fn on_evict_batch<F>(mut self, on_evict_batch: F) -> Self
+    where
+        F: Fn(Vec<(K, V)>) + Send + Sync + 'static,
+    {
+        self.on_evict_batch = Some(Arc::new(on_evict_batch));
+        self
+    }
+
+    /// Splits the cache into `shards` independently locked parts, each holding
+    /// an equal share of the capacity (rounded up). Panics if `shards` is
+    /// zero.
+    pub fn shards(mut self, shards: usize) -> Self {
+        assert!(shards > 0);
+        self.shards = shards;
+        self
+    }
+
+    pub fn build(self) -> Cache<K, V> {
+        let factory = ShardFactory {
+            ttl: self.ttl,
+            on_evict: self.on_evict,
+            on_evict_batch: self.on_evict_batch,
+        };
+        if self.shards == 1 {
+            return factory.build(self.capacity);
+        }
+        let hasher = self.hasher.unwrap_or_else(|| key_hasher(RandomState::new()));
+        Cache::Sharded(ShardedCache::new(self.capacity, self.shards, factory, hasher))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cache, CacheBuilder};
+    use crate::EvictDecision;
+    use near_primitives::time::{Clock, MockClockGuard};
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::BuildHasherDefault;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn test_default() {
+        let cache = CacheBuilder::<u32, u32>::new().build();
+        match &cache {
+            Cache::Lru(cache) => {
+                assert_eq!(cache.capacity(), CacheBuilder::<u32, u32>::DEFAULT_CAPACITY)
+            }
+            _ => panic!("expected an LRU cache"),
+        }
+        cache.insert(1, 10);
+        assert_eq!(cache.get(&1), Some(10));
+    }
+
+    #[test]
+    fn test_capacity_and_on_evict() {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let cache = {
+            let evicted = evicted.clone();
+            CacheBuilder::new()
+                .capacity(2)
+                .on_evict(move |&key, &value| {
+                    evicted.lock().unwrap().push((key, value));
+                    EvictDecision::Evict
+                })
+                .build()
+        };
+        for key in 0..4 {
+            cache.insert(key, key * 10);
+        }
+        assert_eq!(cache.len(), 2);
+        assert_eq!(*evicted.lock().unwrap(), vec![(0, 0), (1, 10)]);
+    }
+
+    #[test]
+    fn test_on_evict_batch() {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let cache = {
+            let batches = batches.clone();
+            CacheBuilder::new()
+                .capacity(2)
+                .on_evict_batch(move |batch| batches.lock().unwrap().push(batch))
+                .build()
+        };
+        for key in 0..3 {
+            cache.insert(key, key * 10);
+        }
+        match &cache {
+            Cache::Lru(cache) => cache.resize(1),
+            _ => panic!("expected an LRU cache"),
+        }
+        assert_eq!(*batches.lock().unwrap(), vec![vec![(0, 0)], vec![(1, 10)]]);
+    }
+
+    #[test]
+    fn test_ttl() {
+        let _mock_clock_guard = MockClockGuard::default();
+        let cache = CacheBuilder::new().capacity(10).ttl(Duration::from_secs(5)).build();
+        assert!(matches!(cache, Cache::Ttl(_)));
+        Clock::add_instant(Duration::from_secs(0));
+        cache.insert(1, "a");
+        Clock::add_instant(Duration::from_secs(4));
+        assert_eq!(cache.get(&1), Some("a"));
+        Clock::add_instant(Duration::from_secs(5));
+        assert_eq!(cache.get(&1), None);
+    }
+
+    #[test]
+    fn test_shards() {
+        let evictions = Arc::new(Mutex::new(0));
+        let cache = {
+            let evictions = evictions.clone();
+            CacheBuilder::new()
+                .capacity(400)
+                .shards(4)
+                .hasher(BuildHasherDefault::<DefaultHasher>::default())
+                .on_evict(move |_, _| {
+                    *evictions.lock().unwrap() += 1;
+                    EvictDecision::Evict
+                })
+                .build()
+        };
+        match &cache {
+            Cache::Sharded(cache) => assert_eq!(cache.shard_count(), 4),
+            _ => panic!("expected a sharded cache"),
+        }
+        for key in 0..100 {
+            cache.insert(key, key);
+        }
+        assert!((0..100).all(|key| cache.get(&key) == Some(key)));
+        assert_eq!(cache.stats().hits, 100);
+        assert_eq!(*evictions.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_sharded_ttl() {
+        let _mock_clock_guard = MockClockGuard::default();
+        let cache = CacheBuilder::new().shards(2).ttl(Duration::from_secs(5)).build();
+        Clock::add_instant(Duration::from_secs(0));
+        cache.insert(1, "a");
+        Clock::add_instant(Duration::from_secs(6));
+        assert_eq!(cache.get(&1), None);
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,74 @@
+use crate::SyncLruCache;
+use std::hash::Hash;
+
+/// Read-only handle to a `SyncLruCache`, for components which may look values
+/// up but shouldn't change what is cached. Created with `SyncLruCache::reader`.
+///
+/// Lookups through the handle still update recency and statistics like
+/// lookups on the cache itself. There is no way to modify the cache:
+///
+/// ```compile_fail
+/// let cache = near_cache::SyncLruCache::<u32, u32>::new(10);
+/// cache.reader().insert(1, 2);
+/// ```
+pub struct CacheReader<'a, K, V> {
+    cache: &'a SyncLruCache<K, V>,
+}
+
+impl<K, V> SyncLruCache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub fn reader(&self) -> CacheReader<'_, K, V> {
+        CacheReader { cache: self }
+    }
+}
+
+impl<'a, K, V> CacheReader<'a, K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.cache.contains_key(key)
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.cache.get(key)
+    }
+
+    pub fn peek(&self, key: &K) -> Option<V> {
+        self.cache.peek(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::SyncLruCache;
+
+    #[test]
+    fn test_reader() {
+        let cache = SyncLruCache::new(2);
+        cache.insert(1, "a");
+        cache.insert(2, "b");
+        let reader = cache.reader();
+        assert_eq!(reader.len(), 2);
+        assert!(reader.contains_key(&1));
+        assert_eq!(reader.peek(&1), Some("a"));
+        assert_eq!(reader.get(&3), None);
+        // `peek` didn't make key 1 recently used.
+        cache.insert(3, "c");
+        assert_eq!(reader.get(&1), None);
+        assert_eq!(reader.get(&2), Some("b"));
+        assert_eq!(cache.stats().hits, 1);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::SyncLruCache;
+use near_primitives::time::Clock;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Immutable copy of the entries of a `SyncLruCache`, created with
+/// `SyncLruCache::snapshot`.
+///
+/// The snapshot is taken at one point in time and doesn't see later changes
+/// of the cache, nor do lookups in it count as uses of the cache. In exchange
+/// reads don't take any lock, which suits caches that barely change after
+/// warm-up. Clones share the same entries.
+#[derive(Clone)]
+pub struct CacheSnapshot<K, V> {
+    entries: Arc<HashMap<K, V>>,
+}
+
+/// Reads entries of a `SyncLruCache` from a `CacheSnapshot` which is taken
+/// again once it is older than `max_staleness` (as measured by
+/// `Clock::instant()`). Created with `SyncLruCache::snapshot_reader`.
+///
+/// Reads don't lock the cache unless they refresh the snapshot, but they do
+/// need a `&mut` reader, so each thread should use its own.
+pub struct SnapshotReader<'a, K, V> {
+    cache: &'a SyncLruCache<K, V>,
+    max_staleness: Duration,
+    snapshot: CacheSnapshot<K, V>,
+    taken_at: Instant,
+}
+
+impl<K, V> SyncLruCache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    /// Copies all entries into a `CacheSnapshot`, holding the lock for the
+    /// whole copy.
+    pub fn snapshot(&self) -> CacheSnapshot<K, V> {
+        let mut entries = HashMap::with_capacity(self.len());
+        self.for_each(|key, value| {
+            entries.insert(key.clone(), value.clone());
+        });
+        CacheSnapshot { entries: Arc::new(entries) }
+    }
+
+    pub fn snapshot_reader(&self, max_staleness: Duration) -> SnapshotReader<'_, K, V> {
+        let taken_at = Clock::instant();
+        SnapshotReader { cache: self, max_staleness, snapshot: self.snapshot(), taken_at }
+    }
+}
+
+impl<'a, K, V> SnapshotReader<'a, K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    /// Returns the current snapshot, taking a new one first if it's too stale.
+    pub fn snapshot(&mut self) -> &CacheSnapshot<K, V> {
+        let now = Clock::instant();
+        if now.saturating_duration_since(self.taken_at) > self.max_staleness {
+            self.snapshot = self.cache.snapshot();
+            self.taken_at = now;
+        }
+        &self.snapshot
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        self.snapshot().get(key)
+    }
+
+    pub fn contains_key(&mut self, key: &K) -> bool {
+        self.snapshot().contains_key(key)
+    }
+}
+
+impl<K: Hash + Eq, V> CacheSnapshot<K, V> {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::SyncLruCache;
+
+    #[test]
+    fn test_snapshot() {
+        let cache = SyncLruCache::new(2);
+        cache.insert(1, "a");
+        cache.insert(2, "b");
+        let snapshot = cache.snapshot();
+        cache.insert(1, "c");
+        cache.insert(3, "d");
+        cache.remove(&1);
+
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot.get(&1), Some(&"a"));
+        assert_eq!(snapshot.get(&2), Some(&"b"));
+        assert!(!snapshot.contains_key(&3));
+        assert_eq!(cache.snapshot().get(&3), Some(&"d"));
+        assert_eq!(cache.stats().hits, 0);
+    }
+
+    #[test]
+    fn test_snapshot_reader_max_staleness() {
+        use near_primitives::time::{Clock, MockClockGuard};
+        use std::time::Duration;
+
+        let _mock_clock_guard = MockClockGuard::default();
+        let cache = SyncLruCache::new(10);
+        cache.insert(1, "a");
+        Clock::add_instant(Duration::from_secs(0));
+        let mut reader = cache.snapshot_reader(Duration::from_secs(5));
+        cache.insert(2, "b");
+
+        Clock::add_instant(Duration::from_secs(3));
+        assert_eq!(reader.get(&2), None);
+        Clock::add_instant(Duration::from_secs(5));
+        assert_eq!(reader.get(&1), Some(&"a"));
+        Clock::add_instant(Duration::from_secs(6));
+        assert_eq!(reader.get(&2), Some(&"b"));
+
+        // The new snapshot is fresh for the next 5s.
+        cache.remove(&2);
+        Clock::add_instant(Duration::from_secs(11));
+        assert!(reader.contains_key(&2));
+        Clock::add_instant(Duration::from_secs(12));
+        assert!(!reader.contains_key(&2));
+    }
+}
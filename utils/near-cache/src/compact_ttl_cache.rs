@@ -0,0 +1,209 @@
+use crate::lru::LruCache;
+use crate::stats::{AtomicCacheStats, CacheStats};
+use near_primitives::time::Clock;
+use std::hash::Hash;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Leaner alternative to `TtlCache` for caches of many small values: instead
+/// of an `Instant` and further metadata, every entry only stores the `u32`
+/// epoch at which it expires, counted in ticks of `resolution` by
+/// `Clock::instant()`.
+///
+/// Expiry is only as precise as the resolution: an entry expires between
+/// `ttl - resolution` and `ttl + resolution` after it was inserted. Expired
+/// entries are dropped lazily when they are looked up, or all at once by
+/// `evict_expired`. Overwriting an entry restarts its TTL.
+///
+/// The epoch counts from a moving origin rather than from the creation of the
+/// cache, so it never rolls over: before the epoch of an expiry would exceed
+/// `u32::MAX`, the origin is moved to the current tick and all stored epochs
+/// are shifted accordingly. This takes time linear in the number of entries,
+/// but only every `u32::MAX - ttl / resolution` ticks, e.g. every 49 days at a
+/// resolution of a millisecond.
+pub struct CompactTtlCache<K, V> {
+    inner: Mutex<Inner<K, V>>,
+    resolution: Duration,
+    /// The TTL in ticks, rounded up.
+    ttl_ticks: u32,
+    stats: AtomicCacheStats,
+}
+
+struct Inner<K, V> {
+    capacity: usize,
+    /// Values with the epochs at which they expire.
+    entries: LruCache<K, (V, u32)>,
+    /// Instant of epoch zero.
+    origin: Instant,
+}
+
+impl<K, V> Inner<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    /// Current epoch, moving the origin first if entries inserted now would
+    /// expire after `u32::MAX`.
+    fn epoch(&mut self, now: Instant, resolution: Duration, ttl_ticks: u32) -> u32 {
+        let ticks = now.saturating_duration_since(self.origin).as_nanos() / resolution.as_nanos();
+        if ticks + u128::from(ttl_ticks) <= u128::from(u32::MAX) {
+            return ticks as u32;
+        }
+        self.origin += Duration::from_nanos((ticks * resolution.as_nanos()) as u64);
+        // Epochs of entries which expired long ago saturate at zero, which
+        // keeps them expired.
+        let shift = ticks.min(u128::from(u32::MAX)) as u32;
+        for (_, expires_at) in self.entries.values_mut() {
+            *expires_at = expires_at.saturating_sub(shift);
+        }
+        0
+    }
+}
+
+impl<K, V> CompactTtlCache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    /// Creates a cache whose entries expire `ttl`, rounded up to whole ticks of
+    /// `resolution`, after they were inserted. The TTL may be at most
+    /// `u32::MAX / 2` ticks.
+    pub fn new(capacity: usize, ttl: Duration, resolution: Duration) -> Self {
+        assert!(capacity > 0);
+        assert!(resolution > Duration::ZERO);
+        let ttl_ticks = (ttl.as_nanos() + resolution.as_nanos() - 1) / resolution.as_nanos();
+        assert!(ttl_ticks <= u128::from(u32::MAX / 2), "ttl is too long for the resolution");
+        Self {
+            inner: Mutex::new(Inner {
+                capacity,
+                entries: LruCache::new(),
+                origin: Clock::instant(),
+            }),
+            resolution,
+            ttl_ticks: ttl_ticks as u32,
+            stats: AtomicCacheStats::default(),
+        }
+    }
+
+    pub fn resolution(&self) -> Duration {
+        self.resolution
+    }
+
+    /// Number of entries, including expired ones which weren't dropped yet.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let now = Clock::instant();
+        let mut inner = self.lock();
+        let epoch = inner.epoch(now, self.resolution, self.ttl_ticks);
+        let expired = match inner.entries.peek(key) {
+            Some(&(_, expires_at)) => epoch >= expires_at,
+            None => {
+                self.stats.record_miss(false);
+                return None;
+            }
+        };
+        if expired {
+            inner.entries.remove(key);
+            self.stats.record_miss(false);
+            return None;
+        }
+        self.stats.record_hit();
+        inner.entries.get(key).map(|(value, _)| value.clone())
+    }
+
+    /// Inserts the value, which expires `ttl` from now. Evicts the least
+    /// recently used entry if the cache is over capacity.
+    pub fn insert(&self, key: K, value: V) {
+        let now = Clock::instant();
+        let mut inner = self.lock();
+        let expires_at = inner.epoch(now, self.resolution, self.ttl_ticks) + self.ttl_ticks;
+        inner.entries.put(key, (value, expires_at));
+        while inner.entries.len() > inner.capacity {
+            inner.entries.pop_lru();
+            self.stats.record_eviction();
+        }
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.lock().entries.remove(key).map(|(value, _)| value)
+    }
+
+    /// Drops all expired entries and returns their number. Swept entries
+    /// aren't counted in `CacheStats::evictions`.
+    pub fn evict_expired(&self) -> usize {
+        let now = Clock::instant();
+        let mut inner = self.lock();
+        let epoch = inner.epoch(now, self.resolution, self.ttl_ticks);
+        inner.entries.drain_where(|_, &(_, expires_at)| epoch >= expires_at).len()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats.snapshot()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<K, V>> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CompactTtlCache;
+    use near_primitives::time::{Clock, MockClockGuard};
+    use std::time::Duration;
+
+    #[test]
+    fn test_expiry() {
+        let start = Clock::instant();
+        let mock_clock_guard = MockClockGuard::default();
+        mock_clock_guard.set_instant(start);
+        let cache = CompactTtlCache::new(10, Duration::from_secs(10), Duration::from_secs(1));
+        mock_clock_guard.set_instant(start + Duration::from_millis(500));
+        cache.insert(1, "a");
+        mock_clock_guard.set_instant(start + Duration::from_millis(2500));
+        cache.insert(2, "b");
+
+        // Expiry is rounded to whole ticks of the resolution.
+        mock_clock_guard.set_instant(start + Duration::from_millis(9999));
+        assert_eq!(cache.get(&1), Some("a"));
+        mock_clock_guard.set_instant(start + Duration::from_secs(10));
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&2), Some("b"));
+        mock_clock_guard.set_instant(start + Duration::from_secs(12));
+        assert_eq!(cache.evict_expired(), 1);
+        assert!(cache.is_empty());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 1));
+    }
+
+    #[test]
+    fn test_epoch_rollover() {
+        let start = Clock::instant();
+        let mock_clock_guard = MockClockGuard::default();
+        mock_clock_guard.set_instant(start);
+        // Nanosecond ticks make the epoch exceed `u32::MAX` after 4.3s.
+        let cache = CompactTtlCache::new(10, Duration::from_secs(1), Duration::from_nanos(1));
+        mock_clock_guard.set_instant(start + Duration::from_secs(3));
+        cache.insert(1, "a");
+        mock_clock_guard.set_instant(start + Duration::from_millis(3500));
+        cache.insert(2, "b");
+        mock_clock_guard.set_instant(start + Duration::from_millis(3999));
+        assert_eq!(cache.get(&1), Some("a"));
+        mock_clock_guard.set_instant(start + Duration::from_secs(4));
+        assert_eq!(cache.get(&1), None);
+        mock_clock_guard.set_instant(start + Duration::from_millis(4499));
+        assert_eq!(cache.get(&2), Some("b"));
+
+        // Long after the entries expired, they still are.
+        cache.insert(1, "c");
+        mock_clock_guard.set_instant(start + Duration::from_secs(60));
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&2), None);
+    }
+}
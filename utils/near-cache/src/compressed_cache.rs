@@ -0,0 +1,171 @@
+use crate::{CacheStats, EvictDecision, EvictionCallback, SyncLruCache};
+use std::hash::Hash;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Compression algorithm used by a `CompressedCache`.
+pub trait Codec {
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+    /// Reverses `compress`; `uncompressed_len` is the length of the original data.
+    fn decompress(&self, data: &[u8], uncompressed_len: usize) -> io::Result<Vec<u8>>;
+}
+
+/// DEFLATE compression from the `flate2` crate.
+#[cfg(feature = "flate2")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Deflate {
+    pub level: flate2::Compression,
+}
+
+#[cfg(feature = "flate2")]
+impl Codec for Deflate {
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), self.level);
+        encoder.write_all(data).expect("writing to a Vec doesn't fail");
+        encoder.finish().expect("writing to a Vec doesn't fail")
+    }
+
+    fn decompress(&self, data: &[u8], uncompressed_len: usize) -> io::Result<Vec<u8>> {
+        use std::io::Read;
+        let mut result = Vec::with_capacity(uncompressed_len);
+        flate2::read::DeflateDecoder::new(data).read_to_end(&mut result)?;
+        Ok(result)
+    }
+}
+
+/// Total sizes of the blobs held by a `CompressedCache`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    pub compressed_bytes: usize,
+    pub uncompressed_bytes: usize,
+}
+
+#[derive(Default)]
+struct AtomicCompressionStats {
+    compressed_bytes: AtomicUsize,
+    uncompressed_bytes: AtomicUsize,
+}
+
+impl AtomicCompressionStats {
+    fn add(&self, blob: &Blob) {
+        self.compressed_bytes.fetch_add(blob.data.len(), Ordering::Relaxed);
+        self.uncompressed_bytes.fetch_add(blob.uncompressed_len, Ordering::Relaxed);
+    }
+
+    fn sub(&self, blob: &Blob) {
+        self.compressed_bytes.fetch_sub(blob.data.len(), Ordering::Relaxed);
+        self.uncompressed_bytes.fetch_sub(blob.uncompressed_len, Ordering::Relaxed);
+    }
+}
+
+struct Blob {
+    data: Vec<u8>,
+    uncompressed_len: usize,
+}
+
+/// LRU cache of byte blobs which are stored compressed with the given `Codec`.
+///
+/// This trades CPU for memory: every `insert` compresses the value and every
+/// `get` decompresses it again, so it only pays off for large values which
+/// compress well and are read rarely enough for the extra work not to matter.
+/// The capacity is still a number of entries, see `compression_stats` for the
+/// memory actually held.
+pub struct CompressedCache<K, C> {
+    cache: SyncLruCache<K, Arc<Blob>>,
+    codec: C,
+    sizes: Arc<AtomicCompressionStats>,
+}
+
+impl<K, C> CompressedCache<K, C>
+where
+    K: Hash + Eq + Clone,
+    C: Codec,
+{
+    pub fn new(capacity: usize, codec: C) -> Self {
+        let sizes = Arc::new(AtomicCompressionStats::default());
+        let on_evict: EvictionCallback<K, Arc<Blob>> = {
+            let sizes = sizes.clone();
+            Arc::new(move |_, blob: &Arc<Blob>| {
+                sizes.sub(blob);
+                EvictDecision::Evict
+            })
+        };
+        Self { cache: SyncLruCache::new(capacity).with_on_evict(on_evict), codec, sizes }
+    }
+
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    pub fn get(&self, key: &K) -> Option<Vec<u8>> {
+        let blob = self.cache.get(key)?;
+        Some(
+            self.codec
+                .decompress(&blob.data, blob.uncompressed_len)
+                .expect("cached blobs were compressed by the same codec"),
+        )
+    }
+
+    /// Compresses and inserts the value. Compression happens before the cache
+    /// is locked.
+    pub fn insert(&self, key: K, value: &[u8]) {
+        let blob =
+            Arc::new(Blob { data: self.codec.compress(value), uncompressed_len: value.len() });
+        self.sizes.add(&blob);
+        if let Some(previous) = self.cache.replace(key, blob) {
+            self.sizes.sub(&previous);
+        }
+    }
+
+    pub fn remove(&self, key: &K) -> Option<Vec<u8>> {
+        let blob = self.cache.remove(key)?;
+        self.sizes.sub(&blob);
+        Some(
+            self.codec
+                .decompress(&blob.data, blob.uncompressed_len)
+                .expect("cached blobs were compressed by the same codec"),
+        )
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    pub fn compression_stats(&self) -> CompressionStats {
+        CompressionStats {
+            compressed_bytes: self.sizes.compressed_bytes.load(Ordering::Relaxed),
+            uncompressed_bytes: self.sizes.uncompressed_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "flate2"))]
+mod tests {
+    use super::{CompressedCache, CompressionStats, Deflate};
+
+    #[test]
+    fn test_round_trip() {
+        let cache = CompressedCache::new(2, Deflate::default());
+        let blob: Vec<u8> = (0..10_000u32).map(|i| (i % 7) as u8).collect();
+        cache.insert(1, &blob);
+        assert_eq!(cache.get(&1).as_deref(), Some(&blob[..]));
+        let stats = cache.compression_stats();
+        assert_eq!(stats.uncompressed_bytes, blob.len());
+        assert!(stats.compressed_bytes < blob.len() / 10);
+
+        cache.insert(1, b"abc");
+        cache.insert(2, b"de");
+        cache.insert(3, b"f");
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.remove(&2), Some(b"de".to_vec()));
+        assert_eq!(cache.compression_stats().uncompressed_bytes, 1);
+        cache.remove(&3);
+        assert_eq!(cache.compression_stats(), CompressionStats::default());
+    }
+}
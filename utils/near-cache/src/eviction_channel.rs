@@ -0,0 +1,118 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// Creates a channel through which a cache hands evicted entries to async
+/// code, e.g. to close connections held by the values. Pass the sender to
+/// `CacheBuilder::evict_to` and drive the receiver from a task:
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let (sender, mut receiver) = near_cache::eviction_channel(64);
+/// let cache = near_cache::CacheBuilder::new().capacity(1).evict_to(sender).build();
+/// let handler = tokio::spawn(async move {
+///     while let Some((key, value)) = receiver.recv().await {
+///         // Await whatever the eviction of `key` requires.
+/// #       let _: (u32, u32) = (key, value);
+///     }
+/// });
+/// cache.insert(1, 10);
+/// cache.insert(2, 20);
+/// drop(cache);
+/// handler.await.unwrap();
+/// # }
+/// ```
+///
+/// Entries arrive in the order they were evicted. The cache sends them with
+/// its lock held, so it never waits for the receiver: once `capacity` entries
+/// are waiting to be received, further evicted entries are dropped instead of
+/// sent and counted by `EvictionReceiver::dropped`. The receiver sees the end
+/// of the channel once the cache is dropped.
+pub fn eviction_channel<K, V>(capacity: usize) -> (EvictionSender<K, V>, EvictionReceiver<K, V>) {
+    let (sender, receiver) = mpsc::channel(capacity);
+    let dropped = Arc::new(AtomicU64::new(0));
+    (EvictionSender { sender, dropped: dropped.clone() }, EvictionReceiver { receiver, dropped })
+}
+
+/// Sending half of `eviction_channel`.
+pub struct EvictionSender<K, V> {
+    sender: mpsc::Sender<(K, V)>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// Receiving half of `eviction_channel`.
+pub struct EvictionReceiver<K, V> {
+    receiver: mpsc::Receiver<(K, V)>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl<K, V> EvictionSender<K, V> {
+    /// Sends the entry unless the channel is full or the receiver is gone.
+    pub(crate) fn send(&self, key: K, value: V) {
+        if self.sender.try_send((key, value)).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<K, V> EvictionReceiver<K, V> {
+    /// Waits for the next evicted entry, or returns `None` once the cache was
+    /// dropped and all sent entries were received.
+    pub async fn recv(&mut self) -> Option<(K, V)> {
+        self.receiver.recv().await
+    }
+
+    /// Number of evicted entries which didn't fit into the channel.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::eviction_channel;
+    use crate::CacheBuilder;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_async_handler() {
+        tokio::time::pause();
+        let (sender, mut receiver) = eviction_channel(16);
+        let cache = CacheBuilder::new().capacity(2).evict_to(sender).build();
+        let closed = Arc::new(Mutex::new(Vec::new()));
+        let handler = {
+            let closed = closed.clone();
+            tokio::spawn(async move {
+                while let Some((key, connection)) = receiver.recv().await {
+                    // Stands in for e.g. closing a network connection.
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    closed.lock().unwrap().push((key, connection));
+                }
+                receiver.dropped()
+            })
+        };
+        for key in 0..5 {
+            cache.insert(key, format!("connection {}", key));
+        }
+        drop(cache);
+        assert_eq!(handler.await.unwrap(), 0);
+        let expected: Vec<_> = (0..3).map(|key| (key, format!("connection {}", key))).collect();
+        assert_eq!(*closed.lock().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_full_channel_drops_entries() {
+        let (sender, mut receiver) = eviction_channel(2);
+        let cache = CacheBuilder::new().capacity(1).evict_to(sender).build();
+        for key in 0..5 {
+            cache.insert(key, key);
+        }
+        assert_eq!(receiver.dropped(), 2);
+        drop(cache);
+        assert_eq!(receiver.recv().await, Some((0, 0)));
+        assert_eq!(receiver.recv().await, Some((1, 1)));
+        assert_eq!(receiver.recv().await, None);
+    }
+}
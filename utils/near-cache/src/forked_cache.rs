@@ -0,0 +1,118 @@
+use crate::lru::LruCache;
+use crate::SyncLruCache;
+use std::hash::Hash;
+
+/// Overlay over a `SyncLruCache` buffering writes which may be thrown away,
+/// e.g. those made by a speculative computation. Created with
+/// `SyncLruCache::fork`.
+///
+/// Lookups see the writes of the fork and read through to the cache for the
+/// other keys. The writes only reach the cache on `commit`; dropping the fork
+/// discards them.
+pub struct ForkedCache<'a, K, V> {
+    parent: &'a SyncLruCache<K, V>,
+    /// Buffered writes ordered by when they were made, `None` for removals.
+    writes: LruCache<K, Option<V>>,
+}
+
+impl<K, V> SyncLruCache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub fn fork(&self) -> ForkedCache<'_, K, V> {
+        ForkedCache { parent: self, writes: LruCache::new() }
+    }
+}
+
+impl<'a, K, V> ForkedCache<'a, K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    /// Returns the value written to the fork, or the one in the cache (marking
+    /// it as recently used there) if the key wasn't written.
+    pub fn get(&self, key: &K) -> Option<V> {
+        match self.writes.peek(key) {
+            Some(value) => value.clone(),
+            None => self.parent.get(key),
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        self.writes.put(key, Some(value));
+    }
+
+    /// Hides the key from the fork until it is written again, returning the
+    /// value which was visible before. Committing removes it from the cache.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let previous = match self.writes.peek(key) {
+            Some(value) => value.clone(),
+            None => self.parent.peek(key),
+        };
+        self.writes.put(key.clone(), None);
+        previous
+    }
+
+    /// Number of keys written or removed in the fork.
+    pub fn pending_writes(&self) -> usize {
+        self.writes.len()
+    }
+
+    /// Applies the buffered writes to the cache in the order they were made,
+    /// so that the last written key ends up as the most recently used one.
+    /// Other threads may observe the cache with only some of them applied.
+    pub fn commit(mut self) {
+        while let Some((key, value)) = self.writes.pop_lru() {
+            match value {
+                Some(value) => self.parent.insert(key, value),
+                None => {
+                    self.parent.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::SyncLruCache;
+
+    #[test]
+    fn test_commit_and_discard() {
+        let cache = SyncLruCache::new(10);
+        cache.insert(1, "a");
+        cache.insert(2, "b");
+
+        let mut fork = cache.fork();
+        fork.insert(1, "x");
+        fork.insert(3, "c");
+        assert_eq!(fork.remove(&2), Some("b"));
+        assert_eq!((fork.get(&1), fork.get(&2), fork.get(&3)), (Some("x"), None, Some("c")));
+        assert_eq!(fork.pending_writes(), 3);
+        // The cache is unaffected until the fork is committed.
+        assert_eq!((cache.get(&1), cache.get(&2), cache.get(&3)), (Some("a"), Some("b"), None));
+        fork.commit();
+        assert_eq!((cache.get(&1), cache.get(&2), cache.get(&3)), (Some("x"), None, Some("c")));
+
+        let mut fork = cache.fork();
+        fork.insert(4, "d");
+        drop(fork);
+        assert_eq!(cache.get(&4), None);
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_commit_order() {
+        let cache = SyncLruCache::new(2);
+        let mut fork = cache.fork();
+        fork.insert(1, "a");
+        fork.insert(2, "b");
+        fork.insert(1, "c");
+        fork.insert(3, "d");
+        fork.commit();
+        // 2 was written least recently, so the cache evicted it.
+        assert_eq!(cache.order(), vec![3, 1]);
+        assert_eq!(cache.get(&1), Some("c"));
+    }
+}
@@ -0,0 +1,59 @@
+//! Tracking of the most frequently looked up keys, enabled with the `hot_keys`
+//! feature.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Approximate lookup counts of the most frequent keys, kept with the
+/// Space-Saving algorithm in at most `max_keys` counters.
+///
+/// A key missing a counter takes over the smallest one, starting from its
+/// count plus one, so counts overestimate by at most the number of lookups
+/// divided by `max_keys`. Every key looked up more often than that is
+/// guaranteed to be tracked.
+pub(crate) struct HotKeys<K> {
+    max_keys: usize,
+    counts: HashMap<K, u64>,
+}
+
+impl<K> HotKeys<K>
+where
+    K: Hash + Eq + Clone,
+{
+    pub fn new(max_keys: usize) -> Self {
+        assert!(max_keys > 0);
+        Self { max_keys, counts: HashMap::with_capacity(max_keys) }
+    }
+
+    pub fn record(&mut self, key: &K) {
+        if let Some(count) = self.counts.get_mut(key) {
+            *count += 1;
+            return;
+        }
+        let mut count = 1;
+        if self.counts.len() == self.max_keys {
+            let (coldest, min_count) = self
+                .counts
+                .iter()
+                .min_by_key(|(_, count)| **count)
+                .map(|(key, count)| (key.clone(), *count))
+                .unwrap();
+            self.counts.remove(&coldest);
+            count += min_count;
+        }
+        self.counts.insert(key.clone(), count);
+    }
+
+    /// The `n` keys with the highest counts, highest first.
+    pub fn top(&self, n: usize) -> Vec<(K, u64)> {
+        let mut top: Vec<_> =
+            self.counts.iter().map(|(key, count)| (key.clone(), *count)).collect();
+        top.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+        top.truncate(n);
+        top
+    }
+
+    pub fn clear(&mut self) {
+        self.counts.clear();
+    }
+}
@@ -0,0 +1,170 @@
+use crate::lru::LruCache;
+use crate::stats::{AtomicCacheStats, CacheStats};
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Thread-safe LRU cache holding at most `capacity` entries, which also
+/// indexes them by an attribute of their values (e.g. the owner of a record),
+/// so that all entries sharing the attribute can be invalidated at once.
+///
+/// The index key of a value is computed by the `indexer` passed to `new`; it
+/// should only depend on the value.
+pub struct IndexedCache<K, V, I> {
+    inner: Mutex<Inner<K, V, I>>,
+    capacity: usize,
+    indexer: Box<dyn Fn(&V) -> I + Send + Sync>,
+    stats: AtomicCacheStats,
+}
+
+struct Inner<K, V, I> {
+    entries: LruCache<K, V>,
+    /// Primary keys of the entries with each index key. Index keys without
+    /// entries are removed.
+    index: HashMap<I, HashSet<K>>,
+}
+
+impl<K, V, I> Inner<K, V, I>
+where
+    K: Hash + Eq + Clone,
+    I: Hash + Eq,
+{
+    fn unindex(&mut self, index_key: I, key: &K) {
+        if let Some(keys) = self.index.get_mut(&index_key) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.index.remove(&index_key);
+            }
+        }
+    }
+}
+
+impl<K, V, I> IndexedCache<K, V, I>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+    I: Hash + Eq,
+{
+    pub fn new(capacity: usize, indexer: impl Fn(&V) -> I + Send + Sync + 'static) -> Self {
+        assert!(capacity > 0);
+        Self {
+            inner: Mutex::new(Inner { entries: LruCache::new(), index: HashMap::new() }),
+            capacity,
+            indexer: Box::new(indexer),
+            stats: AtomicCacheStats::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        match self.lock().entries.get(key) {
+            Some(value) => {
+                self.stats.record_hit();
+                Some(value.clone())
+            }
+            None => {
+                self.stats.record_miss(false);
+                None
+            }
+        }
+    }
+
+    /// Inserts the value, evicting the least recently used entry if the cache
+    /// is over capacity.
+    pub fn insert(&self, key: K, value: V) {
+        let index_key = (self.indexer)(&value);
+        let mut inner = self.lock();
+        if let Some(previous) = inner.entries.put(key.clone(), value) {
+            inner.unindex((self.indexer)(&previous), &key);
+        }
+        inner.index.entry(index_key).or_default().insert(key);
+        while inner.entries.len() > self.capacity {
+            let (key, value) = inner.entries.pop_lru().unwrap();
+            inner.unindex((self.indexer)(&value), &key);
+            self.stats.record_eviction();
+        }
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let mut inner = self.lock();
+        let value = inner.entries.remove(key)?;
+        inner.unindex((self.indexer)(&value), key);
+        Some(value)
+    }
+
+    /// Removes all entries whose values have the given index key. Returns the
+    /// number of removed entries.
+    pub fn invalidate_by_index(&self, index_key: &I) -> usize {
+        let mut inner = self.lock();
+        let keys = match inner.index.remove(index_key) {
+            Some(keys) => keys,
+            None => return 0,
+        };
+        for key in &keys {
+            inner.entries.remove(key);
+        }
+        keys.len()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats.snapshot()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<K, V, I>> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IndexedCache;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Record {
+        owner: &'static str,
+        data: u32,
+    }
+
+    fn cache(capacity: usize) -> IndexedCache<u32, Record, &'static str> {
+        IndexedCache::new(capacity, |record: &Record| record.owner)
+    }
+
+    #[test]
+    fn test_invalidate_by_index() {
+        let cache = cache(10);
+        cache.insert(1, Record { owner: "alice", data: 1 });
+        cache.insert(2, Record { owner: "bob", data: 2 });
+        cache.insert(3, Record { owner: "alice", data: 3 });
+        cache.insert(4, Record { owner: "alice", data: 4 });
+        // Record 4 changes owner, so it's no longer indexed under "alice".
+        cache.insert(4, Record { owner: "carol", data: 4 });
+
+        assert_eq!(cache.invalidate_by_index(&"alice"), 2);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&3), None);
+        assert_eq!(cache.get(&2), Some(Record { owner: "bob", data: 2 }));
+        assert_eq!(cache.get(&4), Some(Record { owner: "carol", data: 4 }));
+        assert_eq!(cache.invalidate_by_index(&"alice"), 0);
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_eviction_updates_index() {
+        let cache = cache(2);
+        cache.insert(1, Record { owner: "alice", data: 1 });
+        cache.insert(2, Record { owner: "alice", data: 2 });
+        cache.insert(3, Record { owner: "bob", data: 3 });
+        assert_eq!(cache.remove(&3), Some(Record { owner: "bob", data: 3 }));
+        assert_eq!(cache.invalidate_by_index(&"bob"), 0);
+        assert_eq!(cache.invalidate_by_index(&"alice"), 1);
+        assert!(cache.is_empty());
+        assert!(cache.lock().index.is_empty());
+    }
+}
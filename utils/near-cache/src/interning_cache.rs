@@ -0,0 +1,94 @@
+use crate::{CacheStats, SyncLruCache};
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// `SyncLruCache` which stores values as `Arc<V>` shared between all keys
+/// mapping to equal values.
+///
+/// Values computed by `get_or_insert` are looked up in an interning set by
+/// value (hence `V: Eq + Hash`), so N keys mapping to the same value hold N
+/// pointers to a single allocation instead of N copies.
+///
+/// Values which are no longer referenced by the cache (or anybody else) are
+/// dropped from the interning set once it grows past twice the cache capacity.
+pub struct InterningCache<K, V> {
+    cache: SyncLruCache<K, Arc<V>>,
+    interned: Mutex<HashSet<Arc<V>>>,
+}
+
+impl<K, V> InterningCache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Hash + Eq,
+{
+    pub fn new(capacity: usize) -> Self {
+        Self { cache: SyncLruCache::new(capacity), interned: Mutex::new(HashSet::new()) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    pub fn get(&self, key: &K) -> Option<Arc<V>> {
+        self.cache.get(key)
+    }
+
+    /// Returns the cached value, computing it with `f` on a miss. If an equal
+    /// value is already interned, the existing allocation is reused.
+    pub fn get_or_insert<F>(&self, key: K, f: F) -> Arc<V>
+    where
+        F: FnOnce(&K) -> V,
+    {
+        self.cache.get_or_insert(key, |key| self.intern(f(key)))
+    }
+
+    fn intern(&self, value: V) -> Arc<V> {
+        let mut interned = self.interned.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(existing) = interned.get(&value) {
+            return existing.clone();
+        }
+        if interned.len() >= 2 * self.cache.capacity() {
+            interned.retain(|value| Arc::strong_count(value) > 1);
+        }
+        let value = Arc::new(value);
+        interned.insert(value.clone());
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InterningCache;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_equal_values_share_allocation() {
+        let cache = InterningCache::new(10);
+        let a = cache.get_or_insert(1, |key| format!("parity {}", key % 2));
+        let b = cache.get_or_insert(3, |key| format!("parity {}", key % 2));
+        let c = cache.get_or_insert(2, |key| format!("parity {}", key % 2));
+        assert!(Arc::ptr_eq(&a, &b));
+        assert!(!Arc::ptr_eq(&a, &c));
+        assert!(Arc::ptr_eq(&cache.get(&1).unwrap(), &cache.get(&3).unwrap()));
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn test_unused_values_are_pruned() {
+        let cache = InterningCache::new(1);
+        for key in 0..10 {
+            cache.get_or_insert(key, |key| *key);
+        }
+        assert_eq!(cache.len(), 1);
+        assert!(cache.interned.lock().unwrap().len() <= 2);
+    }
+}
@@ -0,0 +1,139 @@
+//! Latency tracking of cache operations, enabled with the `op_latency` feature.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the histogram buckets. The last bucket is unbounded.
+pub const LATENCY_BUCKETS: [Duration; 7] = [
+    Duration::from_micros(1),
+    Duration::from_micros(10),
+    Duration::from_micros(100),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+];
+
+/// Distribution of the durations of one kind of operation.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    pub count: u64,
+    pub total: Duration,
+    pub max: Duration,
+    /// `buckets[i]` counts operations which took at most `LATENCY_BUCKETS[i]`
+    /// (and more than the previous bound). The last element counts the rest.
+    pub buckets: [u64; LATENCY_BUCKETS.len() + 1],
+}
+
+/// Latency histograms of the individual cache operations.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OpLatencies {
+    pub get: LatencyHistogram,
+    pub insert: LatencyHistogram,
+    pub get_or_insert: LatencyHistogram,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Op {
+    Get,
+    Insert,
+    GetOrInsert,
+}
+
+#[derive(Default)]
+struct AtomicLatencyHistogram {
+    count: AtomicU64,
+    total_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+}
+
+impl AtomicLatencyHistogram {
+    fn record(&self, latency: Duration) {
+        let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| latency <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> LatencyHistogram {
+        let mut buckets = [0; LATENCY_BUCKETS.len() + 1];
+        for (bucket, count) in buckets.iter_mut().zip(self.buckets.iter()) {
+            *bucket = count.load(Ordering::Relaxed);
+        }
+        LatencyHistogram {
+            count: self.count.load(Ordering::Relaxed),
+            total: Duration::from_nanos(self.total_nanos.load(Ordering::Relaxed)),
+            max: Duration::from_nanos(self.max_nanos.load(Ordering::Relaxed)),
+            buckets,
+        }
+    }
+}
+
+/// Measures operations with `Instant::now()` by default rather than
+/// `Clock::instant()`, so that timing them doesn't consume the samples of a
+/// mock clock.
+pub(crate) struct LatencyRecorder {
+    get: AtomicLatencyHistogram,
+    insert: AtomicLatencyHistogram,
+    get_or_insert: AtomicLatencyHistogram,
+    now: Box<dyn Fn() -> Instant + Send + Sync>,
+}
+
+impl Default for LatencyRecorder {
+    fn default() -> Self {
+        Self {
+            get: Default::default(),
+            insert: Default::default(),
+            get_or_insert: Default::default(),
+            now: Box::new(Instant::now),
+        }
+    }
+}
+
+impl LatencyRecorder {
+    /// Makes the recorder read the time from `now`, e.g. a mock clock.
+    pub fn set_clock(&mut self, now: impl Fn() -> Instant + Send + Sync + 'static) {
+        self.now = Box::new(now);
+    }
+
+    /// Starts measuring an operation. The latency is recorded when the returned
+    /// timer is dropped.
+    pub fn start(&self, op: Op) -> OpTimer<'_> {
+        OpTimer { recorder: self, op, started: (self.now)() }
+    }
+
+    pub fn snapshot(&self) -> OpLatencies {
+        OpLatencies {
+            get: self.get.snapshot(),
+            insert: self.insert.snapshot(),
+            get_or_insert: self.get_or_insert.snapshot(),
+        }
+    }
+
+    fn histogram(&self, op: Op) -> &AtomicLatencyHistogram {
+        match op {
+            Op::Get => &self.get,
+            Op::Insert => &self.insert,
+            Op::GetOrInsert => &self.get_or_insert,
+        }
+    }
+}
+
+pub(crate) struct OpTimer<'a> {
+    recorder: &'a LatencyRecorder,
+    op: Op,
+    started: Instant,
+}
+
+impl Drop for OpTimer<'_> {
+    fn drop(&mut self) {
+        let latency = (self.recorder.now)().saturating_duration_since(self.started);
+        self.recorder.histogram(self.op).record(latency);
+    }
+}
@@ -0,0 +1,114 @@
+//! In-memory caches shared by nearcore components.
+
+/// Emits a `trace` event describing a cache operation when the `tracing`
+/// feature is enabled, and compiles to nothing otherwise. `key` is an
+/// `Option` of the key's `Debug` representation, which is logged as the `key`
+/// field if present.
+macro_rules! trace_op {
+    ($op:literal, key = $key:expr) => {
+        #[cfg(feature = "tracing")]
+        match $key {
+            Some(key) => tracing::trace!(target: "near_cache", op = $op, key = ?key),
+            None => tracing::trace!(target: "near_cache", op = $op),
+        }
+    };
+    ($op:literal, key = $key:expr, $hit:expr) => {
+        #[cfg(feature = "tracing")]
+        match $key {
+            Some(key) => tracing::trace!(target: "near_cache", op = $op, hit = $hit, key = ?key),
+            None => tracing::trace!(target: "near_cache", op = $op, hit = $hit),
+        }
+    };
+}
+
+use std::sync::Arc;
+
+/// Called with every entry a cache is about to evict to stay within its
+/// capacity, which it evicts or keeps as the callback decides.
+pub(crate) type EvictionCallback<K, V> = Arc<dyn Fn(&K, &V) -> EvictDecision + Send + Sync>;
+/// What to do with an entry passed to an eviction callback.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictDecision {
+    Evict,
+    /// Keeps the entry as the most recently used one and evicts the next
+    /// candidate instead. Once every candidate was kept during a single
+    /// eviction, the next one is evicted regardless, so that the cache still
+    /// fits its capacity.
+    Keep,
+}
+
+/// Called with all entries a cache evicted at once to stay within its capacity.
+pub(crate) type EvictionBatchCallback<K, V> = Arc<dyn Fn(Vec<(K, V)>) + Send + Sync>;
+
+mod auto_sized_cache;
+mod builder;
+mod cache_reader;
+mod cache_snapshot;
+mod compact_ttl_cache;
+mod compressed_cache;
+mod eviction_channel;
+mod forked_cache;
+#[cfg(feature = "hot_keys")]
+mod hot_keys;
+mod indexed_cache;
+mod interning_cache;
+#[cfg(feature = "op_latency")]
+mod latency;
+mod loading_cache;
+mod lru;
+mod maintenance;
+mod namespaced_cache;
+mod ordered_cache;
+mod persistence;
+mod request_coalescer;
+#[cfg(feature = "reuse_distance")]
+mod reuse_distance;
+mod shadow_cache;
+mod sharded_cache;
+mod stats;
+mod sync_lru_cache;
+mod tracing_cache;
+mod ttl_cache;
+mod turnstile;
+mod two_queue_cache;
+mod typed_key;
+mod versioned_cache;
+mod weak_cache;
+mod weighted_cache;
+mod windowed_stats;
+mod write_back_cache;
+
+pub use auto_sized_cache::{AutoSizeConfig, AutoSizedCache};
+pub use builder::{Cache, CacheBuilder};
+pub use cache_reader::CacheReader;
+pub use cache_snapshot::{CacheSnapshot, SnapshotReader};
+pub use compact_ttl_cache::CompactTtlCache;
+#[cfg(feature = "flate2")]
+pub use compressed_cache::Deflate;
+pub use compressed_cache::{Codec, CompressedCache, CompressionStats};
+pub use eviction_channel::{eviction_channel, EvictionReceiver, EvictionSender};
+pub use forked_cache::ForkedCache;
+pub use indexed_cache::IndexedCache;
+pub use interning_cache::InterningCache;
+#[cfg(feature = "op_latency")]
+pub use latency::{LatencyHistogram, OpLatencies, LATENCY_BUCKETS};
+pub use loading_cache::{LoadTimeout, LoadingCache};
+pub use maintenance::{MaintenanceHandle, MaintenanceStats};
+pub use namespaced_cache::NamespacedCache;
+pub use ordered_cache::OrderedCache;
+pub use request_coalescer::RequestCoalescer;
+#[cfg(feature = "reuse_distance")]
+pub use reuse_distance::ReuseDistances;
+pub use shadow_cache::ShadowCache;
+pub use sharded_cache::ShardedCache;
+pub use stats::{CacheMetrics, CacheStats, LockWaitStats};
+pub use sync_lru_cache::SyncLruCache;
+pub use tracing_cache::{replay, CacheOp, TracingCache};
+pub use ttl_cache::{EntryInfo, TtlCache};
+pub use two_queue_cache::TwoQueueCache;
+pub use typed_key::{TypedCache, TypedKey};
+pub use versioned_cache::VersionedCache;
+pub use weak_cache::WeakCache;
+pub use weighted_cache::{InsertError, MemSized, WeightedCache};
+pub use windowed_stats::WindowStats;
+pub use write_back_cache::{WriteBackCache, WriteBackStore};
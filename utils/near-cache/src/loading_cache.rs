@@ -0,0 +1,295 @@
+use crate::{RequestCoalescer, SyncLruCache};
+use futures::future::{self, Either};
+use futures::StreamExt;
+use near_primitives::time::Clock;
+use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+/// Error of `LoadingCache::get_or_load_timeout`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoadTimeout {
+    pub timeout: Duration,
+}
+
+impl fmt::Display for LoadTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "value wasn't loaded within {:?}", self.timeout)
+    }
+}
+
+impl std::error::Error for LoadTimeout {}
+
+/// `SyncLruCache` which fills misses by running an async loader.
+///
+/// Concurrent misses for the same key share a single load. Loads of distinct
+/// keys run concurrently, but at most `max_concurrent_loads` at once: the
+/// others wait for a free slot in the order they asked for one. This protects
+/// backends which can only serve a limited number of requests at a time.
+pub struct LoadingCache<K, V> {
+    cache: SyncLruCache<K, V>,
+    /// Loads in progress, shared by everyone missing the same key.
+    in_flight: RequestCoalescer<K, V>,
+    load_slots: Semaphore,
+}
+
+impl<K, V> LoadingCache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub fn new(capacity: usize, max_concurrent_loads: usize) -> Self {
+        assert!(max_concurrent_loads > 0);
+        Self {
+            cache: SyncLruCache::new(capacity),
+            in_flight: RequestCoalescer::new(),
+            load_slots: Semaphore::new(max_concurrent_loads),
+        }
+    }
+
+    /// The underlying cache, e.g. to look up or insert values directly.
+    pub fn cache(&self) -> &SyncLruCache<K, V> {
+        &self.cache
+    }
+
+    /// Returns the cached value, loading it with `load` on a miss.
+    ///
+    /// If a load of the key is already in progress, waits for its result
+    /// instead of calling `load`. If the future loading the key is dropped
+    /// before it finishes, one of the waiting callers loads it instead.
+    pub async fn get_or_load<F, Fut>(&self, key: K, load: F) -> V
+    where
+        F: FnOnce(K) -> Fut,
+        Fut: Future<Output = V>,
+    {
+        if let Some(value) = self.cache.get(&key) {
+            return value;
+        }
+        self.in_flight
+            .run(key, |key| async move {
+                // The previous load may have finished while we were waiting.
+                if let Some(value) = self.cache.peek(&key) {
+                    return value;
+                }
+                let _slot = self.load_slots.acquire().await.expect("semaphore is never closed");
+                let value = load(key.clone()).await;
+                self.cache.insert(key, value.clone());
+                value
+            })
+            .await
+    }
+
+    /// Like `get_or_load`, but gives up waiting for the value after `timeout`,
+    /// so that callers don't wait forever for a load which hangs. The timeout
+    /// is measured with `Clock::sleep`, i.e. with tokio's timer, which tests
+    /// can drive with `tokio::time::pause`, or by the mock clock if active: a
+    /// load which isn't done when first polled then times out right away and
+    /// moves the frozen mock time forward by `timeout`.
+    ///
+    /// The load runs as part of the call which started it, so if that caller
+    /// times out, its load is dropped, i.e. cancelled, and one of the callers
+    /// still waiting starts another load, bounded by its own timeout.
+    pub async fn get_or_load_timeout<F, Fut>(
+        &self,
+        key: K,
+        load: F,
+        timeout: Duration,
+    ) -> Result<V, LoadTimeout>
+    where
+        F: FnOnce(K) -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let load = self.get_or_load(key, load);
+        let timer = Clock::sleep(timeout);
+        futures::pin_mut!(load, timer);
+        match future::select(load, timer).await {
+            Either::Left((value, _)) => Ok(value),
+            Either::Right(((), _)) => Err(LoadTimeout { timeout }),
+        }
+    }
+
+    /// Loads all `keys` which aren't cached yet, e.g. to pre-populate the cache
+    /// on startup, with at most `concurrency` keys in progress at a time (and
+    /// never more loads than `max_concurrent_loads`). Duplicate keys and keys
+    /// already being loaded by others are loaded only once.
+    pub async fn warm<F, Fut>(&self, keys: Vec<K>, load: F, concurrency: usize)
+    where
+        F: Fn(K) -> Fut,
+        Fut: Future<Output = V>,
+    {
+        assert!(concurrency > 0);
+        futures::stream::iter(keys)
+            .for_each_concurrent(concurrency, |key| async {
+                self.get_or_load(key, &load).await;
+            })
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LoadTimeout, LoadingCache};
+    use near_primitives::time::{Clock, MockClockGuard};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_max_concurrent_loads() {
+        tokio::time::pause();
+        let cache = Arc::new(LoadingCache::new(100, 3));
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let tasks: Vec<_> = (0..20)
+            .map(|key| {
+                let (cache, running, peak) = (cache.clone(), running.clone(), peak.clone());
+                tokio::spawn(async move {
+                    let value = cache
+                        .get_or_load(key, |key| async move {
+                            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                            peak.fetch_max(now, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(10)).await;
+                            running.fetch_sub(1, Ordering::SeqCst);
+                            key * 2
+                        })
+                        .await;
+                    (key, value)
+                })
+            })
+            .collect();
+        for task in tasks {
+            let (key, value) = task.await.unwrap();
+            assert_eq!(value, key * 2);
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 3);
+        assert_eq!(cache.cache().len(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_single_load_per_key() {
+        tokio::time::pause();
+        let cache = Arc::new(LoadingCache::new(100, 3));
+        let loads = Arc::new(AtomicUsize::new(0));
+        let tasks: Vec<_> = (0..5)
+            .map(|_| {
+                let (cache, loads) = (cache.clone(), loads.clone());
+                tokio::spawn(async move {
+                    cache
+                        .get_or_load(1, |_| async move {
+                            loads.fetch_add(1, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(10)).await;
+                            "a"
+                        })
+                        .await
+                })
+            })
+            .collect();
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), "a");
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert!(cache.in_flight.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_hanging_load_times_out() {
+        tokio::time::pause();
+        let cache = Arc::new(LoadingCache::new(100, 3));
+        let loads = Arc::new(AtomicUsize::new(0));
+        let timeout = Duration::from_millis(100);
+        let waiter = |cache: Arc<LoadingCache<u32, u32>>, loads: Arc<AtomicUsize>| async move {
+            let start = tokio::time::Instant::now();
+            let result = cache
+                .get_or_load_timeout(
+                    1,
+                    |_| async move {
+                        loads.fetch_add(1, Ordering::SeqCst);
+                        futures::future::pending().await
+                    },
+                    timeout,
+                )
+                .await;
+            (result, start.elapsed())
+        };
+        let first = tokio::spawn(waiter(cache.clone(), loads.clone()));
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let second = tokio::spawn(waiter(cache.clone(), loads.clone()));
+
+        // The second caller took over the cancelled load when the first one
+        // gave up, and waited for its own timeout.
+        for waiter in [first, second] {
+            let (result, waited) = waiter.await.unwrap();
+            assert_eq!(result, Err(LoadTimeout { timeout }));
+            // Tokio's timer rounds deadlines up to whole milliseconds.
+            assert!(waited >= timeout && waited <= timeout + Duration::from_millis(1));
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+        assert!(cache.cache().is_empty());
+        assert!(cache.in_flight.is_empty());
+
+        let loaded = cache.get_or_load_timeout(1, |key| async move { key + 1 }, timeout).await;
+        assert_eq!(loaded, Ok(2));
+    }
+
+    #[tokio::test]
+    async fn test_load_timeout_with_mock_clock() {
+        let start = Clock::instant();
+        let mock_clock_guard = MockClockGuard::default();
+        mock_clock_guard.set_instant(start);
+        let cache = LoadingCache::new(100, 3);
+        let loads = AtomicUsize::new(0);
+        let timeout = Duration::from_secs(5);
+        let waiter = || {
+            cache.get_or_load_timeout(
+                1,
+                |_| {
+                    loads.fetch_add(1, Ordering::SeqCst);
+                    futures::future::pending::<u32>()
+                },
+                timeout,
+            )
+        };
+
+        // Each waiter starts its own load once the previous one was cancelled,
+        // and times out after its own timeout of mock time.
+        let (first, second) = futures::join!(waiter(), waiter());
+        assert_eq!(first, Err(LoadTimeout { timeout }));
+        assert_eq!(second, Err(LoadTimeout { timeout }));
+        assert_eq!(mock_clock_guard.current_instant(), Some(start + 2 * timeout));
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+        assert!(cache.in_flight.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_warm() {
+        tokio::time::pause();
+        let cache = LoadingCache::new(100, 10);
+        cache.cache().insert(0, 0);
+        let loads = AtomicUsize::new(0);
+        let running = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        let keys = vec![0, 1, 2, 3, 1, 4, 5, 2, 6, 7, 8, 9];
+        cache
+            .warm(
+                keys,
+                |key| {
+                    let (loads, running, peak) = (&loads, &running, &peak);
+                    async move {
+                        loads.fetch_add(1, Ordering::SeqCst);
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        peak.fetch_max(now, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                        running.fetch_sub(1, Ordering::SeqCst);
+                        key * 3
+                    }
+                },
+                4,
+            )
+            .await;
+        assert!((0..10).all(|key| cache.cache().peek(&key) == Some(key * 3)));
+        assert_eq!(loads.load(Ordering::SeqCst), 9);
+        assert!(peak.load(Ordering::SeqCst) <= 4);
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Single-threaded map which remembers the order in which its keys were used.
+///
+/// Every access to a key through a `&mut self` method bumps it to the most
+/// recently used position. The map itself is unbounded: callers decide when to
+/// shrink it with `pop_lru`.
+pub(crate) struct LruCache<K, V> {
+    map: HashMap<K, Entry<V>>,
+    /// Keys ordered from the least to the most recently used.
+    order: BTreeMap<u64, K>,
+    /// Monotonically increasing counter used to stamp accesses.
+    tick: u64,
+}
+
+struct Entry<V> {
+    value: V,
+    tick: u64,
+}
+
+impl<K, V> LruCache<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    pub fn new() -> Self {
+        Self { map: HashMap::new(), order: BTreeMap::new(), tick: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    /// Returns the value without changing the recency of the key.
+    pub fn peek(&self, key: &K) -> Option<&V> {
+        self.map.get(key).map(|entry| &entry.value)
+    }
+
+    /// Mutable version of `peek`.
+    pub fn peek_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.map.get_mut(key).map(|entry| &mut entry.value)
+    }
+
+    /// Returns the value and marks the key as the most recently used.
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        self.get_mut(key).map(|value| &*value)
+    }
+
+    /// Mutable version of `get`.
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let tick = self.next_tick();
+        let entry = self.map.get_mut(key)?;
+        let key = self.order.remove(&entry.tick).unwrap();
+        entry.tick = tick;
+        self.order.insert(tick, key);
+        Some(&mut entry.value)
+    }
+
+    /// Inserts the value as the most recently used one, returning the value
+    /// previously stored under the same key.
+    pub fn put(&mut self, key: K, value: V) -> Option<V> {
+        let tick = self.next_tick();
+        let previous = self.map.insert(key.clone(), Entry { value, tick });
+        if let Some(previous) = &previous {
+            self.order.remove(&previous.tick);
+        }
+        self.order.insert(tick, key);
+        previous.map(|entry| entry.value)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let entry = self.map.remove(key)?;
+        self.order.remove(&entry.tick);
+        Some(entry.value)
+    }
+
+    /// Returns the least recently used entry without removing it.
+    pub fn peek_lru(&self) -> Option<(&K, &V)> {
+        let key = self.order.values().next()?;
+        Some((key, &self.map[key].value))
+    }
+
+    /// Removes and returns the least recently used entry.
+    pub fn pop_lru(&mut self) -> Option<(K, V)> {
+        let tick = *self.order.keys().next()?;
+        let key = self.order.remove(&tick).unwrap();
+        let entry = self.map.remove(&key).unwrap();
+        Some((key, entry.value))
+    }
+
+    /// Removes and returns the least recently used entry whose key `skip`
+    /// returns false for.
+    pub fn pop_lru_except<F>(&mut self, mut skip: F) -> Option<(K, V)>
+    where
+        F: FnMut(&K) -> bool,
+    {
+        let tick = *self.order.iter().find(|(_, key)| !skip(key))?.0;
+        let key = self.order.remove(&tick).unwrap();
+        let entry = self.map.remove(&key).unwrap();
+        Some((key, entry.value))
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+        self.order.clear();
+    }
+
+    /// Removes all entries for which `f` returns false.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        self.drain_where(|key, value| !f(key, value));
+    }
+
+    /// Removes and returns the entries for which `f` returns true, from the
+    /// most to the least recently used.
+    pub fn drain_where<F>(&mut self, mut f: F) -> Vec<(K, V)>
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let keys: Vec<K> =
+            self.iter().filter(|(key, value)| f(key, value)).map(|(key, _)| key.clone()).collect();
+        let mut drained = Vec::with_capacity(keys.len());
+        for key in keys {
+            let value = self.remove(&key).unwrap();
+            drained.push((key, value));
+        }
+        drained
+    }
+
+    /// Releases memory held by the map beyond what its entries need.
+    pub fn shrink_to_fit(&mut self) {
+        self.map.shrink_to_fit();
+    }
+
+    /// Number of entries the map can hold without reallocating.
+    #[cfg(test)]
+    pub fn allocated_capacity(&self) -> usize {
+        self.map.capacity()
+    }
+
+    /// Iterates over the values in no particular order, without changing their
+    /// recency.
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.map.values_mut().map(|entry| &mut entry.value)
+    }
+
+    /// Iterates over the entries from the most to the least recently used.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.order.values().rev().map(move |key| (key, &self.map[key].value))
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LruCache;
+
+    #[test]
+    fn test_recency_order() {
+        let mut cache = LruCache::new();
+        cache.put(1, "a");
+        cache.put(2, "b");
+        cache.put(3, "c");
+        assert_eq!(cache.get(&1), Some(&"a"));
+        assert!(cache.contains_key(&2));
+        let keys: Vec<_> = cache.iter().map(|(key, _)| *key).collect();
+        assert_eq!(keys, vec![1, 3, 2]);
+
+        assert_eq!(cache.pop_lru(), Some((2, "b")));
+        assert_eq!(cache.put(3, "d"), Some("c"));
+        assert_eq!(cache.pop_lru(), Some((1, "a")));
+        assert_eq!(cache.remove(&3), Some("d"));
+        assert_eq!(cache.pop_lru(), None);
+        assert_eq!(cache.len(), 0);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::TtlCache;
+use near_primitives::time::Clock;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Handle of the task started by `TtlCache::spawn_maintenance`. Dropping it
+/// stops the task.
+pub struct MaintenanceHandle {
+    task: JoinHandle<()>,
+    gauges: Arc<Gauges>,
+}
+
+/// Values the maintenance task updates after every sweep.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MaintenanceStats {
+    /// Number of sweeps run so far.
+    pub sweeps: u64,
+    /// Total number of expired entries the sweeps dropped.
+    pub swept: u64,
+    /// Number of entries left in the cache after the last sweep.
+    pub len: u64,
+}
+
+#[derive(Default)]
+struct Gauges {
+    sweeps: AtomicU64,
+    swept: AtomicU64,
+    len: AtomicU64,
+}
+
+impl<K, V> TtlCache<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    /// Spawns a tokio task running `evict_expired` every `interval` and
+    /// updating the `MaintenanceStats` of the returned handle. The task waits
+    /// with `Clock::sleep`, so tests can drive it with `tokio::time::pause`,
+    /// or with the mock clock on a current-thread runtime: every time the
+    /// task gets to run, it then moves frozen mock time forward by `interval`
+    /// and sweeps. The task doesn't keep the cache alive: it stops once the
+    /// cache or the handle is dropped.
+    ///
+    /// Panics if called outside of a tokio runtime.
+    pub fn spawn_maintenance(self: &Arc<Self>, interval: Duration) -> MaintenanceHandle {
+        assert!(interval > Duration::ZERO);
+        let cache = Arc::downgrade(self);
+        let gauges = Arc::new(Gauges::default());
+        let task = {
+            let gauges = gauges.clone();
+            tokio::spawn(async move {
+                loop {
+                    // Under the mock clock the sleep returns right away, so
+                    // give the rest of the runtime a turn first.
+                    tokio::task::yield_now().await;
+                    Clock::sleep(interval).await;
+                    let cache = match cache.upgrade() {
+                        Some(cache) => cache,
+                        None => return,
+                    };
+                    let swept = cache.evict_expired();
+                    gauges.sweeps.fetch_add(1, Ordering::Relaxed);
+                    gauges.swept.fetch_add(swept as u64, Ordering::Relaxed);
+                    gauges.len.store(cache.len() as u64, Ordering::Relaxed);
+                }
+            })
+        };
+        MaintenanceHandle { task, gauges }
+    }
+}
+
+impl MaintenanceHandle {
+    pub fn stats(&self) -> MaintenanceStats {
+        MaintenanceStats {
+            sweeps: self.gauges.sweeps.load(Ordering::Relaxed),
+            swept: self.gauges.swept.load(Ordering::Relaxed),
+            len: self.gauges.len.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for MaintenanceHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MaintenanceHandle, MaintenanceStats};
+    use crate::TtlCache;
+    use near_primitives::time::{Clock, MockClockGuard};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Lets the maintenance task run until it completed `sweeps` sweeps.
+    async fn wait_for_sweeps(handle: &MaintenanceHandle, sweeps: u64) {
+        while handle.stats().sweeps < sweeps {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_periodic_sweeps() {
+        let start = Clock::instant();
+        let mock_clock_guard = MockClockGuard::default();
+        mock_clock_guard.set_instant(start);
+        // Spawned tasks take turns in order, while the test itself would only
+        // be polled after a batch of them.
+        tokio::spawn(async move {
+            let cache = Arc::new(TtlCache::new(10, Duration::from_secs(10)));
+            for key in 0..3 {
+                cache.insert(key, key);
+            }
+
+            let handle = cache.spawn_maintenance(Duration::from_secs(4));
+            // Sweeps at 4s and 8s find nothing expired yet.
+            wait_for_sweeps(&handle, 2).await;
+            assert_eq!(handle.stats(), MaintenanceStats { sweeps: 2, swept: 0, len: 3 });
+            assert_eq!(Clock::instant(), start + Duration::from_secs(8));
+            cache.insert(3, 3);
+
+            wait_for_sweeps(&handle, 3).await;
+            assert_eq!(handle.stats(), MaintenanceStats { sweeps: 3, swept: 3, len: 1 });
+            wait_for_sweeps(&handle, 5).await;
+            assert_eq!(handle.stats(), MaintenanceStats { sweeps: 5, swept: 4, len: 0 });
+            assert_eq!(Clock::instant(), start + Duration::from_secs(20));
+
+            // Dropping the handle stops the sweeps, and with them the mock time.
+            drop(handle);
+            cache.insert(4, 4);
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(Clock::instant(), start + Duration::from_secs(20));
+            assert_eq!(cache.len(), 1);
+        })
+        .await
+        .unwrap();
+    }
+}
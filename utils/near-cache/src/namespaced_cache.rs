@@ -0,0 +1,90 @@
+use crate::SyncLruCache;
+use std::hash::Hash;
+
+/// View of a `SyncLruCache` keyed by `(prefix, key)` pairs which only sees the
+/// entries with one prefix.
+///
+/// This lets several components share one cache (and its capacity) without
+/// their keys colliding. Created with `SyncLruCache::namespace`.
+pub struct NamespacedCache<'a, P, K, V> {
+    cache: &'a SyncLruCache<(P, K), V>,
+    prefix: P,
+}
+
+impl<P, K, V> SyncLruCache<(P, K), V>
+where
+    P: Hash + Eq + Clone,
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub fn namespace(&self, prefix: P) -> NamespacedCache<'_, P, K, V> {
+        NamespacedCache { cache: self, prefix }
+    }
+}
+
+impl<'a, P, K, V> NamespacedCache<'a, P, K, V>
+where
+    P: Hash + Eq + Clone,
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub fn prefix(&self) -> &P {
+        &self.prefix
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.cache.contains_key(&self.full_key(key.clone()))
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.cache.get(&self.full_key(key.clone()))
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        self.cache.insert(self.full_key(key), value)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.cache.remove(&self.full_key(key.clone()))
+    }
+
+    pub fn get_or_insert<F>(&self, key: K, f: F) -> V
+    where
+        F: FnOnce(&K) -> V,
+    {
+        self.cache.get_or_insert(self.full_key(key), |(_, key)| f(key))
+    }
+
+    /// Removes all entries of this namespace, leaving other namespaces intact.
+    pub fn clear_namespace(&self) {
+        self.cache.retain(|(prefix, _), _| *prefix != self.prefix)
+    }
+
+    fn full_key(&self, key: K) -> (P, K) {
+        (self.prefix.clone(), key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::SyncLruCache;
+
+    #[test]
+    fn test_namespaces_are_isolated() {
+        let cache = SyncLruCache::new(10);
+        let blocks = cache.namespace("blocks");
+        let chunks = cache.namespace("chunks");
+        blocks.insert(1, "block 1");
+        assert_eq!(chunks.get(&1), None);
+        assert_eq!(chunks.get_or_insert(1, |_| "chunk 1"), "chunk 1");
+        assert_eq!(blocks.get(&1), Some("block 1"));
+        assert_eq!(cache.len(), 2);
+
+        blocks.insert(2, "block 2");
+        blocks.clear_namespace();
+        assert!(!blocks.contains_key(&1));
+        assert!(!blocks.contains_key(&2));
+        assert_eq!(chunks.get(&1), Some("chunk 1"));
+        assert_eq!(cache.len(), 1);
+    }
+}
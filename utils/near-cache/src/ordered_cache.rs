@@ -0,0 +1,114 @@
+use crate::stats::{AtomicCacheStats, CacheStats};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Thread-safe cache holding at most `capacity` entries, which evicts the
+/// entry whose value is the smallest according to `compare` rather than the
+/// least recently used one, e.g. the one with the lowest score.
+///
+/// A value inserted into a full cache may thus be evicted right away if it is
+/// the smallest. Which of several equally small values goes first is
+/// unspecified. Finding the smallest value takes a scan of all entries, so
+/// this is meant for small caches.
+pub struct OrderedCache<K, V> {
+    inner: Mutex<HashMap<K, V>>,
+    capacity: usize,
+    compare: Box<dyn Fn(&V, &V) -> Ordering + Send + Sync>,
+    stats: AtomicCacheStats,
+}
+
+impl<K, V> OrderedCache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub fn new(
+        capacity: usize,
+        compare: impl Fn(&V, &V) -> Ordering + Send + Sync + 'static,
+    ) -> Self {
+        assert!(capacity > 0);
+        Self {
+            inner: Mutex::new(HashMap::new()),
+            capacity,
+            compare: Box::new(compare),
+            stats: AtomicCacheStats::default(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        match self.lock().get(key) {
+            Some(value) => {
+                self.stats.record_hit();
+                Some(value.clone())
+            }
+            None => {
+                self.stats.record_miss(false);
+                None
+            }
+        }
+    }
+
+    /// Inserts the value, then evicts the smallest entry if the cache is over
+    /// capacity and returns it.
+    pub fn insert(&self, key: K, value: V) -> Option<(K, V)> {
+        let mut entries = self.lock();
+        entries.insert(key, value);
+        if entries.len() <= self.capacity {
+            return None;
+        }
+        let smallest = entries
+            .iter()
+            .min_by(|(_, a), (_, b)| (self.compare)(a, b))
+            .map(|(key, _)| key.clone())
+            .unwrap();
+        self.stats.record_eviction();
+        entries.remove_entry(&smallest)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.lock().remove(key)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats.snapshot()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<K, V>> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OrderedCache;
+
+    #[test]
+    fn test_evicts_lowest_score() {
+        let cache = OrderedCache::new(3, |a: &(u32, &str), b: &(u32, &str)| a.0.cmp(&b.0));
+        assert_eq!(cache.insert("x", (5, "x")), None);
+        assert_eq!(cache.insert("y", (2, "y")), None);
+        assert_eq!(cache.insert("z", (7, "z")), None);
+        // Recency doesn't matter.
+        assert_eq!(cache.get(&"y"), Some((2, "y")));
+        assert_eq!(cache.insert("w", (4, "w")), Some(("y", (2, "y"))));
+        // Neither does the order of insertion.
+        assert_eq!(cache.insert("v", (1, "v")), Some(("v", (1, "v"))));
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.get(&"w"), Some((4, "w")));
+        assert_eq!(cache.stats().evictions, 2);
+    }
+}
@@ -0,0 +1,146 @@
+use crate::SyncLruCache;
+use borsh::{BorshDeserialize, BorshSerialize};
+use std::fs::File;
+use std::hash::Hash;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Start of every file written by `save_to_path`.
+const MAGIC: &[u8; 8] = b"nearlru\0";
+/// Version of the format of the entries following `MAGIC`. Bump it whenever
+/// the format changes, and teach `load_from_path` to migrate older versions
+/// where possible.
+const FORMAT_VERSION: u32 = 1;
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl<K, V> SyncLruCache<K, V>
+where
+    K: Hash + Eq + Clone + BorshSerialize + BorshDeserialize,
+    V: Clone + BorshSerialize + BorshDeserialize,
+{
+    /// Writes the entries to `path` (replacing the file if it exists) in
+    /// borsh format, so that `load_from_path` can restore them later, e.g. to
+    /// avoid a cold cache after a restart. Statistics aren't saved. The file
+    /// starts with a header naming the version of the format.
+    pub fn save_to_path(&self, path: &Path) -> io::Result<()> {
+        let mut entries = Vec::with_capacity(self.len());
+        self.for_each(|key, value| entries.push((key.clone(), value.clone())));
+        // Least recently used first, so inserting the entries in order
+        // restores their recency.
+        entries.reverse();
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        FORMAT_VERSION.serialize(&mut writer)?;
+        entries.serialize(&mut writer)?;
+        writer.flush()
+    }
+
+    /// Creates a cache holding the entries saved with `save_to_path`, in the
+    /// same recency order. If there are more than `capacity` of them, only the
+    /// most recently used ones are kept.
+    ///
+    /// Fails with `io::ErrorKind::InvalidData` if the file wasn't written by
+    /// `save_to_path` or uses a format version this code doesn't know, e.g.
+    /// one written by a newer node.
+    pub fn load_from_path(path: &Path, capacity: usize) -> io::Result<Self> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        let mut body = bytes
+            .strip_prefix(&MAGIC[..])
+            .ok_or_else(|| invalid_data(format!("{} is not a saved cache", path.display())))?;
+        let version = u32::deserialize(&mut body)?;
+        if version != FORMAT_VERSION {
+            return Err(invalid_data(format!(
+                "{} has cache format version {}, but only version {} is supported",
+                path.display(),
+                version,
+                FORMAT_VERSION
+            )));
+        }
+        let entries = Vec::<(K, V)>::try_from_slice(body)?;
+        let cache = Self::new(capacity);
+        let skip = entries.len().saturating_sub(capacity);
+        for (key, value) in entries.into_iter().skip(skip) {
+            cache.insert(key, value);
+        }
+        Ok(cache)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FORMAT_VERSION, MAGIC};
+    use crate::SyncLruCache;
+
+    fn entries(cache: &SyncLruCache<u32, String>) -> Vec<(u32, String)> {
+        let mut entries = Vec::new();
+        cache.for_each(|key, value| entries.push((*key, value.clone())));
+        entries
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache");
+        let cache = SyncLruCache::new(10);
+        for key in 0..5 {
+            cache.insert(key, format!("value {}", key));
+        }
+        cache.get(&1);
+        cache.save_to_path(&path).unwrap();
+
+        let loaded = SyncLruCache::load_from_path(&path, 10).unwrap();
+        assert_eq!(entries(&loaded), entries(&cache));
+        assert_eq!(entries(&loaded)[0].0, 1);
+
+        let truncated = SyncLruCache::<u32, String>::load_from_path(&path, 2).unwrap();
+        assert_eq!(entries(&truncated), entries(&cache)[..2].to_vec());
+        assert_eq!(truncated.stats().evictions, 0);
+    }
+
+    #[test]
+    fn test_load_corrupted_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache");
+        std::fs::write(&path, [1, 2, 3]).unwrap();
+        assert!(SyncLruCache::<u32, String>::load_from_path(&path, 10).is_err());
+        assert!(
+            SyncLruCache::<u32, String>::load_from_path(&dir.path().join("missing"), 10).is_err()
+        );
+    }
+
+    #[test]
+    fn test_format_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache");
+        let cache = SyncLruCache::new(10);
+        cache.insert(1, "a".to_string());
+        cache.save_to_path(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(bytes.starts_with(MAGIC));
+        assert_eq!(bytes[MAGIC.len()..MAGIC.len() + 4], FORMAT_VERSION.to_le_bytes());
+        let loaded = SyncLruCache::<u32, String>::load_from_path(&path, 10).unwrap();
+        assert_eq!(entries(&loaded), vec![(1, "a".to_string())]);
+
+        let mut bumped = bytes.clone();
+        bumped[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        std::fs::write(&path, bumped).unwrap();
+        let error = SyncLruCache::<u32, String>::load_from_path(&path, 10).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "{} has cache format version 2, but only version 1 is supported",
+                path.display()
+            )
+        );
+
+        // Files without the header, e.g. written before it was introduced.
+        std::fs::write(&path, &bytes[MAGIC.len() + 4..]).unwrap();
+        let error = SyncLruCache::<u32, String>::load_from_path(&path, 10).err().unwrap();
+        assert_eq!(error.to_string(), format!("{} is not a saved cache", path.display()));
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use tokio::sync::OnceCell;
+
+/// Deduplicates concurrent async requests: callers asking for the same key
+/// while a request for it is in flight share its result instead of issuing
+/// their own.
+///
+/// Nothing is kept once a request completes, so a later call issues a new
+/// request; combine with a cache (as `LoadingCache` does) to reuse results.
+pub struct RequestCoalescer<K, V> {
+    in_flight: Mutex<HashMap<K, Arc<OnceCell<V>>>>,
+}
+
+impl<K, V> Default for RequestCoalescer<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> RequestCoalescer<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub fn new() -> Self {
+        Self { in_flight: Mutex::new(HashMap::new()) }
+    }
+
+    /// Number of keys with a request in flight.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the result of `request(key)`, unless a request for the key is
+    /// already in flight, in which case it waits for that result instead.
+    ///
+    /// If the future running the request is dropped before it finishes, one of
+    /// the waiting callers runs its own `request` instead. Once all callers
+    /// are dropped, the key isn't in flight anymore.
+    pub async fn run<F, Fut>(&self, key: K, request: F) -> V
+    where
+        F: FnOnce(K) -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let cell =
+            self.lock().entry(key.clone()).or_insert_with(|| Arc::new(OnceCell::new())).clone();
+        let caller = Caller { coalescer: self, key, cell };
+        caller.cell.get_or_init(|| request(caller.key.clone())).await.clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<K, Arc<OnceCell<V>>>> {
+        self.in_flight.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Caller of `RequestCoalescer::run`, which removes the request from the ones
+/// in flight on drop once it completed, or when the caller was the last one
+/// waiting for it.
+struct Caller<'a, K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    coalescer: &'a RequestCoalescer<K, V>,
+    key: K,
+    cell: Arc<OnceCell<V>>,
+}
+
+impl<K, V> Drop for Caller<'_, K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    fn drop(&mut self) {
+        let mut in_flight = self.coalescer.lock();
+        // Other callers only get the cell with the lock held, so its count of
+        // references, held by the map and this caller, can't grow meanwhile.
+        let is_last = Arc::strong_count(&self.cell) == 2;
+        if (self.cell.initialized() || is_last)
+            && in_flight.get(&self.key).map_or(false, |current| Arc::ptr_eq(current, &self.cell))
+        {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RequestCoalescer;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    async fn request(
+        coalescer: Arc<RequestCoalescer<u32, String>>,
+        count: Arc<AtomicUsize>,
+    ) -> String {
+        coalescer
+            .run(7, |key| async move {
+                count.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                format!("response {}", key)
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_run_once() {
+        tokio::time::pause();
+        let coalescer = Arc::new(RequestCoalescer::new());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let tasks: Vec<_> =
+            (0..20).map(|_| tokio::spawn(request(coalescer.clone(), requests.clone()))).collect();
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), "response 7");
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert!(coalescer.is_empty());
+
+        // Completed requests aren't remembered.
+        assert_eq!(request(coalescer.clone(), requests.clone()).await, "response 7");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_poisoned_lock_is_ignored() {
+        let coalescer = Arc::new(RequestCoalescer::new());
+        let poisoner = coalescer.clone();
+        let result = std::thread::spawn(move || {
+            let _in_flight = poisoner.lock();
+            panic!("panic with the lock held");
+        })
+        .join();
+        assert!(result.is_err());
+        let response = coalescer.run(7, |key| async move { format!("response {}", key) }).await;
+        assert_eq!(response, "response 7");
+        assert!(coalescer.is_empty());
+    }
+}
@@ -0,0 +1,101 @@
+//! Reuse distances of lookups, enabled with the `reuse_distance` feature.
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Histogram of the reuse distances of lookups, see
+/// `SyncLruCache::with_reuse_distances`.
+///
+/// The reuse distance of a lookup is the number of distinct other keys looked
+/// up since the previous lookup of the same key. An LRU cache of capacity `c`
+/// which every missed key is inserted into hits exactly the lookups with a
+/// distance below `c`, so the histogram tells the hit rate of any capacity.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReuseDistances {
+    /// Number of lookups by distance.
+    pub histogram: Vec<u64>,
+    /// Number of lookups of keys never looked up before, or whose previous
+    /// lookup is too long ago to be tracked.
+    pub cold: u64,
+}
+
+impl ReuseDistances {
+    pub fn lookups(&self) -> u64 {
+        self.histogram.iter().sum::<u64>() + self.cold
+    }
+
+    /// Fraction of the lookups an LRU cache of `capacity` entries would hit.
+    /// Returns 0 without lookups.
+    pub fn hit_rate(&self, capacity: usize) -> f64 {
+        let lookups = self.lookups();
+        if lookups == 0 {
+            return 0.0;
+        }
+        let hits: u64 = self.histogram.iter().take(capacity).sum();
+        hits as f64 / lookups as f64
+    }
+}
+
+/// Computes the reuse distances of lookups of the last `max_keys` distinct
+/// keys. Lookups take time linear in their distance.
+pub(crate) struct ReuseDistanceTracker<K> {
+    max_keys: usize,
+    next_tick: u64,
+    /// Tick of the last lookup of every tracked key.
+    last_lookups: HashMap<K, u64>,
+    /// Tracked keys by the tick of their last lookup.
+    order: BTreeMap<u64, K>,
+    distances: ReuseDistances,
+}
+
+impl<K> ReuseDistanceTracker<K>
+where
+    K: Hash + Eq + Clone,
+{
+    pub fn new(max_keys: usize) -> Self {
+        assert!(max_keys > 0);
+        Self {
+            max_keys,
+            next_tick: 0,
+            last_lookups: HashMap::new(),
+            order: BTreeMap::new(),
+            distances: ReuseDistances::default(),
+        }
+    }
+
+    pub fn record(&mut self, key: &K) {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        match self.last_lookups.insert(key.clone(), tick) {
+            Some(last_tick) => {
+                let distance = self.order.range(last_tick + 1..).count();
+                let key = self.order.remove(&last_tick).unwrap();
+                self.order.insert(tick, key);
+                let histogram = &mut self.distances.histogram;
+                if histogram.len() <= distance {
+                    histogram.resize(distance + 1, 0);
+                }
+                histogram[distance] += 1;
+            }
+            None => {
+                self.order.insert(tick, key.clone());
+                if self.order.len() > self.max_keys {
+                    let oldest_tick = *self.order.keys().next().unwrap();
+                    let oldest = self.order.remove(&oldest_tick).unwrap();
+                    self.last_lookups.remove(&oldest);
+                }
+                self.distances.cold += 1;
+            }
+        }
+    }
+
+    pub fn distances(&self) -> ReuseDistances {
+        self.distances.clone()
+    }
+
+    pub fn clear(&mut self) {
+        self.last_lookups.clear();
+        self.order.clear();
+        self.distances = ReuseDistances::default();
+    }
+}
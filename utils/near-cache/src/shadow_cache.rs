@@ -0,0 +1,140 @@
+use crate::{Cache, CacheStats};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Called with the key of a lookup on which the shadow disagreed with the
+/// primary, and whether the primary had the key.
+type DivergenceCallback<K> = Box<dyn Fn(&K, bool) + Send + Sync>;
+
+/// Pair of caches which receive the same operations, for trying out a new
+/// cache configuration (e.g. a different policy or capacity) on real traffic
+/// before switching to it.
+///
+/// Results are always served from the primary cache. The shadow only
+/// answers the same lookups to find out whether it would have hit or missed
+/// differently, and such divergences are counted, passed to the callback set
+/// with `with_on_divergence` and, with the `tracing` feature, logged as
+/// warnings. Values aren't compared, since both caches store the same ones.
+pub struct ShadowCache<K, V> {
+    primary: Cache<K, V>,
+    shadow: Cache<K, V>,
+    divergences: AtomicU64,
+    on_divergence: Option<DivergenceCallback<K>>,
+}
+
+impl<K, V> ShadowCache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub fn new(primary: Cache<K, V>, shadow: Cache<K, V>) -> Self {
+        Self { primary, shadow, divergences: AtomicU64::new(0), on_divergence: None }
+    }
+
+    /// Makes the cache call `on_divergence` for every lookup on which the
+    /// shadow disagreed, e.g. to panic in tests or to collect samples of keys.
+    pub fn with_on_divergence<F>(mut self, on_divergence: F) -> Self
+    where
+        F: Fn(&K, bool) + Send + Sync + 'static,
+    {
+        self.on_divergence = Some(Box::new(on_divergence));
+        self
+    }
+
+    pub fn primary(&self) -> &Cache<K, V> {
+        &self.primary
+    }
+
+    pub fn shadow(&self) -> &Cache<K, V> {
+        &self.shadow
+    }
+
+    /// Returns the value cached by the primary, after looking the key up in
+    /// the shadow as well.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let value = self.primary.get(key);
+        let shadow_hit = self.shadow.get(key).is_some();
+        if value.is_some() != shadow_hit {
+            self.divergences.fetch_add(1, Ordering::Relaxed);
+            #[cfg(feature = "tracing")]
+            tracing::warn!(
+                target: "near_cache",
+                primary_hit = value.is_some(),
+                "Shadow cache diverged from the primary"
+            );
+            if let Some(on_divergence) = &self.on_divergence {
+                on_divergence(key, value.is_some());
+            }
+        }
+        value
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        self.shadow.insert(key.clone(), value.clone());
+        self.primary.insert(key, value);
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.shadow.remove(key);
+        self.primary.remove(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.primary.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.primary.is_empty()
+    }
+
+    /// Number of lookups on which the shadow disagreed with the primary.
+    pub fn divergences(&self) -> u64 {
+        self.divergences.load(Ordering::Relaxed)
+    }
+
+    /// Statistics of the primary cache. Those of the shadow are available
+    /// through `shadow().stats()`, e.g. to compare the hit rates.
+    pub fn stats(&self) -> CacheStats {
+        self.primary.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShadowCache;
+    use crate::CacheBuilder;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_divergence_is_reported() {
+        let diverged = Arc::new(Mutex::new(Vec::new()));
+        let cache = {
+            let diverged = diverged.clone();
+            ShadowCache::new(
+                CacheBuilder::new().capacity(3).build(),
+                // Too small, so it misses where the primary hits.
+                CacheBuilder::new().capacity(2).build(),
+            )
+            .with_on_divergence(move |key, primary_hit| {
+                diverged.lock().unwrap().push((*key, primary_hit))
+            })
+        };
+        for key in 1..=3 {
+            cache.insert(key, key * 10);
+        }
+        assert_eq!(cache.get(&1), Some(10));
+        assert_eq!(cache.get(&2), Some(20));
+        assert_eq!(cache.get(&3), Some(30));
+        assert_eq!(cache.get(&4), None);
+        assert_eq!(cache.divergences(), 1);
+        assert_eq!(*diverged.lock().unwrap(), vec![(1, true)]);
+        assert_eq!(cache.stats().hits, 3);
+        assert_eq!(cache.shadow().stats().hits, 2);
+
+        // Removals reach both caches, so the shadow agrees again.
+        assert_eq!(cache.remove(&3), Some(30));
+        assert_eq!(cache.get(&3), None);
+        assert_eq!(cache.divergences(), 1);
+        assert_eq!(cache.len(), 2);
+    }
+}
@@ -0,0 +1,235 @@
+use crate::builder::ShardFactory;
+use crate::{Cache, CacheStats};
+use std::hash::{BuildHasher, Hash, Hasher};
+
+/// Computes the hash which decides the shard a key goes to.
+pub(crate) type KeyHasher<K> = Box<dyn Fn(&K) -> u64 + Send + Sync>;
+
+pub(crate) fn key_hasher<K: Hash, S: BuildHasher + Send + Sync + 'static>(
+    hasher: S,
+) -> KeyHasher<K> {
+    Box::new(move |key| {
+        let mut state = hasher.build_hasher();
+        key.hash(&mut state);
+        state.finish()
+    })
+}
+
+/// Picks one of `buckets` buckets for the hash with the "jump consistent
+/// hash" of Lamping and Veach: going from `n` to `n + 1` buckets moves only
+/// about `1 / (n + 1)` of the hashes, all of them to the new bucket.
+fn jump_consistent_hash(mut hash: u64, buckets: usize) -> usize {
+    let (mut bucket, mut next) = (0, 0);
+    while next < buckets as u64 {
+        bucket = next;
+        hash = hash.wrapping_mul(2862933555777941757).wrapping_add(1);
+        next = ((bucket + 1) as f64 * ((1u64 << 31) as f64 / ((hash >> 33) + 1) as f64)) as u64;
+    }
+    bucket as usize
+}
+
+/// Cache split into independently locked shards to reduce lock contention.
+///
+/// Every key always goes to the same shard, chosen by consistent hashing of
+/// its hash, and each shard evicts on its own once it reaches its share of the
+/// capacity. Created with `CacheBuilder::shards`.
+pub struct ShardedCache<K, V> {
+    shards: Vec<Cache<K, V>>,
+    capacity: usize,
+    factory: ShardFactory<K, V>,
+    hasher: KeyHasher<K>,
+    /// Statistics of the shards removed by `reshard`.
+    removed_stats: CacheStats,
+}
+
+impl<K, V> ShardedCache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub(crate) fn new(
+        capacity: usize,
+        shard_count: usize,
+        factory: ShardFactory<K, V>,
+        hasher: KeyHasher<K>,
+    ) -> Self {
+        assert!(shard_count > 0);
+        let shard_capacity = shard_capacity(capacity, shard_count);
+        let shards = (0..shard_count).map(|_| factory.build(shard_capacity)).collect();
+        Self { shards, capacity, factory, hasher, removed_stats: CacheStats::default() }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Total capacity, which the shards share.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Changes the number of shards to `shard_count`, splitting the capacity
+    /// among them anew. Thanks to consistent hashing only keys which the new
+    /// assignment puts into another shard (about `1 / shard_count` of them
+    /// when adding one shard) are moved; the others stay where they are.
+    /// Entries move along with their recency and expiry, but shards which
+    /// end up over their new capacity evict as usual. The statistics of
+    /// removed shards keep counting towards `stats`.
+    pub fn reshard(&mut self, shard_count: usize) {
+        assert!(shard_count > 0);
+        let old_count = self.shards.len();
+        let shard_capacity = shard_capacity(self.capacity, shard_count);
+        // Make room before moving entries in, and shrink after moving them out.
+        for shard in self.shards.iter().take(shard_count) {
+            if shard_capacity > shard.capacity() {
+                shard.resize_shard(shard_capacity);
+            }
+        }
+        while self.shards.len() < shard_count {
+            self.shards.push(self.factory.build(shard_capacity));
+        }
+        let (shards, hasher) = (&self.shards, &self.hasher);
+        let index = |key: &K| jump_consistent_hash(hasher(key), shard_count);
+        for (i, shard) in shards.iter().enumerate().take(old_count) {
+            shard.move_shard_entries(|key| index(key) != i, |key| &shards[index(key)]);
+        }
+        for shard in self.shards.drain(shard_count..) {
+            self.removed_stats.accumulate(&shard.stats());
+        }
+        for shard in &self.shards {
+            if shard_capacity < shard.capacity() {
+                shard.resize_shard(shard_capacity);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(Cache::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(Cache::is_empty)
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.shard(key).get(key)
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        self.shard(&key).insert(key, value)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.shard(key).remove(key)
+    }
+
+    /// Statistics summed over all shards.
+    pub fn stats(&self) -> CacheStats {
+        let mut stats = self.removed_stats;
+        for shard in &self.shards {
+            stats.accumulate(&shard.stats());
+        }
+        stats
+    }
+
+    fn shard(&self, key: &K) -> &Cache<K, V> {
+        &self.shards[self.shard_index(key)]
+    }
+
+    fn shard_index(&self, key: &K) -> usize {
+        jump_consistent_hash((self.hasher)(key), self.shards.len())
+    }
+}
+
+/// Equal share of the capacity, rounded up.
+fn shard_capacity(capacity: usize, shard_count: usize) -> usize {
+    (capacity + shard_count - 1) / shard_count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{jump_consistent_hash, ShardedCache};
+    use crate::{Cache, CacheBuilder};
+    use near_primitives::time::{Clock, MockClockGuard};
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::BuildHasherDefault;
+    use std::time::Duration;
+
+    fn sharded(builder: CacheBuilder<u32, u32>) -> ShardedCache<u32, u32> {
+        match builder.hasher(BuildHasherDefault::<DefaultHasher>::default()).build() {
+            Cache::Sharded(cache) => cache,
+            _ => panic!("expected a sharded cache"),
+        }
+    }
+
+    #[test]
+    fn test_stats_are_summed() {
+        let cache = sharded(CacheBuilder::new().capacity(30).shards(3));
+        for key in 0..10 {
+            cache.insert(key, key * 2);
+        }
+        for key in 0..20 {
+            assert_eq!(cache.get(&key), if key < 10 { Some(key * 2) } else { None });
+        }
+        assert_eq!(cache.len(), 10);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (10, 10));
+    }
+
+    #[test]
+    fn test_jump_consistent_hash() {
+        for hash in 0..1000u64 {
+            let hash = hash.wrapping_mul(0x9e3779b97f4a7c15);
+            assert_eq!(jump_consistent_hash(hash, 1), 0);
+            // Growing by one bucket moves hashes only into the new bucket.
+            for buckets in 1..10 {
+                let bucket = jump_consistent_hash(hash, buckets + 1);
+                assert!(bucket == buckets || bucket == jump_consistent_hash(hash, buckets));
+            }
+        }
+    }
+
+    #[test]
+    fn test_reshard() {
+        let mut cache = sharded(CacheBuilder::new().capacity(4000).shards(4));
+        for key in 0..1000 {
+            cache.insert(key, key * 2);
+        }
+        let before: Vec<_> = (0..1000).map(|key| cache.shard_index(&key)).collect();
+        cache.reshard(5);
+        assert_eq!(cache.shard_count(), 5);
+        let stayed = (0..1000).filter(|&key| cache.shard_index(&key) == before[key as usize]);
+        // About 4/5 of the keys are expected to stay.
+        assert!(stayed.count() > 700);
+        assert_eq!(cache.len(), 1000);
+        assert!((0..1000).all(|key| cache.get(&key) == Some(key * 2)));
+        assert!((0..1000).all(|key| cache.shards[cache.shard_index(&key)].get(&key).is_some()));
+
+        let hits = cache.stats().hits;
+        cache.reshard(2);
+        assert_eq!(cache.stats().hits, hits);
+        assert_eq!(cache.len(), 1000);
+        assert!((0..1000).all(|key| cache.get(&key) == Some(key * 2)));
+        assert_eq!(cache.capacity(), 4000);
+        assert!(cache.shards.iter().all(|shard| shard.capacity() == 2000));
+    }
+
+    #[test]
+    fn test_reshard_evicts_and_keeps_expiry() {
+        let _mock_clock_guard = MockClockGuard::default();
+        let mut cache =
+            sharded(CacheBuilder::new().capacity(100).shards(2).ttl(Duration::from_secs(10)));
+        for key in 0..50 {
+            Clock::add_instant(Duration::ZERO);
+            cache.insert(key, key);
+        }
+        cache.reshard(4);
+        // Each shard holds at most 25 entries now, possibly fewer than before.
+        assert!(cache.len() <= 50);
+        assert!(cache.shards.iter().all(|shard| shard.len() <= 25));
+        for key in 0..50 {
+            Clock::add_instant(Duration::from_secs(10));
+            assert_eq!(cache.get(&key), None);
+        }
+    }
+}
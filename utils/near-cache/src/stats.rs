@@ -0,0 +1,172 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Counters describing how well a cache performs.
+///
+/// The caches keep these counters in atomics outside of their locks, so
+/// taking a snapshot with `stats()` never blocks nor allocates: it is a
+/// handful of relaxed loads into this plain `Copy` struct.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Number of lookups which found the key in the cache.
+    pub hits: u64,
+    /// Number of lookups which didn't find the key in the cache.
+    pub misses: u64,
+    /// Misses for keys which were recently evicted to make room for other
+    /// entries. A high number means the cache is too small.
+    pub capacity_misses: u64,
+    /// Misses for keys which haven't been seen recently (or ever).
+    pub cold_misses: u64,
+    /// Number of entries removed to keep the cache within its capacity.
+    pub evictions: u64,
+    /// Number of inserts dropped because all entries were pinned, see
+    /// `SyncLruCache::pin`.
+    pub rejected_inserts: u64,
+}
+
+impl CacheStats {
+    /// Fraction of lookups which were hits, or 0 if there were no lookups.
+    pub fn hit_ratio(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits as f64 / lookups as f64
+        }
+    }
+
+    /// Adds the counters of `other` to these ones.
+    pub(crate) fn accumulate(&mut self, other: &CacheStats) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.capacity_misses += other.capacity_misses;
+        self.cold_misses += other.cold_misses;
+        self.evictions += other.evictions;
+        self.rejected_inserts += other.rejected_inserts;
+    }
+}
+
+/// Receives the events a cache counts in its `CacheStats` as they happen, for
+/// reporting them to a metrics backend, see `SyncLruCache::with_metrics`.
+/// The methods are called with the cache locked, so they should be cheap and
+/// must not call back into the cache.
+pub trait CacheMetrics: Send + Sync {
+    fn on_hit(&self) {}
+    fn on_miss(&self) {}
+    /// Called for every entry removed to keep the cache within its capacity.
+    fn on_evict(&self) {}
+    /// Called for every value stored, including overwrites.
+    fn on_insert(&self) {}
+}
+
+/// Lock-free counterpart of `CacheStats` updated by the caches.
+#[derive(Default)]
+pub(crate) struct AtomicCacheStats {
+    hits: AtomicU64,
+    capacity_misses: AtomicU64,
+    cold_misses: AtomicU64,
+    evictions: AtomicU64,
+    rejected_inserts: AtomicU64,
+    /// Notified of each recorded event in addition to the counters.
+    metrics: Option<Arc<dyn CacheMetrics>>,
+}
+
+impl AtomicCacheStats {
+    pub fn set_metrics(&mut self, metrics: Arc<dyn CacheMetrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    pub fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        if let Some(metrics) = &self.metrics {
+            metrics.on_hit();
+        }
+    }
+
+    pub fn record_miss(&self, recently_evicted: bool) {
+        if recently_evicted {
+            self.capacity_misses.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.cold_misses.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.on_miss();
+        }
+    }
+
+    pub fn record_eviction(&self) {
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+        if let Some(metrics) = &self.metrics {
+            metrics.on_evict();
+        }
+    }
+
+    pub fn record_rejected_insert(&self) {
+        self.rejected_inserts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Inserts aren't counted, so this only notifies the metrics.
+    pub fn record_insert(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.on_insert();
+        }
+    }
+
+    pub fn reset(&self) {
+        self.hits.store(0, Ordering::Relaxed);
+        self.capacity_misses.store(0, Ordering::Relaxed);
+        self.cold_misses.store(0, Ordering::Relaxed);
+        self.evictions.store(0, Ordering::Relaxed);
+        self.rejected_inserts.store(0, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> CacheStats {
+        let capacity_misses = self.capacity_misses.load(Ordering::Relaxed);
+        let cold_misses = self.cold_misses.load(Ordering::Relaxed);
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: capacity_misses + cold_misses,
+            capacity_misses,
+            cold_misses,
+            evictions: self.evictions.load(Ordering::Relaxed),
+            rejected_inserts: self.rejected_inserts.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Time spent waiting to lock a cache, see `SyncLruCache::with_lock_wait_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LockWaitStats {
+    /// Number of times the lock was acquired.
+    pub acquisitions: u64,
+    /// Total time spent waiting for the lock.
+    pub total_wait: Duration,
+    /// Longest single wait for the lock.
+    pub max_wait: Duration,
+}
+
+/// Lock-free counterpart of `LockWaitStats`.
+#[derive(Default)]
+pub(crate) struct AtomicLockWaitStats {
+    acquisitions: AtomicU64,
+    total_wait_nanos: AtomicU64,
+    max_wait_nanos: AtomicU64,
+}
+
+impl AtomicLockWaitStats {
+    pub fn record(&self, wait: Duration) {
+        let nanos = wait.as_nanos().min(u64::MAX as u128) as u64;
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        self.total_wait_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.max_wait_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> LockWaitStats {
+        LockWaitStats {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            total_wait: Duration::from_nanos(self.total_wait_nanos.load(Ordering::Relaxed)),
+            max_wait: Duration::from_nanos(self.max_wait_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
@@ -0,0 +1,2026 @@
+#[cfg(feature = "hot_keys")]
+use crate::hot_keys::HotKeys;
+#[cfg(feature = "op_latency")]
+use crate::latency::{LatencyRecorder, Op, OpLatencies};
+use crate::lru::LruCache;
+#[cfg(feature = "reuse_distance")]
+use crate::reuse_distance::{ReuseDistanceTracker, ReuseDistances};
+use crate::stats::{
+    AtomicCacheStats, AtomicLockWaitStats, CacheMetrics, CacheStats, LockWaitStats,
+};
+use crate::turnstile::{Turn, Turnstile};
+use crate::windowed_stats::{WindowStats, WindowedStats};
+use crate::{EvictDecision, EvictionBatchCallback, EvictionCallback};
+use near_primitives::time::Clock;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+#[cfg(feature = "tracing")]
+use std::fmt;
+use std::hash::Hash;
+use std::ops::{Add, Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Thread-safe LRU cache holding at most `capacity` entries.
+///
+/// Besides the entries themselves, the cache remembers up to `capacity` keys
+/// which were recently evicted. This lets it tell apart misses caused by the
+/// cache being too small (`CacheStats::capacity_misses`) from misses for keys
+/// which simply weren't requested before (`CacheStats::cold_misses`).
+pub struct SyncLruCache<K, V> {
+    inner: Mutex<Inner<K, V>>,
+    /// Orders the threads waiting for `inner` if the cache is fair.
+    turnstile: Option<Turnstile>,
+    stats: AtomicCacheStats,
+    /// Set by `with_lock_wait_stats`.
+    lock_waits: Option<AtomicLockWaitStats>,
+    #[cfg(feature = "op_latency")]
+    latencies: LatencyRecorder,
+    /// Formats keys for the `trace` events, see `with_traced_keys`.
+    #[cfg(feature = "tracing")]
+    key_fmt: Option<KeyFmt<K>>,
+}
+
+struct Inner<K, V> {
+    capacity: usize,
+    entries: LruCache<K, V>,
+    /// Keys recently evicted from `entries`, bounded by `capacity`.
+    evicted: LruCache<K, ()>,
+    on_evict: Option<EvictionCallback<K, V>>,
+    on_evict_batch: Option<BatchCallback<K, V>>,
+    eviction_times: Option<EvictionTimes>,
+    windowed_stats: Option<WindowedStats>,
+    recency_buffer: Option<RecencyBuffer<K>>,
+    /// Number of `pin`s of each pinned key not yet undone by `unpin`.
+    pinned: HashMap<K, usize>,
+    /// Set by `with_strict_capacity`.
+    strict_capacity: bool,
+    #[cfg(feature = "hot_keys")]
+    hot_keys: Option<HotKeys<K>>,
+    #[cfg(feature = "reuse_distance")]
+    reuse_distances: Option<ReuseDistanceTracker<K>>,
+}
+
+/// `on_evict_batch` together with `V::clone`, so that only caches with a batch
+/// callback need their values to be `Clone`.
+struct BatchCallback<K, V> {
+    callback: EvictionBatchCallback<K, V>,
+    clone: fn(&V) -> V,
+}
+
+/// `Debug::fmt` of the keys, so that only caches with traced keys need them to
+/// be `Debug`.
+#[cfg(feature = "tracing")]
+type KeyFmt<K> = fn(&K, &mut fmt::Formatter<'_>) -> fmt::Result;
+
+/// A key formatted with the `KeyFmt` of its cache.
+#[cfg(feature = "tracing")]
+struct TracedKey<'a, K> {
+    key: &'a K,
+    fmt: KeyFmt<K>,
+}
+
+#[cfg(feature = "tracing")]
+impl<K> fmt::Debug for TracedKey<'_, K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (self.fmt)(self.key, f)
+    }
+}
+
+/// Times of the most recent evictions, see `SyncLruCache::with_eviction_rate`.
+struct EvictionTimes {
+    window: Duration,
+    max_len: usize,
+    /// Oldest first.
+    times: VecDeque<Instant>,
+}
+
+impl EvictionTimes {
+    fn record(&mut self, now: Instant) {
+        if self.times.len() == self.max_len {
+            self.times.pop_front();
+        }
+        self.times.push_back(now);
+    }
+
+    fn rate_per_sec(&mut self, now: Instant) -> f64 {
+        while let Some(&time) = self.times.front() {
+            if now.saturating_duration_since(time) < self.window {
+                break;
+            }
+            self.times.pop_front();
+        }
+        self.times.len() as f64 / self.window.as_secs_f64()
+    }
+}
+
+/// Keys looked up since the recency of the entries was last updated, see
+/// `SyncLruCache::with_batched_recency`.
+struct RecencyBuffer<K> {
+    /// In lookup order, with duplicates.
+    keys: Vec<K>,
+    max_keys: usize,
+    max_delay: Option<Duration>,
+    /// Scratch space of `Inner::flush_recency`, kept to reuse its allocation.
+    seen: HashSet<K>,
+    /// When the first of `keys` was recorded, if `max_delay` is set.
+    since: Option<Instant>,
+}
+
+impl<K> RecencyBuffer<K> {
+    fn record(&mut self, key: K) {
+        if self.keys.is_empty() && self.max_delay.is_some() {
+            self.since = Some(Clock::instant());
+        }
+        self.keys.push(key);
+    }
+
+    fn is_due(&self) -> bool {
+        if self.keys.len() >= self.max_keys {
+            return true;
+        }
+        match (self.max_delay, self.since) {
+            (Some(max_delay), Some(since)) if !self.keys.is_empty() => {
+                Clock::instant().saturating_duration_since(since) >= max_delay
+            }
+            _ => false,
+        }
+    }
+}
+
+impl<K, V> Inner<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    fn lookup(&mut self, key: &K, stats: &AtomicCacheStats) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.lookup_with(key, stats, V::clone)
+    }
+
+    fn lookup_with<R>(
+        &mut self,
+        key: &K,
+        stats: &AtomicCacheStats,
+        f: impl FnOnce(&V) -> R,
+    ) -> Option<R> {
+        #[cfg(feature = "hot_keys")]
+        if let Some(hot_keys) = &mut self.hot_keys {
+            hot_keys.record(key);
+        }
+        #[cfg(feature = "reuse_distance")]
+        if let Some(reuse_distances) = &mut self.reuse_distances {
+            reuse_distances.record(key);
+        }
+        let entry = match &mut self.recency_buffer {
+            Some(recency_buffer) => {
+                let entry = self.entries.peek(key);
+                if entry.is_some() {
+                    recency_buffer.record(key.clone());
+                }
+                entry
+            }
+            None => self.entries.get(key),
+        };
+        let value = match entry {
+            Some(value) => {
+                stats.record_hit();
+                Some(f(value))
+            }
+            None => {
+                stats.record_miss(self.evicted.contains_key(key));
+                None
+            }
+        };
+        if let Some(windowed_stats) = &mut self.windowed_stats {
+            windowed_stats.record(Clock::instant(), value.is_some());
+        }
+        if self.recency_buffer.as_ref().map_or(false, RecencyBuffer::is_due) {
+            self.flush_recency();
+        }
+        value
+    }
+
+    /// Replays the buffered lookups on the recency of the entries. Only the
+    /// last lookup of every key matters, so each key is marked as recently
+    /// used once, in the order of their last lookups. Keys which were removed
+    /// since are skipped.
+    fn flush_recency(&mut self) {
+        if let Some(recency_buffer) = &mut self.recency_buffer {
+            let keys = &mut recency_buffer.keys;
+            let seen = &mut recency_buffer.seen;
+            let mut last = keys.len();
+            for i in (0..keys.len()).rev() {
+                if seen.insert(keys[i].clone()) {
+                    last -= 1;
+                    keys.swap(i, last);
+                }
+            }
+            for key in keys.drain(..).skip(last) {
+                self.entries.get(&key);
+            }
+            seen.clear();
+            recency_buffer.since = None;
+        }
+    }
+
+    fn insert(&mut self, key: K, value: V, stats: &AtomicCacheStats) -> Option<V> {
+        if self.is_full_of_pinned(&key) {
+            stats.record_rejected_insert();
+            return None;
+        }
+        stats.record_insert();
+        if self.strict_capacity && !self.entries.contains_key(&key) {
+            self.evict_down_to(self.capacity - 1, stats);
+        }
+        self.evicted.remove(&key);
+        let previous = self.entries.put(key, value);
+        self.evict_overflow(stats);
+        previous
+    }
+
+    /// Removes the entry along with its pins.
+    fn remove(&mut self, key: &K) -> Option<V> {
+        self.pinned.remove(key);
+        self.entries.remove(key)
+    }
+
+    /// Drops the pins of keys which aren't cached anymore.
+    fn forget_removed_pins(&mut self) {
+        let entries = &self.entries;
+        self.pinned.retain(|key, _| entries.contains_key(key));
+    }
+
+    /// Whether inserting the key would require evicting an entry while all of
+    /// them are pinned.
+    fn is_full_of_pinned(&self, key: &K) -> bool {
+        !self.pinned.is_empty()
+            && self.entries.len() >= self.capacity
+            && !self.entries.contains_key(key)
+            && self.entries.iter().all(|(key, _)| self.pinned.contains_key(key))
+    }
+
+    /// Evicts unpinned entries, least recently used first, until the cache
+    /// fits its capacity or only pinned entries are left.
+    fn evict_overflow(&mut self, stats: &AtomicCacheStats) {
+        self.evict_down_to(self.capacity, stats);
+    }
+
+    /// Like `evict_overflow`, but evicts until at most `max_len` entries are
+    /// left.
+    fn evict_down_to(&mut self, max_len: usize, stats: &AtomicCacheStats) {
+        // Read lazily, so that the clock is only used when something is evicted.
+        let mut now = None;
+        let mut batch = Vec::new();
+        // Keys the callback chose to keep, which are evicted if they come up
+        // again.
+        let mut kept = HashSet::new();
+        while self.entries.len() > max_len {
+            let pinned = &self.pinned;
+            let (key, value) = match self.entries.pop_lru_except(|key| pinned.contains_key(key)) {
+                Some(entry) => entry,
+                None => break,
+            };
+            if let Some(on_evict) = &self.on_evict {
+                if !kept.contains(&key) && on_evict(&key, &value) == EvictDecision::Keep {
+                    kept.insert(key.clone());
+                    self.entries.put(key, value);
+                    continue;
+                }
+            }
+            stats.record_eviction();
+            if let Some(eviction_times) = &mut self.eviction_times {
+                eviction_times.record(*now.get_or_insert_with(Clock::instant));
+            }
+            if let Some(on_evict_batch) = &self.on_evict_batch {
+                batch.push((key.clone(), (on_evict_batch.clone)(&value)));
+            }
+            self.evicted.put(key, ());
+            if self.evicted.len() > self.capacity {
+                self.evicted.pop_lru();
+            }
+        }
+        if let Some(on_evict_batch) = &self.on_evict_batch {
+            if !batch.is_empty() {
+                (on_evict_batch.callback)(batch);
+            }
+        }
+    }
+}
+
+impl<K, V> SyncLruCache<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    pub fn new(capacity: usize) -> Self {
+        Self::with_turnstile(capacity, None)
+    }
+
+    /// Creates a cache whose lock is fair: threads get to use the cache in the
+    /// order they tried to, so an occasional `insert` waits at most for the
+    /// operations of threads which were already waiting rather than for as
+    /// long as a stream of `get`s keeps the cache busy. Fairness makes every
+    /// operation slower, so only use this under heavy contention.
+    pub fn new_fair(capacity: usize) -> Self {
+        Self::with_turnstile(capacity, Some(Turnstile::default()))
+    }
+
+    fn with_turnstile(capacity: usize, turnstile: Option<Turnstile>) -> Self {
+        assert!(capacity > 0);
+        Self {
+            inner: Mutex::new(Inner {
+                capacity,
+                entries: LruCache::new(),
+                evicted: LruCache::new(),
+                on_evict: None,
+                on_evict_batch: None,
+                eviction_times: None,
+                windowed_stats: None,
+                recency_buffer: None,
+                pinned: HashMap::new(),
+                strict_capacity: false,
+                #[cfg(feature = "hot_keys")]
+                hot_keys: None,
+                #[cfg(feature = "reuse_distance")]
+                reuse_distances: None,
+            }),
+            turnstile,
+            stats: AtomicCacheStats::default(),
+            lock_waits: None,
+            #[cfg(feature = "op_latency")]
+            latencies: LatencyRecorder::default(),
+            #[cfg(feature = "tracing")]
+            key_fmt: None,
+        }
+    }
+
+    /// Makes the cache call `on_evict` for every entry about to be evicted to
+    /// stay within the capacity, which may keep it instead, see
+    /// `EvictDecision`. The callback runs with the cache locked, so it must not
+    /// call back into the cache.
+    pub(crate) fn with_on_evict(self, on_evict: EvictionCallback<K, V>) -> Self {
+        self.lock().on_evict = Some(on_evict);
+        self
+    }
+
+    /// Makes the cache call `on_evict_batch` once with all entries evicted by
+    /// a single operation, e.g. by `resize`, in eviction order. It is called
+    /// after `on_evict` was called for each of them, with the cache locked.
+    pub(crate) fn with_on_evict_batch(self, on_evict_batch: EvictionBatchCallback<K, V>) -> Self
+    where
+        V: Clone,
+    {
+        self.lock().on_evict_batch =
+            Some(BatchCallback { callback: on_evict_batch, clone: V::clone });
+        self
+    }
+
+    /// Makes inserts of new keys into a full cache evict the least recently
+    /// used entry before storing the new one rather than after, so that the
+    /// cache never holds more than `capacity` entries, not even for the moment
+    /// between the two steps. Its tables then never grow past the size which
+    /// `capacity` entries need, however many `get_or_insert` misses race to
+    /// insert. Eviction callbacks run before the new value is stored.
+    pub fn with_strict_capacity(self) -> Self {
+        self.lock().strict_capacity = true;
+        self
+    }
+
+    /// Makes the cache remember the `Clock::instant()` of its last
+    /// `max_samples` evictions, so that `eviction_rate_per_sec` can report how
+    /// many evictions happened per second over the last `window`. If more than
+    /// `max_samples` evictions happen within the window, the rate is
+    /// underestimated.
+    pub fn with_eviction_rate(self, window: Duration, max_samples: usize) -> Self {
+        assert!(window > Duration::ZERO && max_samples > 0);
+        self.lock().eviction_times =
+            Some(EvictionTimes { window, max_len: max_samples, times: VecDeque::new() });
+        self
+    }
+
+    /// Average number of evictions per second over the window set with
+    /// `with_eviction_rate`, or zero if eviction times aren't tracked.
+    pub fn eviction_rate_per_sec(&self) -> f64 {
+        match &mut self.lock().eviction_times {
+            Some(eviction_times) => eviction_times.rate_per_sec(Clock::instant()),
+            None => 0.0,
+        }
+    }
+
+    /// Makes the cache count its hits and misses per consecutive `window` of
+    /// `Clock::instant()`, keeping the last `max_windows` windows, so that
+    /// `recent_windows` can report them e.g. to a time-series database.
+    pub fn with_windowed_stats(self, window: Duration, max_windows: usize) -> Self {
+        self.lock().windowed_stats =
+            Some(WindowedStats::new(Clock::instant(), window, max_windows));
+        self
+    }
+
+    /// Hits and misses of the last `n` windows set with `with_windowed_stats`,
+    /// oldest first and ending with the current window. Windows without any
+    /// lookups are reported as empty rather than skipped. Returns nothing if
+    /// the stats aren't tracked.
+    pub fn recent_windows(&self, n: usize) -> Vec<WindowStats> {
+        match &mut self.lock().windowed_stats {
+            Some(windowed_stats) => windowed_stats.recent(Clock::instant(), n),
+            None => Vec::new(),
+        }
+    }
+
+    /// Makes lookups record the key in a buffer instead of marking it as
+    /// recently used right away, and replay the buffered lookups on the order
+    /// of the entries once `max_pending` of them were recorded, or on the first
+    /// lookup at least `max_delay` of `Clock::instant()` after the oldest one,
+    /// or on `flush_recency`. A flush reorders every key once however often it
+    /// was looked up, which saves most of the cost of hits in read-heavy
+    /// workloads repeatedly looking up the same keys.
+    ///
+    /// The price is that recency is stale by up to `max_pending` lookups (or
+    /// by `max_delay`): an entry looked up since the last flush is evicted,
+    /// listed by `order` and visited by `for_each` as if it wasn't. So a hot
+    /// entry which was cold at the last flush may be evicted in favour of a
+    /// colder one. Statistics, lookup results and `peek` are not affected.
+    /// Setting `max_delay` costs a clock read on every lookup while lookups
+    /// are buffered.
+    ///
+    /// The buffer is shared by all threads and lives under the cache lock,
+    /// since lookups need the lock to read the value anyway. So this only
+    /// shortens the time a hit holds the lock (and adds a clone of the key);
+    /// it doesn't reduce contention on the lock.
+    pub fn with_batched_recency(self, max_pending: usize, max_delay: Option<Duration>) -> Self {
+        assert!(max_pending > 0);
+        self.lock().recency_buffer = Some(RecencyBuffer {
+            keys: Vec::with_capacity(max_pending),
+            max_keys: max_pending,
+            max_delay,
+            seen: HashSet::with_capacity(max_pending),
+            since: None,
+        });
+        self
+    }
+
+    /// Marks the keys of the lookups buffered since the last flush as recently
+    /// used, in the order they were looked up, see `with_batched_recency`.
+    /// Does nothing if recency updates aren't batched.
+    pub fn flush_recency(&self) {
+        self.lock().flush_recency();
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.lock().capacity
+    }
+
+    /// Changes the capacity, evicting the least recently used entries if the
+    /// cache holds more than `capacity` of them.
+    pub fn resize(&self, capacity: usize) {
+        assert!(capacity > 0);
+        let mut inner = self.lock();
+        inner.capacity = capacity;
+        inner.evict_overflow(&self.stats);
+        while inner.evicted.len() > capacity {
+            inner.evicted.pop_lru();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.lock().entries.contains_key(key)
+    }
+
+    /// Returns a copy of the cached value and marks the key as recently used.
+    pub fn get(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        #[cfg(feature = "op_latency")]
+        let _timer = self.latencies.start(Op::Get);
+        let value = self.lock().lookup(key, &self.stats);
+        trace_op!("get", key = self.traced_key(key), value.is_some());
+        value
+    }
+
+    /// Calls `f` on the cached value and marks the key as recently used, like
+    /// `get` but without copying the value, which doesn't need to be `Clone`.
+    /// `f` runs with the cache locked, so it should be quick.
+    pub fn with<R, F>(&self, key: &K, f: F) -> Option<R>
+    where
+        F: FnOnce(&V) -> R,
+    {
+        #[cfg(feature = "op_latency")]
+        let _timer = self.latencies.start(Op::Get);
+        let result = self.lock().lookup_with(key, &self.stats, f);
+        trace_op!("with", key = self.traced_key(key), result.is_some());
+        result
+    }
+
+    /// Returns a copy of the cached value without changing its recency or the
+    /// statistics.
+    pub fn peek(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.lock().entries.peek(key).cloned()
+    }
+
+    /// Inserts the value, evicting the least recently used entry if the cache
+    /// is over capacity. If all entries are pinned, the value is dropped
+    /// instead, see `pin`.
+    pub fn insert(&self, key: K, value: V) {
+        #[cfg(feature = "op_latency")]
+        let _timer = self.latencies.start(Op::Insert);
+        trace_op!("insert", key = self.traced_key(&key));
+        self.lock().insert(key, value, &self.stats);
+    }
+
+    /// Like `insert`, but returns the value previously stored under the key.
+    /// Entries evicted to make room for the new one are not returned.
+    pub fn replace(&self, key: K, value: V) -> Option<V> {
+        #[cfg(feature = "op_latency")]
+        let _timer = self.latencies.start(Op::Insert);
+        let mut inner = self.lock();
+        // Checked before the key is moved: a cached key is always replaced,
+        // see `is_full_of_pinned`.
+        trace_op!("replace", key = self.traced_key(&key), inner.entries.contains_key(&key));
+        inner.insert(key, value, &self.stats)
+    }
+
+    /// Inserts the value unless the key is already cached, in which case the
+    /// cached value is kept and the key's recency isn't changed. Returns
+    /// whether the value was inserted.
+    pub fn insert_if_absent(&self, key: K, value: V) -> bool {
+        let mut inner = self.lock();
+        let absent = !inner.entries.contains_key(&key);
+        trace_op!("insert_if_absent", key = self.traced_key(&key), !absent);
+        if absent {
+            inner.insert(key, value, &self.stats);
+        }
+        absent
+    }
+
+    /// Replaces the value of the key with `new` if the current value equals
+    /// `expected`. Returns whether the value was replaced.
+    pub fn compare_and_swap(&self, key: &K, expected: &V, new: V) -> bool
+    where
+        V: PartialEq,
+    {
+        let mut inner = self.lock();
+        if inner.entries.peek(key) != Some(expected) {
+            return false;
+        }
+        inner.insert(key.clone(), new, &self.stats);
+        true
+    }
+
+    /// Adds `delta` to the cached value, or to `V::default()` if the key isn't
+    /// cached, stores the sum as the most recently used entry and returns it.
+    /// Unlike a `get` followed by an `insert`, concurrent increments of the
+    /// same key don't lose updates. The statistics aren't changed.
+    pub fn increment(&self, key: &K, delta: V) -> V
+    where
+        V: Add<Output = V> + Default + Copy,
+    {
+        let mut inner = self.lock();
+        if let Some(value) = inner.entries.get_mut(key) {
+            *value = *value + delta;
+            return *value;
+        }
+        let value = V::default() + delta;
+        inner.insert(key.clone(), value, &self.stats);
+        value
+    }
+
+    /// Keeps the entry from being evicted until `unpin` is called for it as
+    /// many times as `pin`, e.g. while a computation depends on it. Returns
+    /// whether the key is cached; absent keys aren't pinned.
+    ///
+    /// Pinned entries still count towards the capacity, and evictions pass
+    /// over them to the least recently used unpinned entry. Once all entries
+    /// are pinned, inserts of new keys are rejected and counted in
+    /// `CacheStats::rejected_inserts`: `insert` drops the value instead of
+    /// evicting and `get_or_insert` returns it without caching it. Overwriting
+    /// a pinned entry keeps its pins, while removing it (e.g. with `remove` or
+    /// `clear`) undoes them all.
+    pub fn pin(&self, key: &K) -> bool {
+        let mut inner = self.lock();
+        if !inner.entries.contains_key(key) {
+            return false;
+        }
+        *inner.pinned.entry(key.clone()).or_default() += 1;
+        true
+    }
+
+    /// Undoes one `pin` of the key. Once it isn't pinned anymore, the entry
+    /// can be evicted again, right away if the cache is over capacity (e.g.
+    /// because it was shrunk while full of pinned entries). Keys which aren't
+    /// pinned are ignored.
+    pub fn unpin(&self, key: &K) {
+        let mut inner = self.lock();
+        if let Some(pins) = inner.pinned.get_mut(key) {
+            *pins -= 1;
+            if *pins == 0 {
+                inner.pinned.remove(key);
+                inner.evict_overflow(&self.stats);
+            }
+        }
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let value = self.lock().remove(key);
+        trace_op!("remove", key = self.traced_key(key), value.is_some());
+        value
+    }
+
+    /// Removes and returns the cached value. Same as `remove`, for callers
+    /// which use the cache as a queue of work: when several threads take the
+    /// same key concurrently, exactly one of them gets the value.
+    pub fn take(&self, key: &K) -> Option<V> {
+        let value = self.lock().remove(key);
+        trace_op!("take", key = self.traced_key(key), value.is_some());
+        value
+    }
+
+    /// Returns the cached value, computing and inserting it with `f` on a miss.
+    ///
+    /// The lock is not held while `f` runs, so concurrent misses for the same
+    /// key may all call `f`; the first value to be inserted wins.
+    pub fn get_or_insert<F>(&self, key: K, f: F) -> V
+    where
+        V: Clone,
+        F: FnOnce(&K) -> V,
+    {
+        self.get_or_insert_cow(Cow::Owned(key), f)
+    }
+
+    /// Like `get_or_insert`, but borrows the key, which is only cloned if it
+    /// has to be inserted. Useful for keys which are expensive to clone.
+    pub fn get_or_insert_ref<F>(&self, key: &K, f: F) -> V
+    where
+        V: Clone,
+        F: FnOnce(&K) -> V,
+    {
+        self.get_or_insert_cow(Cow::Borrowed(key), f)
+    }
+
+    fn get_or_insert_cow<F>(&self, key: Cow<'_, K>, f: F) -> V
+    where
+        V: Clone,
+        F: FnOnce(&K) -> V,
+    {
+        #[cfg(feature = "op_latency")]
+        let _timer = self.latencies.start(Op::GetOrInsert);
+        if let Some(value) = self.lock().lookup(&key, &self.stats) {
+            trace_op!("get_or_insert", key = self.traced_key(&key), true);
+            return value;
+        }
+        trace_op!("get_or_insert", key = self.traced_key(&key), false);
+        let value = f(&key);
+        let mut inner = self.lock();
+        if let Some(existing) = inner.entries.get(&key) {
+            return existing.clone();
+        }
+        inner.insert(key.into_owned(), value.clone(), &self.stats);
+        value
+    }
+
+    /// Like `get_or_insert`, but with a value at hand instead of a closure
+    /// computing it. On a hit `default` is dropped untouched; on a miss it is
+    /// moved into the cache and a copy of it is returned.
+    pub fn get_or_insert_value(&self, key: K, default: V) -> V
+    where
+        V: Clone,
+    {
+        #[cfg(feature = "op_latency")]
+        let _timer = self.latencies.start(Op::GetOrInsert);
+        let mut inner = self.lock();
+        if let Some(value) = inner.lookup(&key, &self.stats) {
+            trace_op!("get_or_insert_value", key = self.traced_key(&key), true);
+            return value;
+        }
+        trace_op!("get_or_insert_value", key = self.traced_key(&key), false);
+        inner.insert(key, default.clone(), &self.stats);
+        default
+    }
+
+    /// Like `get_or_insert`, but never evicts: on a miss with the cache full it
+    /// returns `None` without calling `f`. If the cache fills up while `f`
+    /// runs, the computed value is returned but not inserted.
+    pub fn get_or_insert_no_evict<F>(&self, key: K, f: F) -> Option<V>
+    where
+        V: Clone,
+        F: FnOnce(&K) -> V,
+    {
+        {
+            let mut inner = self.lock();
+            if let Some(value) = inner.lookup(&key, &self.stats) {
+                trace_op!("get_or_insert_no_evict", key = self.traced_key(&key), true);
+                return Some(value);
+            }
+            if inner.entries.len() >= inner.capacity {
+                trace_op!("get_or_insert_no_evict", key = self.traced_key(&key), false);
+                return None;
+            }
+        }
+        trace_op!("get_or_insert_no_evict", key = self.traced_key(&key), false);
+        let value = f(&key);
+        let mut inner = self.lock();
+        if let Some(existing) = inner.entries.get(&key) {
+            return Some(existing.clone());
+        }
+        if inner.entries.len() < inner.capacity {
+            inner.insert(key, value.clone(), &self.stats);
+        }
+        Some(value)
+    }
+
+    /// Looks up all `keys` under a single lock. Returns the found entries and
+    /// the keys which weren't cached, in the order they were requested and
+    /// without duplicates.
+    pub fn get_present(&self, keys: &[K]) -> (HashMap<K, V>, Vec<K>)
+    where
+        V: Clone,
+    {
+        let mut found = HashMap::with_capacity(keys.len());
+        let mut missing = Vec::new();
+        let mut seen = HashSet::with_capacity(keys.len());
+        let mut inner = self.lock();
+        for key in keys {
+            if !seen.insert(key) {
+                continue;
+            }
+            match inner.lookup(key, &self.stats) {
+                Some(value) => {
+                    found.insert(key.clone(), value);
+                }
+                None => missing.push(key.clone()),
+            }
+        }
+        (found, missing)
+    }
+
+    /// Batched version of `get_or_insert`: returns the values of all `keys`, in
+    /// the same order, computing the missing ones with a single call to `f`.
+    ///
+    /// `f` receives every missing key once and must return their values in the
+    /// same order. Like in `get_or_insert`, the lock isn't held while `f` runs.
+    pub fn get_or_insert_many<F>(&self, keys: Vec<K>, f: F) -> Vec<V>
+    where
+        V: Clone,
+        F: FnOnce(&[K]) -> Vec<V>,
+    {
+        let mut found = Vec::with_capacity(keys.len());
+        let mut missing = Vec::new();
+        {
+            let mut inner = self.lock();
+            let mut seen = HashSet::new();
+            for key in &keys {
+                let value = inner.lookup(key, &self.stats);
+                if value.is_none() && seen.insert(key) {
+                    missing.push(key.clone());
+                }
+                found.push(value);
+            }
+        }
+        if missing.is_empty() {
+            return found.into_iter().map(Option::unwrap).collect();
+        }
+
+        let computed = f(&missing);
+        assert_eq!(computed.len(), missing.len(), "factory must return one value per key");
+        let mut loaded = HashMap::with_capacity(missing.len());
+        {
+            let mut inner = self.lock();
+            for (key, value) in missing.into_iter().zip(computed) {
+                let value = match inner.entries.get(&key) {
+                    Some(existing) => existing.clone(),
+                    None => {
+                        inner.insert(key.clone(), value.clone(), &self.stats);
+                        value
+                    }
+                };
+                loaded.insert(key, value);
+            }
+        }
+        found
+            .into_iter()
+            .zip(keys)
+            .map(|(value, key)| value.unwrap_or_else(|| loaded[&key].clone()))
+            .collect()
+    }
+
+    /// Calls `f` for every entry, from the most to the least recently used,
+    /// without copying the entries or changing their recency.
+    ///
+    /// The lock is held for the whole iteration, so `f` must not call back
+    /// into this cache or it will deadlock.
+    pub fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&K, &V),
+    {
+        for (key, value) in self.lock().entries.iter() {
+            f(key, value);
+        }
+    }
+
+    /// Returns the cached keys from the most to the least recently used, i.e.
+    /// the last key is the next to be evicted. Meant for diagnostics; the
+    /// order is taken under the lock and doesn't change the recency.
+    pub fn order(&self) -> Vec<K> {
+        self.lock().entries.iter().map(|(key, _)| key.clone()).collect()
+    }
+
+    /// Removes all entries for which `f` returns false. Removed entries are not
+    /// counted as evictions.
+    pub fn retain<F>(&self, f: F)
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let mut inner = self.lock();
+        inner.entries.retain(f);
+        inner.forget_removed_pins();
+    }
+
+    /// Removes and returns the entries for which `f` returns true, from the
+    /// most to the least recently used, e.g. to move them to another cache.
+    /// The complement of `retain`: the other entries are left as they are.
+    /// Removed entries are not counted as evictions.
+    pub fn drain_where<F>(&self, f: F) -> Vec<(K, V)>
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let mut inner = self.lock();
+        let drained = inner.entries.drain_where(f);
+        inner.forget_removed_pins();
+        drained
+    }
+
+    /// Removes all entries. Statistics and the record of evicted keys are kept.
+    pub fn clear(&self) {
+        let mut inner = self.lock();
+        inner.entries.clear();
+        inner.pinned.clear();
+    }
+
+    /// Releases memory which is no longer needed, e.g. after `clear` or after
+    /// shrinking the cache with `resize`.
+    pub fn shrink_to_fit(&self) {
+        let mut inner = self.lock();
+        inner.entries.shrink_to_fit();
+        inner.evicted.shrink_to_fit();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats.snapshot()
+    }
+
+    /// Zeroes the statistics, e.g. at the start of a measurement window. The
+    /// cached entries are not affected.
+    pub fn reset_stats(&self) {
+        self.stats.reset();
+        #[cfg(feature = "hot_keys")]
+        if let Some(hot_keys) = &mut self.lock().hot_keys {
+            hot_keys.clear();
+        }
+        #[cfg(feature = "reuse_distance")]
+        if let Some(reuse_distances) = &mut self.lock().reuse_distances {
+            reuse_distances.clear();
+        }
+    }
+
+    /// Makes lookups count how often each key is looked up, hit or miss, in
+    /// `max_keys` counters, so that `top_keys` can report the keys causing
+    /// the most load. Counts are approximate: they may be overestimated by
+    /// up to the number of lookups divided by `max_keys`, and keys looked up
+    /// less often than that may be missing. `reset_stats` restarts the count.
+    #[cfg(feature = "hot_keys")]
+    pub fn with_hot_keys(self, max_keys: usize) -> Self {
+        self.lock().hot_keys = Some(HotKeys::new(max_keys));
+        self
+    }
+
+    /// The `n` most frequently looked up keys with their approximate lookup
+    /// counts since `with_hot_keys` or `reset_stats`, most frequent first.
+    #[cfg(feature = "hot_keys")]
+    pub fn top_keys(&self, n: usize) -> Vec<(K, u64)> {
+        self.lock().hot_keys.as_ref().map_or_else(Vec::new, |hot_keys| hot_keys.top(n))
+    }
+
+    /// Makes lookups compute their reuse distances, for estimating with
+    /// `reuse_distances` how the hit rate would change with the capacity.
+    /// Keys whose last lookup was followed by lookups of `max_keys` other keys
+    /// are forgotten, so only distances below `max_keys` are measured, and
+    /// every lookup takes additional time linear in its distance.
+    /// `reset_stats` restarts the measurement.
+    #[cfg(feature = "reuse_distance")]
+    pub fn with_reuse_distances(self, max_keys: usize) -> Self {
+        self.lock().reuse_distances = Some(ReuseDistanceTracker::new(max_keys));
+        self
+    }
+
+    /// Histogram of the reuse distances of lookups since
+    /// `with_reuse_distances` or `reset_stats`.
+    #[cfg(feature = "reuse_distance")]
+    pub fn reuse_distances(&self) -> ReuseDistances {
+        self.lock()
+            .reuse_distances
+            .as_ref()
+            .map_or_else(ReuseDistances::default, ReuseDistanceTracker::distances)
+    }
+
+    /// Makes the cache report every hit, miss, eviction and insert to
+    /// `metrics`, e.g. to export them to the metrics system of the
+    /// deployment. Without metrics, recording the events costs a single
+    /// branch.
+    pub fn with_metrics(mut self, metrics: Arc<dyn CacheMetrics>) -> Self {
+        self.stats.set_metrics(metrics);
+        self
+    }
+
+    /// Makes every operation measure, with `Clock::instant()`, how long it
+    /// waits to lock the cache, separately from how long it then holds the
+    /// lock, so that `lock_wait_stats` can tell whether the lock is contended.
+    /// Costs two clock reads per operation.
+    pub fn with_lock_wait_stats(mut self) -> Self {
+        self.lock_waits = Some(AtomicLockWaitStats::default());
+        self
+    }
+
+    /// Makes the `trace` events of operations include the key they were
+    /// called with, formatted with `Debug`, as the `key` field. Keys are left
+    /// out by default, since they may be large, or not `Debug` at all.
+    #[cfg(feature = "tracing")]
+    pub fn with_traced_keys(mut self) -> Self
+    where
+        K: fmt::Debug,
+    {
+        self.key_fmt = Some(<K as fmt::Debug>::fmt);
+        self
+    }
+
+    #[cfg(feature = "tracing")]
+    fn traced_key<'a>(&self, key: &'a K) -> Option<TracedKey<'a, K>> {
+        self.key_fmt.map(|fmt| TracedKey { key, fmt })
+    }
+
+    /// Time waited for the lock since `with_lock_wait_stats`, or all zeros if
+    /// waits aren't measured. Reading the stats doesn't lock the cache.
+    pub fn lock_wait_stats(&self) -> LockWaitStats {
+        self.lock_waits.as_ref().map(AtomicLockWaitStats::snapshot).unwrap_or_default()
+    }
+
+    /// Latencies of `get`, `insert` and `get_or_insert`, measured with
+    /// `Instant::now()` unless `with_clock_latencies` is used.
+    #[cfg(feature = "op_latency")]
+    pub fn op_latencies(&self) -> OpLatencies {
+        self.latencies.snapshot()
+    }
+
+    /// Makes latencies be measured with `Clock::instant()`, so that they are
+    /// deterministic under a mock clock. Every measured operation then reads
+    /// the clock twice, so tests must queue two samples per operation.
+    #[cfg(feature = "op_latency")]
+    pub fn with_clock_latencies(mut self) -> Self {
+        self.latencies.set_clock(Clock::instant);
+        self
+    }
+
+    /// Locks the cache, ignoring poisoning. A panic under the lock (e.g. in a
+    /// `for_each` callback) doesn't leave the cache half-updated, so there is
+    /// no reason to make it unusable for everyone else.
+    fn lock(&self) -> InnerGuard<'_, K, V> {
+        let started = self.lock_waits.as_ref().map(|_| Clock::instant());
+        let turn = self.turnstile.as_ref().map(Turnstile::wait_turn);
+        let guard = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        if let (Some(lock_waits), Some(started)) = (&self.lock_waits, started) {
+            lock_waits.record(Clock::instant().saturating_duration_since(started));
+        }
+        InnerGuard { guard, _turn: turn }
+    }
+}
+
+/// Locked `Inner`, keeping the turn of a fair cache until unlocked.
+struct InnerGuard<'a, K, V> {
+    // Dropped before `_turn`, so the next thread finds the mutex unlocked.
+    guard: MutexGuard<'a, Inner<K, V>>,
+    _turn: Option<Turn<'a>>,
+}
+
+impl<K, V> Deref for InnerGuard<'_, K, V> {
+    type Target = Inner<K, V>;
+
+    fn deref(&self) -> &Inner<K, V> {
+        &self.guard
+    }
+}
+
+impl<K, V> DerefMut for InnerGuard<'_, K, V> {
+    fn deref_mut(&mut self) -> &mut Inner<K, V> {
+        &mut self.guard
+    }
+}
+
+impl<K, T: ?Sized> SyncLruCache<K, Arc<T>>
+where
+    K: Hash + Eq + Clone,
+{
+    /// `get` for caches of `Arc`s: the value is returned by bumping its
+    /// reference count once, without cloning `T` or any temporaries.
+    pub fn get_arc(&self, key: &K) -> Option<Arc<T>> {
+        self.get(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SyncLruCache;
+    use crate::{CacheStats, EvictDecision};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = SyncLruCache::new(2);
+        cache.insert(1, "a");
+        cache.insert(2, "b");
+        assert_eq!(cache.get(&1), Some("a"));
+        cache.insert(3, "c");
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some("a"));
+        assert_eq!(cache.get(&3), Some("c"));
+        assert_eq!(cache.stats().evictions, 1);
+    }
+
+    #[test]
+    fn test_with_non_clone_value() {
+        struct Connection {
+            id: u32,
+            _buffer: Box<[u8]>,
+        }
+
+        let cache = SyncLruCache::new(2);
+        cache.insert(1, Connection { id: 10, _buffer: Box::new([0; 16]) });
+        cache.insert(2, Connection { id: 20, _buffer: Box::new([0; 16]) });
+        assert_eq!(cache.with(&1, |connection| connection.id), Some(10));
+        assert_eq!(cache.with(&3, |connection| connection.id), None);
+        // The lookup made key 1 the most recently used one.
+        cache.insert(3, Connection { id: 30, _buffer: Box::new([0; 16]) });
+        assert!(!cache.contains_key(&2));
+        assert_eq!(cache.with(&1, |connection| connection.id), Some(10));
+        assert_eq!(cache.stats().hits, 2);
+        assert_eq!(cache.stats().misses, 1);
+    }
+
+    #[test]
+    fn test_resize() {
+        let cache = SyncLruCache::new(3);
+        for i in 0..3 {
+            cache.insert(i, i);
+        }
+        cache.resize(1);
+        assert_eq!(cache.capacity(), 1);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&2), Some(2));
+        assert_eq!(cache.stats().evictions, 2);
+
+        cache.resize(2);
+        cache.insert(3, 3);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&2), Some(2));
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let cache = SyncLruCache::new(2);
+        cache.insert(1, "a");
+        assert!(cache.compare_and_swap(&1, &"a", "b"));
+        assert_eq!(cache.get(&1), Some("b"));
+
+        assert!(!cache.compare_and_swap(&1, &"a", "c"));
+        assert_eq!(cache.get(&1), Some("b"));
+        assert!(!cache.compare_and_swap(&2, &"a", "c"));
+        assert!(!cache.contains_key(&2));
+    }
+
+    #[test]
+    fn test_for_each() {
+        let cache = SyncLruCache::new(3);
+        for i in 1..=4 {
+            cache.insert(i, i * 10);
+        }
+        cache.get(&2);
+
+        let mut sum = 0;
+        let mut keys = [0; 3];
+        let mut visited = 0;
+        cache.for_each(|key, value| {
+            sum += value;
+            keys[visited] = *key;
+            visited += 1;
+        });
+        assert_eq!(sum, 90);
+        assert_eq!(keys, [2, 4, 3]);
+    }
+
+    #[cfg(feature = "op_latency")]
+    #[test]
+    fn test_op_latencies() {
+        use near_primitives::time::{Clock, MockClockGuard};
+        use std::time::Duration;
+
+        let _mock_clock_guard = MockClockGuard::default();
+        let cache = SyncLruCache::new(2).with_clock_latencies();
+        Clock::add_instant(Duration::from_millis(10));
+        Clock::add_instant(Duration::from_millis(15));
+        cache.insert(1, 1);
+        Clock::add_instant(Duration::from_millis(20));
+        Clock::add_instant(Duration::from_millis(20) + Duration::from_micros(7));
+        cache.get(&1);
+
+        let latencies = cache.op_latencies();
+        assert_eq!(latencies.insert.count, 1);
+        assert_eq!(latencies.insert.max, Duration::from_millis(5));
+        assert_eq!(latencies.insert.buckets[4], 1);
+        assert_eq!(latencies.get.total, Duration::from_micros(7));
+        assert_eq!(latencies.get.buckets[1], 1);
+        assert_eq!(latencies.get_or_insert.count, 0);
+    }
+
+    #[test]
+    fn test_batched_recency() {
+        let cache = SyncLruCache::new(3).with_batched_recency(4, None);
+        for key in 1..=3 {
+            cache.insert(key, key);
+        }
+        assert_eq!(cache.get(&1), Some(1));
+        // The lookup is buffered, so key 1 is still the least recently used.
+        assert_eq!(cache.order(), vec![3, 2, 1]);
+        cache.flush_recency();
+        assert_eq!(cache.order(), vec![1, 3, 2]);
+
+        // The fourth buffered lookup flushes them all in lookup order.
+        for key in [2, 3, 2] {
+            cache.get(&key);
+        }
+        assert_eq!(cache.order(), vec![1, 3, 2]);
+        cache.get(&1);
+        assert_eq!(cache.order(), vec![1, 2, 3]);
+        cache.insert(4, 4);
+        assert_eq!(cache.get(&3), None);
+        assert_eq!(cache.stats().hits, 5);
+    }
+
+    #[test]
+    fn test_batched_recency_max_delay() {
+        use near_primitives::time::{Clock, MockClockGuard};
+        use std::time::Duration;
+
+        let start = Clock::instant();
+        let mock_clock_guard = MockClockGuard::default();
+        mock_clock_guard.set_instant(start);
+        let cache = SyncLruCache::new(2).with_batched_recency(100, Some(Duration::from_millis(10)));
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        cache.get(&1);
+        mock_clock_guard.set_instant(start + Duration::from_millis(5));
+        cache.get(&2);
+        cache.get(&1);
+        assert_eq!(cache.order(), vec![2, 1]);
+        mock_clock_guard.set_instant(start + Duration::from_millis(10));
+        cache.get(&1);
+        assert_eq!(cache.order(), vec![1, 2]);
+        cache.insert(3, 3);
+        assert_eq!(cache.order(), vec![3, 1]);
+    }
+
+    #[test]
+    fn test_strict_capacity() {
+        use std::collections::HashMap;
+
+        const CAPACITY: usize = 7;
+        // What a map which never holds more than `CAPACITY` entries allocates.
+        let needed = HashMap::<usize, ()>::with_capacity(CAPACITY).capacity();
+        // Largest allocation of the map over inserts of new keys into a full
+        // cache, which a non-strict cache briefly holds one entry too many for.
+        let max_allocated = |cache: SyncLruCache<usize, usize>| {
+            let mut max_allocated = 0;
+            for key in 0..10 * CAPACITY {
+                cache.insert(key, key);
+                assert!(cache.len() <= CAPACITY);
+                max_allocated = max_allocated.max(cache.lock().entries.allocated_capacity());
+            }
+            max_allocated
+        };
+        assert!(max_allocated(SyncLruCache::new(CAPACITY).with_strict_capacity()) <= needed);
+        assert!(max_allocated(SyncLruCache::new(CAPACITY)) > needed);
+
+        // The same holds with racing `get_or_insert` misses. Maps don't shrink
+        // by themselves, so the final allocation is the largest one.
+        let cache = Arc::new(SyncLruCache::new(CAPACITY).with_strict_capacity());
+        let handles: Vec<_> = (0..4)
+            .map(|thread| {
+                let cache = cache.clone();
+                std::thread::spawn(move || {
+                    for i in 0..1000 {
+                        // Pairs of threads miss on the same keys at times.
+                        let key = (thread / 2) * 1000 + i;
+                        cache.get_or_insert(key, |key| *key);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(cache.len(), CAPACITY);
+        assert!(cache.stats().evictions > 0);
+        assert!(cache.lock().entries.allocated_capacity() <= needed);
+    }
+
+    #[test]
+    fn test_pinned_entry_survives() {
+        let cache = SyncLruCache::new(3);
+        cache.insert(1, "dependency");
+        assert!(cache.pin(&1));
+        assert!(!cache.pin(&10));
+        for key in 2..10 {
+            cache.insert(key, "filler");
+        }
+        // Key 1 is the least recently used one, yet it wasn't evicted.
+        assert_eq!(cache.order(), vec![9, 8, 1]);
+        assert_eq!(cache.get(&1), Some("dependency"));
+        assert_eq!(cache.stats().evictions, 6);
+
+        cache.unpin(&1);
+        for key in 10..13 {
+            cache.insert(key, "filler");
+        }
+        assert!(!cache.contains_key(&1));
+    }
+
+    #[test]
+    fn test_removing_entry_drops_pins() {
+        let cache = SyncLruCache::new(2);
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        assert!(cache.pin(&1));
+        assert!(cache.pin(&2));
+        assert_eq!(cache.remove(&1), Some(1));
+        cache.clear();
+        assert!(cache.lock().pinned.is_empty());
+
+        // The key isn't pinned anymore when inserted again.
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        cache.insert(3, 3);
+        assert_eq!(cache.order(), vec![3, 2]);
+    }
+
+    #[test]
+    fn test_full_of_pinned_entries() {
+        let cache = SyncLruCache::new(2);
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        assert!(cache.pin(&1));
+        assert!(cache.pin(&1));
+        assert!(cache.pin(&2));
+        cache.insert(3, 3);
+        assert_eq!(cache.order(), vec![2, 1]);
+        assert_eq!(cache.get_or_insert(3, |_| 30), 30);
+        assert_eq!(cache.stats().rejected_inserts, 2);
+        // Overwriting a pinned entry doesn't need an eviction.
+        cache.insert(2, 20);
+        assert_eq!(cache.get(&2), Some(20));
+
+        cache.resize(1);
+        assert_eq!(cache.len(), 2);
+        cache.unpin(&1);
+        assert_eq!(cache.len(), 2);
+        cache.unpin(&2);
+        assert_eq!(cache.order(), vec![1]);
+        cache.unpin(&1);
+        cache.insert(3, 3);
+        assert_eq!(cache.order(), vec![3]);
+    }
+
+    #[test]
+    fn test_get_or_insert_many() {
+        let cache = SyncLruCache::new(10);
+        cache.insert(2, 20);
+        cache.insert(4, 40);
+
+        let mut requested = vec![];
+        let values = cache.get_or_insert_many(vec![1, 2, 3, 1, 4], |keys| {
+            requested.extend_from_slice(keys);
+            keys.iter().map(|key| key * 10).collect()
+        });
+        assert_eq!(values, vec![10, 20, 30, 10, 40]);
+        assert_eq!(requested, vec![1, 3]);
+        assert_eq!(cache.len(), 4);
+
+        let values = cache.get_or_insert_many(vec![3, 1], |_| unreachable!());
+        assert_eq!(values, vec![30, 10]);
+    }
+
+    #[test]
+    fn test_retain() {
+        let cache = SyncLruCache::new(10);
+        for i in 0..10 {
+            cache.insert(i, i);
+        }
+        cache.retain(|key, _| key % 3 == 0);
+        let mut keys = vec![];
+        cache.for_each(|key, _| keys.push(*key));
+        assert_eq!(keys, vec![9, 6, 3, 0]);
+        assert_eq!(cache.stats().evictions, 0);
+    }
+
+    #[test]
+    fn test_shrink_to_fit() {
+        let cache = SyncLruCache::new(1000);
+        for i in 0..1000 {
+            cache.insert(i, i);
+        }
+        let allocated = cache.lock().entries.allocated_capacity();
+        cache.clear();
+        cache.shrink_to_fit();
+        assert!(cache.lock().entries.allocated_capacity() < allocated);
+
+        cache.insert(1, 1);
+        assert_eq!(cache.get(&1), Some(1));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_usable_after_poisoning() {
+        let cache = Arc::new(SyncLruCache::new(2));
+        cache.insert(1, 1);
+        let poisoner = cache.clone();
+        let result = std::thread::spawn(move || poisoner.for_each(|_, _| panic!("poison")));
+        assert!(result.join().is_err());
+        assert!(cache.inner.is_poisoned());
+
+        let cache_ = cache.clone();
+        std::thread::spawn(move || {
+            assert_eq!(cache_.get(&1), Some(1));
+            cache_.insert(2, 2);
+            assert_eq!(cache_.get_or_insert(3, |_| 3), 3);
+        })
+        .join()
+        .unwrap();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&2), Some(2));
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tracing_test::traced_test]
+    #[test]
+    fn test_trace_events() {
+        let cache = SyncLruCache::new(2);
+        cache.get_or_insert(1, |_| 1);
+        cache.get_or_insert(1, |_| 1);
+        cache.insert(2, 2);
+        assert!(logs_contain(r#"op="get_or_insert" hit=false"#));
+        assert!(logs_contain(r#"op="get_or_insert" hit=true"#));
+        assert!(logs_contain(r#"op="insert""#));
+        assert!(!logs_contain("key="));
+
+        let cache = SyncLruCache::new(2).with_traced_keys();
+        cache.insert("a", 1);
+        cache.get(&"a");
+        cache.get(&"b");
+        assert!(logs_contain(r#"op="get" hit=true key="a""#));
+        assert!(logs_contain(r#"op="get" hit=false key="b""#));
+    }
+
+    #[test]
+    fn test_miss_classification() {
+        let cache = SyncLruCache::new(2);
+        assert_eq!(cache.get_or_insert(1, |_| 10), 10);
+        assert_eq!(cache.get_or_insert(2, |_| 20), 20);
+        // Evicts key 1.
+        assert_eq!(cache.get_or_insert(3, |_| 30), 30);
+        let stats = cache.stats();
+        assert_eq!((stats.cold_misses, stats.capacity_misses), (3, 0));
+
+        // Key 1 was seen before and only missed because the cache was full.
+        assert_eq!(cache.get_or_insert(1, |_| 11), 11);
+        let stats = cache.stats();
+        assert_eq!((stats.cold_misses, stats.capacity_misses), (3, 1));
+
+        // Key 4 was never seen.
+        assert_eq!(cache.get_or_insert(4, |_| 40), 40);
+        // Key 1 is cached again, so this is a hit.
+        assert_eq!(cache.get_or_insert(1, |_| unreachable!()), 11);
+        let stats = cache.stats();
+        assert_eq!(stats.cold_misses, 4);
+        assert_eq!(stats.capacity_misses, 1);
+        assert_eq!(stats.misses, 5);
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.evictions, 3);
+    }
+
+    #[test]
+    fn test_reset_stats() {
+        let cache = SyncLruCache::new(1);
+        cache.insert(1, 10);
+        assert_eq!(cache.get(&1), Some(10));
+        cache.insert(2, 20);
+        assert_eq!(cache.get(&1), None);
+        cache.reset_stats();
+        assert_eq!(cache.stats(), CacheStats::default());
+
+        assert_eq!(cache.get(&2), Some(20));
+        assert_eq!(cache.get(&3), None);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (1, 1, 0));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_replace() {
+        let cache = SyncLruCache::new(1);
+        assert_eq!(cache.replace(1, "a"), None);
+        assert_eq!(cache.replace(1, "b"), Some("a"));
+        // Key 1 is evicted, not replaced.
+        assert_eq!(cache.replace(2, "c"), None);
+        assert_eq!(cache.get(&2), Some("c"));
+        assert_eq!(cache.stats().evictions, 1);
+    }
+
+    #[test]
+    fn test_fair_lock_serves_writers() {
+        use std::sync::mpsc;
+        use std::thread::{self, JoinHandle};
+
+        /// Spawns a thread running `f` and waits until it is queued for the
+        /// lock, i.e. until it took the next ticket.
+        fn spawn_queued<T: Send + 'static>(
+            cache: &Arc<SyncLruCache<u32, u32>>,
+            f: impl FnOnce(&SyncLruCache<u32, u32>) -> T + Send + 'static,
+        ) -> JoinHandle<T> {
+            let turnstile = cache.turnstile.as_ref().unwrap();
+            let ticket = turnstile.next_ticket();
+            let handle = {
+                let cache = cache.clone();
+                thread::spawn(move || f(&cache))
+            };
+            while turnstile.next_ticket() == ticket {
+                thread::yield_now();
+            }
+            handle
+        }
+
+        let cache = Arc::new(SyncLruCache::new_fair(10));
+        cache.insert(0, 0);
+        // Holds the lock until told to release it, then tries to take it back
+        // right away, which an unfair lock would let it do before everyone
+        // who is waiting.
+        let (locked_sender, locked) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let holder = spawn_queued(&cache, move |cache| {
+            cache.for_each(|_, _| {
+                locked_sender.send(()).unwrap();
+                released.recv().unwrap();
+            });
+            cache.get(&1)
+        });
+        locked.recv().unwrap();
+
+        let early_readers: Vec<_> =
+            (0..3).map(|_| spawn_queued(&cache, |cache| cache.get(&1))).collect();
+        let writer = spawn_queued(&cache, |cache| cache.insert(1, 1));
+        let late_readers: Vec<_> =
+            (0..3).map(|_| spawn_queued(&cache, |cache| cache.get(&1))).collect();
+        release.send(()).unwrap();
+
+        // The writer is served after the readers queued before it, and before
+        // the ones queued after it.
+        assert_eq!(holder.join().unwrap(), Some(1));
+        writer.join().unwrap();
+        for reader in early_readers {
+            assert_eq!(reader.join().unwrap(), None);
+        }
+        for reader in late_readers {
+            assert_eq!(reader.join().unwrap(), Some(1));
+        }
+    }
+
+    #[test]
+    fn test_get_or_insert_ref_clones_only_on_miss() {
+        use std::hash::{Hash, Hasher};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        struct Key {
+            id: u32,
+            clones: Arc<AtomicUsize>,
+        }
+
+        impl Clone for Key {
+            fn clone(&self) -> Self {
+                self.clones.fetch_add(1, Ordering::SeqCst);
+                Key { id: self.id, clones: self.clones.clone() }
+            }
+        }
+
+        impl PartialEq for Key {
+            fn eq(&self, other: &Self) -> bool {
+                self.id == other.id
+            }
+        }
+
+        impl Eq for Key {}
+
+        impl Hash for Key {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                self.id.hash(state)
+            }
+        }
+
+        let clones = Arc::new(AtomicUsize::new(0));
+        let key = Key { id: 1, clones: clones.clone() };
+        let cache = SyncLruCache::new(2);
+        assert_eq!(cache.get_or_insert_ref(&key, |key| key.id * 10), 10);
+        let clones_on_miss = clones.load(Ordering::SeqCst);
+        assert!(clones_on_miss > 0);
+        for _ in 0..3 {
+            assert_eq!(cache.get_or_insert_ref(&key, |_| unreachable!()), 10);
+        }
+        assert_eq!(clones.load(Ordering::SeqCst), clones_on_miss);
+    }
+
+    #[test]
+    fn test_insert_if_absent() {
+        let cache = SyncLruCache::new(2);
+        cache.insert(1, "a");
+        assert!(!cache.insert_if_absent(1, "b"));
+        assert_eq!(cache.get(&1), Some("a"));
+        assert!(cache.insert_if_absent(2, "c"));
+        assert_eq!(cache.get(&2), Some("c"));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_get_present() {
+        let cache = SyncLruCache::new(10);
+        cache.insert(1, "a");
+        cache.insert(3, "c");
+        let (found, missing) = cache.get_present(&[4, 1, 2, 3, 4, 1]);
+        assert_eq!(found, [(1, "a"), (3, "c")].into_iter().collect());
+        assert_eq!(missing, vec![4, 2]);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+    }
+
+    #[cfg(feature = "reuse_distance")]
+    #[test]
+    fn test_reuse_distances() {
+        let cache = SyncLruCache::<char, ()>::new(10).with_reuse_distances(3);
+        for key in "abcabbdca".chars() {
+            cache.get(&key);
+        }
+        // The second a and b follow lookups of 2 other keys, the third b none.
+        // New keys make the tracker forget the least recently looked up ones,
+        // c when d comes and a when c comes back, so the last c and a are cold
+        // like the first lookups of a, b, c and d.
+        let distances = cache.reuse_distances();
+        assert_eq!(distances.histogram, vec![1, 0, 2]);
+        assert_eq!(distances.cold, 6);
+        assert_eq!(distances.lookups(), 9);
+        assert!((distances.hit_rate(1) - 1.0 / 9.0).abs() < 1e-9);
+        // Which is what an LRU cache of capacity 3 would hit.
+        assert!((distances.hit_rate(3) - 3.0 / 9.0).abs() < 1e-9);
+        assert!((distances.hit_rate(10) - 3.0 / 9.0).abs() < 1e-9);
+
+        cache.reset_stats();
+        assert_eq!(cache.reuse_distances().lookups(), 0);
+    }
+
+    #[cfg(feature = "hot_keys")]
+    #[test]
+    fn test_top_keys() {
+        const MAX_KEYS: usize = 64;
+        let cache = SyncLruCache::<u64, ()>::new(100).with_hot_keys(MAX_KEYS);
+        let hot = [(1, 1000), (2, 500), (3, 200)];
+        let mut lookups = 0;
+        for round in 0..1000 {
+            for &(key, count) in &hot {
+                if round < count {
+                    cache.get(&key);
+                    lookups += 1;
+                }
+            }
+            // Two keys which are looked up only once.
+            for cold in 0..2 {
+                cache.get(&(1000 + 2 * round + cold));
+                lookups += 1;
+            }
+        }
+        let top = cache.top_keys(3);
+        assert_eq!(top.iter().map(|&(key, _)| key).collect::<Vec<_>>(), [1, 2, 3]);
+        let max_error = lookups / MAX_KEYS as u64;
+        for (&(key, count), &(_, expected)) in top.iter().zip(&hot) {
+            assert!(count >= expected && count <= expected + max_error, "{}: {}", key, count);
+        }
+
+        cache.reset_stats();
+        assert!(cache.top_keys(3).is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_increments() {
+        let cache = Arc::new(SyncLruCache::new(2));
+        assert_eq!(cache.increment(&"other", 5u64), 5);
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let cache = cache.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        cache.increment(&"requests", 1);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(cache.peek(&"requests"), Some(8000));
+        assert_eq!(cache.increment(&"other", 2), 7);
+        assert_eq!(cache.order(), vec!["other", "requests"]);
+    }
+
+    #[test]
+    fn test_take_single_winner() {
+        use std::sync::Barrier;
+
+        for _ in 0..10 {
+            let cache = Arc::new(SyncLruCache::new(10));
+            cache.insert(1, "job");
+            let barrier = Arc::new(Barrier::new(8));
+            let mut handles = Vec::new();
+            for _ in 0..8 {
+                let cache = cache.clone();
+                let barrier = barrier.clone();
+                handles.push(std::thread::spawn(move || {
+                    barrier.wait();
+                    cache.take(&1)
+                }));
+            }
+            let taken: Vec<_> =
+                handles.into_iter().filter_map(|handle| handle.join().unwrap()).collect();
+            assert_eq!(taken, vec!["job"]);
+            assert!(cache.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_eviction_rate() {
+        use near_primitives::time::{Clock, MockClockGuard};
+        use std::time::Duration;
+
+        fn approx_eq(a: f64, b: f64) -> bool {
+            (a - b).abs() < 1e-9
+        }
+
+        let _mock_clock_guard = MockClockGuard::strict();
+        let cache = SyncLruCache::new(1).with_eviction_rate(Duration::from_secs(10), 3);
+        cache.insert(0, 0);
+        // One eviction per second.
+        for key in 1..=5 {
+            Clock::add_instant(Duration::from_secs(key));
+            cache.insert(key, key);
+        }
+        Clock::add_instant(Duration::from_secs(5));
+        // Five evictions, but only the last three are remembered.
+        assert!(approx_eq(cache.eviction_rate_per_sec(), 0.3));
+        Clock::add_instant(Duration::from_secs(14));
+        // Only the eviction at 5s is still within the window.
+        assert!(approx_eq(cache.eviction_rate_per_sec(), 0.1));
+        Clock::add_instant(Duration::from_secs(15));
+        assert!(approx_eq(cache.eviction_rate_per_sec(), 0.0));
+
+        assert!(approx_eq(SyncLruCache::<u32, u32>::new(1).eviction_rate_per_sec(), 0.0));
+    }
+
+    #[test]
+    fn test_windowed_stats() {
+        use near_primitives::time::{Clock, MockClockGuard};
+        use std::time::Duration;
+
+        let _mock_clock_guard = MockClockGuard::strict();
+        Clock::add_instant(Duration::ZERO);
+        let cache = SyncLruCache::new(10).with_windowed_stats(Duration::from_secs(10), 3);
+        cache.insert(1, 1);
+        Clock::add_instant(Duration::from_secs(1));
+        cache.get(&1);
+        Clock::add_instant(Duration::from_secs(2));
+        cache.get(&2);
+        Clock::add_instant(Duration::from_secs(25));
+        cache.get(&1);
+
+        Clock::add_instant(Duration::from_secs(29));
+        let windows = cache.recent_windows(5);
+        let counts: Vec<_> = windows.iter().map(|window| (window.hits, window.misses)).collect();
+        assert_eq!(counts, vec![(1, 1), (0, 0), (1, 0)]);
+        let origin = windows[0].start;
+        assert_eq!(windows[2].start - origin, Duration::from_secs(20));
+
+        // Only the last three windows are kept.
+        Clock::add_instant(Duration::from_secs(45));
+        let windows = cache.recent_windows(2);
+        let starts: Vec<_> = windows.iter().map(|window| window.start - origin).collect();
+        assert_eq!(starts, vec![Duration::from_secs(30), Duration::from_secs(40)]);
+
+        Clock::add_instant(Duration::from_secs(100));
+        cache.get(&2);
+        Clock::add_instant(Duration::from_secs(100));
+        let windows = cache.recent_windows(5);
+        let starts: Vec<_> = windows.iter().map(|window| window.start - origin).collect();
+        assert_eq!(
+            starts,
+            vec![80, 90, 100].into_iter().map(Duration::from_secs).collect::<Vec<_>>()
+        );
+        assert_eq!((windows[2].hits, windows[2].misses), (0, 1));
+
+        assert!(SyncLruCache::<u32, u32>::new(1).recent_windows(5).is_empty());
+    }
+
+    #[test]
+    fn test_lock_wait_stats() {
+        use near_primitives::time::{Clock, MockClockGuard};
+        use std::sync::mpsc;
+        use std::thread;
+        use std::time::Duration;
+
+        let cache = Arc::new(SyncLruCache::new(10).with_lock_wait_stats());
+        let _mock_clock_guard = MockClockGuard::strict();
+        Clock::add_instant(Duration::ZERO);
+        Clock::add_instant(Duration::ZERO);
+        cache.insert(1, 1);
+
+        // Another thread (reading the real clock) holds the lock for a while.
+        let (locked_sender, locked) = mpsc::channel();
+        let holder = {
+            let cache = cache.clone();
+            thread::spawn(move || {
+                cache.for_each(|_, _| {
+                    locked_sender.send(()).unwrap();
+                    thread::sleep(Duration::from_millis(50));
+                })
+            })
+        };
+        locked.recv().unwrap();
+        Clock::add_instant(Duration::from_secs(1));
+        Clock::add_instant(Duration::from_secs(4));
+        assert_eq!(cache.get(&1), Some(1));
+        holder.join().unwrap();
+
+        let stats = cache.lock_wait_stats();
+        assert_eq!(stats.acquisitions, 3);
+        assert_eq!(stats.max_wait, Duration::from_secs(3));
+        assert!(stats.total_wait >= Duration::from_secs(3));
+        assert_eq!(SyncLruCache::<u32, u32>::new(1).lock_wait_stats(), Default::default());
+    }
+
+    #[test]
+    fn test_get_or_insert_value() {
+        use std::sync::Mutex;
+
+        /// Records the ids of the values which were cloned.
+        struct Value {
+            id: u32,
+            clones: Arc<Mutex<Vec<u32>>>,
+        }
+
+        impl Clone for Value {
+            fn clone(&self) -> Self {
+                self.clones.lock().unwrap().push(self.id);
+                Value { id: self.id, clones: self.clones.clone() }
+            }
+        }
+
+        let clones = Arc::new(Mutex::new(Vec::new()));
+        let value = |id| Value { id, clones: clones.clone() };
+        let cache = SyncLruCache::new(10);
+        assert_eq!(cache.get_or_insert_value(1, value(1)).id, 1);
+        assert_eq!(*clones.lock().unwrap(), vec![1]);
+        assert_eq!(cache.get_or_insert_value(1, value(2)).id, 1);
+        // Only the cached value was copied.
+        assert_eq!(*clones.lock().unwrap(), vec![1, 1]);
+        assert_eq!(cache.len(), 1);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+    }
+
+    #[test]
+    fn test_drain_where() {
+        let cache = SyncLruCache::new(10);
+        for key in 0..6 {
+            cache.insert(key, key * 10);
+        }
+        cache.get(&2);
+        let drained = cache.drain_where(|key, _| key % 2 == 0);
+        assert_eq!(drained, vec![(2, 20), (4, 40), (0, 0)]);
+        assert_eq!(cache.order(), vec![5, 3, 1]);
+        assert_eq!(cache.drain_where(|_, value| *value > 100), vec![]);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.stats().evictions, 0);
+    }
+
+    #[test]
+    fn test_on_evict_batch() {
+        use std::sync::Mutex;
+
+        let single = Arc::new(Mutex::new(Vec::new()));
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let cache = {
+            let single = single.clone();
+            let batches = batches.clone();
+            SyncLruCache::new(10)
+                .with_on_evict(Arc::new(move |&key, &value| {
+                    single.lock().unwrap().push((key, value));
+                    EvictDecision::Evict
+                }))
+                .with_on_evict_batch(Arc::new(move |batch| batches.lock().unwrap().push(batch)))
+        };
+        for key in 0..10 {
+            cache.insert(key, key * 10);
+        }
+        cache.resize(3);
+        let expected: Vec<_> = (0..7).map(|key| (key, key * 10)).collect();
+        assert_eq!(*batches.lock().unwrap(), vec![expected.clone()]);
+        assert_eq!(*single.lock().unwrap(), expected);
+
+        cache.insert(10, 100);
+        assert_eq!(batches.lock().unwrap().last(), Some(&vec![(7, 70)]));
+        cache.resize(5);
+        assert_eq!(batches.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_metrics() {
+        use crate::CacheMetrics;
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct RecordingMetrics(Mutex<Vec<&'static str>>);
+
+        impl CacheMetrics for RecordingMetrics {
+            fn on_hit(&self) {
+                self.0.lock().unwrap().push("hit");
+            }
+            fn on_miss(&self) {
+                self.0.lock().unwrap().push("miss");
+            }
+            fn on_evict(&self) {
+                self.0.lock().unwrap().push("evict");
+            }
+            fn on_insert(&self) {
+                self.0.lock().unwrap().push("insert");
+            }
+        }
+
+        let metrics = Arc::new(RecordingMetrics::default());
+        let cache = SyncLruCache::new(2).with_metrics(metrics.clone());
+        cache.insert(1, "a");
+        cache.insert(2, "b");
+        assert_eq!(cache.get(&1), Some("a"));
+        assert_eq!(cache.get(&3), None);
+        cache.insert(3, "c");
+        assert_eq!(cache.get_or_insert(2, |_| "b"), "b");
+        assert_eq!(
+            *metrics.0.lock().unwrap(),
+            vec!["insert", "insert", "hit", "miss", "insert", "evict", "miss", "insert", "evict"]
+        );
+        // The metrics see the same events as the statistics.
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (1, 2, 2));
+    }
+
+    #[test]
+    fn test_on_evict_keep() {
+        use std::sync::Mutex;
+
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let cache = {
+            let evicted = evicted.clone();
+            SyncLruCache::new(3).with_on_evict(Arc::new(move |&key, _| {
+                if key == 1 {
+                    return EvictDecision::Keep;
+                }
+                evicted.lock().unwrap().push(key);
+                EvictDecision::Evict
+            }))
+        };
+        for key in 1..=3 {
+            cache.insert(key, key * 10);
+        }
+        // 1 is the least recently used entry, but the callback keeps it, so the
+        // next-oldest one is evicted instead.
+        cache.insert(4, 40);
+        assert_eq!(*evicted.lock().unwrap(), vec![2]);
+        assert_eq!(cache.stats().evictions, 1);
+
+        // Kept entries become the most recently used ones.
+        cache.insert(5, 50);
+        assert_eq!(*evicted.lock().unwrap(), vec![2, 3]);
+        assert_eq!(cache.get(&1), Some(10));
+    }
+
+    #[test]
+    fn test_on_evict_keep_all() {
+        let cache = SyncLruCache::new(3).with_on_evict(Arc::new(|_, _| EvictDecision::Keep));
+        for key in 1..=4 {
+            cache.insert(key, key * 10);
+        }
+        // After a full pass of kept entries, the least recently used one is
+        // evicted regardless.
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.get(&1), None);
+        cache.resize(1);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&4), Some(40));
+    }
+
+    #[test]
+    fn test_stats_doesnt_lock() {
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let cache = Arc::new(SyncLruCache::new(2));
+        cache.insert(1, "a");
+        cache.get(&1);
+        let _guard = cache.lock();
+        let (sender, receiver) = mpsc::channel();
+        {
+            let cache = cache.clone();
+            std::thread::spawn(move || sender.send(cache.stats()).unwrap());
+        }
+        let stats = receiver.recv_timeout(Duration::from_secs(10)).expect("stats() blocked");
+        let copy = stats;
+        assert_eq!((stats.hits, copy.hits), (1, 1));
+    }
+
+    #[test]
+    fn test_get_arc() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static CLONES: AtomicUsize = AtomicUsize::new(0);
+        struct Counted;
+        impl Clone for Counted {
+            fn clone(&self) -> Self {
+                CLONES.fetch_add(1, Ordering::SeqCst);
+                Counted
+            }
+        }
+
+        let cache = SyncLruCache::new(2);
+        let value = Arc::new(Counted);
+        cache.insert(1, value.clone());
+        assert_eq!(Arc::strong_count(&value), 2);
+        let first = cache.get_arc(&1).unwrap();
+        assert_eq!(Arc::strong_count(&value), 3);
+        let second = cache.get_arc(&1).unwrap();
+        assert_eq!(Arc::strong_count(&value), 4);
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(cache.get_arc(&2).is_none());
+        assert_eq!(CLONES.load(Ordering::SeqCst), 0);
+
+        let unsized_cache: SyncLruCache<u32, Arc<str>> = SyncLruCache::new(2);
+        unsized_cache.insert(1, Arc::from("a"));
+        assert_eq!(unsized_cache.get_arc(&1).as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn test_get_or_insert_no_evict() {
+        let cache = SyncLruCache::new(2);
+        assert_eq!(cache.get_or_insert_no_evict(1, |_| "a"), Some("a"));
+        assert_eq!(cache.get_or_insert_no_evict(2, |_| "b"), Some("b"));
+        assert_eq!(cache.get_or_insert_no_evict(3, |_| panic!("cache is full")), None);
+        assert_eq!(cache.get_or_insert_no_evict(1, |_| panic!("key is cached")), Some("a"));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&2), Some("b"));
+        assert_eq!(cache.stats().evictions, 0);
+    }
+
+    #[test]
+    fn test_order() {
+        let cache = SyncLruCache::new(4);
+        for key in 1..=4 {
+            cache.insert(key, key);
+        }
+        cache.get(&2);
+        cache.peek(&1);
+        cache.insert(3, 30);
+        cache.get(&7);
+        assert_eq!(cache.order(), vec![3, 2, 4, 1]);
+        cache.insert(5, 5);
+        assert_eq!(cache.order(), vec![5, 3, 2, 4]);
+    }
+}
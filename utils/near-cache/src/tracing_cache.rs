@@ -0,0 +1,141 @@
+use crate::{CacheStats, SyncLruCache};
+use std::hash::Hash;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Operation performed on a `TracingCache`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CacheOp<K, V> {
+    Get(K),
+    Insert(K, V),
+    Remove(K),
+    /// `get_or_insert` with the value it returned.
+    GetOrInsert(K, V),
+}
+
+/// `SyncLruCache` which records every operation performed on it, so that they
+/// can be replayed on a fresh cache with `replay`, e.g. to reproduce an
+/// eviction pattern seen in production in a test.
+///
+/// Operations are serialized so that the recorded order is exactly the order
+/// in which they were applied, which makes this wrapper slower than the plain
+/// cache under contention.
+pub struct TracingCache<K, V> {
+    cache: SyncLruCache<K, V>,
+    ops: Mutex<Vec<CacheOp<K, V>>>,
+}
+
+impl<K, V> TracingCache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub fn new(capacity: usize) -> Self {
+        Self { cache: SyncLruCache::new(capacity), ops: Mutex::new(Vec::new()) }
+    }
+
+    /// The underlying cache. Operations performed on it directly aren't recorded.
+    pub fn cache(&self) -> &SyncLruCache<K, V> {
+        &self.cache
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut ops = self.lock_ops();
+        ops.push(CacheOp::Get(key.clone()));
+        self.cache.get(key)
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        let mut ops = self.lock_ops();
+        ops.push(CacheOp::Insert(key.clone(), value.clone()));
+        self.cache.insert(key, value)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let mut ops = self.lock_ops();
+        ops.push(CacheOp::Remove(key.clone()));
+        self.cache.remove(key)
+    }
+
+    /// See `SyncLruCache::get_or_insert`. Unlike there, `f` runs while other
+    /// operations on this cache wait.
+    pub fn get_or_insert<F>(&self, key: K, f: F) -> V
+    where
+        F: FnOnce(&K) -> V,
+    {
+        let mut ops = self.lock_ops();
+        let value = self.cache.get_or_insert(key.clone(), f);
+        ops.push(CacheOp::GetOrInsert(key, value.clone()));
+        value
+    }
+
+    /// Returns the operations recorded so far and starts a new recording.
+    pub fn take_ops(&self) -> Vec<CacheOp<K, V>> {
+        std::mem::take(&mut *self.lock_ops())
+    }
+
+    fn lock_ops(&self) -> MutexGuard<'_, Vec<CacheOp<K, V>>> {
+        self.ops.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Applies `ops` in order to a new cache of the given capacity and returns it.
+pub fn replay<K, V>(ops: &[CacheOp<K, V>], capacity: usize) -> SyncLruCache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    let cache = SyncLruCache::new(capacity);
+    for op in ops {
+        match op {
+            CacheOp::Get(key) => {
+                cache.get(key);
+            }
+            CacheOp::Insert(key, value) => cache.insert(key.clone(), value.clone()),
+            CacheOp::Remove(key) => {
+                cache.remove(key);
+            }
+            CacheOp::GetOrInsert(key, value) => {
+                cache.get_or_insert(key.clone(), |_| value.clone());
+            }
+        }
+    }
+    cache
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{replay, CacheOp, TracingCache};
+    use crate::SyncLruCache;
+
+    fn contents(cache: &SyncLruCache<u32, u32>) -> Vec<(u32, u32)> {
+        let mut entries = Vec::new();
+        cache.for_each(|key, value| entries.push((*key, *value)));
+        entries
+    }
+
+    #[test]
+    fn test_replay() {
+        let cache = TracingCache::new(3);
+        for key in 0..5 {
+            cache.insert(key, key);
+            cache.get(&(key / 2));
+        }
+        cache.get_or_insert(1, |key| key + 10);
+        cache.remove(&4);
+        cache.get_or_insert(7, |key| key + 10);
+
+        let ops = cache.take_ops();
+        assert_eq!(ops.len(), 13);
+        // Key 1 was still cached, so the factory wasn't called.
+        assert_eq!(ops[10], CacheOp::GetOrInsert(1, 1));
+        assert_eq!(ops[12], CacheOp::GetOrInsert(7, 17));
+        let replayed = replay(&ops, 3);
+        assert_eq!(contents(&replayed), contents(cache.cache()));
+        assert_eq!(replayed.stats(), cache.stats());
+        assert!(cache.take_ops().is_empty());
+    }
+}
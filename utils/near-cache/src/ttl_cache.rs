@@ -0,0 +1,840 @@
+use crate::lru::LruCache;
+use crate::stats::{AtomicCacheStats, CacheStats};
+use crate::{EvictDecision, EvictionBatchCallback, EvictionCallback};
+use near_primitives::time::Clock;
+use rand::{Rng, RngCore};
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Thread-safe LRU cache whose entries additionally expire `ttl` after they
+/// were inserted, as measured by `Clock::instant()`.
+///
+/// By default the expiry is absolute: overwriting a live entry keeps its
+/// original expiry time. A cache created with `new_sliding` instead restarts
+/// the `ttl` on every insert, so entries expire `ttl` after the last write.
+///
+/// Expired entries are dropped lazily when they are looked up, or all at once
+/// by `evict_expired`. Misses for expired keys are counted as cold misses.
+/// Pinned entries (see `pin`) neither expire nor get evicted.
+///
+/// Every entry carries several timestamps; for caches of many small values
+/// which only need expiry, `CompactTtlCache` takes less memory.
+///
+/// With `with_early_expiration`, entries computed by `get_or_insert` may also
+/// expire a bit before their time (the "XFetch" algorithm), so that reloads of
+/// entries inserted together are spread over time.
+pub struct TtlCache<K, V> {
+    inner: Mutex<Inner<K, V>>,
+    ttl: Duration,
+    sliding: bool,
+    stats: AtomicCacheStats,
+    on_evict: Option<EvictionCallback<K, V>>,
+    on_evict_batch: Option<EvictionBatchCallback<K, V>>,
+}
+
+struct Inner<K, V> {
+    capacity: usize,
+    entries: LruCache<K, Entry<V>>,
+    early_expiration: Option<EarlyExpiration>,
+    /// Incremented on every insert to order entries expiring at the same time.
+    next_seq: u64,
+    /// Number of `pin`s of each pinned key not yet undone by `unpin`.
+    pinned: HashMap<K, usize>,
+    /// Average compute time of the keys most recently computed by
+    /// `get_or_insert`, see `TtlCache::load_latency`. Kept apart from the
+    /// entries so that it outlives their expiry.
+    load_latencies: LruCache<K, Duration>,
+}
+
+/// Metadata of a cached entry, see `TtlCache::entry_info`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryInfo {
+    /// When the current value was inserted.
+    pub inserted_at: Instant,
+    /// Number of lookups which returned the current value.
+    pub hits: u64,
+    /// Time left until the entry expires.
+    pub ttl_remaining: Duration,
+}
+
+/// Cached value with its metadata, which `ShardedCache::reshard` moves
+/// between shards as a whole so that entries keep their expiry.
+pub(crate) struct Entry<V> {
+    value: V,
+    inserted_at: Instant,
+    hits: u64,
+    expires_at: Instant,
+    /// Average time it took to compute values of the key as of the insert,
+    /// zero if the value wasn't computed by `get_or_insert`.
+    compute_time: Duration,
+    /// Position of the insert among all inserts into the cache.
+    seq: u64,
+}
+
+struct EarlyExpiration {
+    beta: f64,
+    rng: Box<dyn RngCore + Send>,
+}
+
+impl EarlyExpiration {
+    /// Decides whether an entry should be treated as expired already. The
+    /// closer it is to expiring and the longer it takes to recompute, the more
+    /// likely it is to expire early.
+    fn is_expired<V>(&mut self, entry: &Entry<V>, now: Instant) -> bool {
+        if entry.compute_time == Duration::ZERO {
+            return false;
+        }
+        // Uniform in (0, 1], so that the logarithm is finite and not positive.
+        let sample = 1.0 - self.rng.gen::<f64>();
+        let gap = entry.compute_time.as_secs_f64() * self.beta * -sample.ln();
+        now + Duration::from_secs_f64(gap.min(u32::MAX as f64)) >= entry.expires_at
+    }
+}
+
+/// `instant + d`, or the latest instant representable if that overflows.
+fn saturating_add(instant: Instant, d: Duration) -> Instant {
+    if let Some(sum) = instant.checked_add(d) {
+        return sum;
+    }
+    // Approach the limit by adding ever smaller steps.
+    let (mut sum, mut step) = (instant, d);
+    while step > Duration::ZERO {
+        match sum.checked_add(step) {
+            Some(next) => sum = next,
+            None => step /= 2,
+        }
+    }
+    sum
+}
+
+impl<K, V> Inner<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    /// Whether inserting the key would require evicting an entry while all of
+    /// them are pinned.
+    fn is_full_of_pinned(&self, key: &K) -> bool {
+        !self.pinned.is_empty()
+            && self.entries.len() >= self.capacity
+            && !self.entries.contains_key(key)
+            && self.entries.iter().all(|(key, _)| self.pinned.contains_key(key))
+    }
+
+    /// Adds a compute time of the key to its average, which each new one
+    /// weighs a quarter of, and returns the new average.
+    fn record_load_latency(&mut self, key: &K, compute_time: Duration) -> Duration {
+        let average = match self.load_latencies.peek(key) {
+            Some(average) => (average.as_nanos() * 3 + compute_time.as_nanos()) / 4,
+            None => compute_time.as_nanos(),
+        };
+        let average = Duration::from_nanos(average as u64);
+        self.load_latencies.put(key.clone(), average);
+        if self.load_latencies.len() > self.capacity {
+            self.load_latencies.pop_lru();
+        }
+        average
+    }
+
+    /// Returns the live entry for the key, dropping it if it has expired.
+    fn lookup(&mut self, key: &K, now: Instant, stats: &AtomicCacheStats) -> Option<&Entry<V>> {
+        let expired = match self.entries.get(key) {
+            Some(entry) => {
+                now >= entry.expires_at
+                    || self
+                        .early_expiration
+                        .as_mut()
+                        .map_or(false, |early| early.is_expired(entry, now))
+            }
+            None => {
+                stats.record_miss(false);
+                return None;
+            }
+        };
+        if expired && !self.pinned.contains_key(key) {
+            self.entries.remove(key);
+            stats.record_miss(false);
+            return None;
+        }
+        stats.record_hit();
+        let entry = self.entries.get_mut(key).unwrap();
+        entry.hits += 1;
+        Some(entry)
+    }
+}
+
+impl<K, V> TtlCache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    /// Creates a cache where entries expire `ttl` after they were first inserted.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self::with_expiration(capacity, ttl, false)
+    }
+
+    /// Creates a cache where entries expire `ttl` after they were last inserted.
+    pub fn new_sliding(capacity: usize, ttl: Duration) -> Self {
+        Self::with_expiration(capacity, ttl, true)
+    }
+
+    fn with_expiration(capacity: usize, ttl: Duration, sliding: bool) -> Self {
+        assert!(capacity > 0);
+        Self {
+            inner: Mutex::new(Inner {
+                capacity,
+                entries: LruCache::new(),
+                early_expiration: None,
+                next_seq: 0,
+                pinned: HashMap::new(),
+                load_latencies: LruCache::new(),
+            }),
+            ttl,
+            sliding,
+            stats: AtomicCacheStats::default(),
+            on_evict: None,
+            on_evict_batch: None,
+        }
+    }
+
+    /// Makes the cache call `on_evict` for every entry about to be evicted to
+    /// stay within the capacity, which may keep it instead (see
+    /// `EvictDecision`), or swept by `evict_expired`, whose decision is
+    /// ignored. Expired entries dropped by lookups are dropped without calling
+    /// it. The callback runs with the cache locked, so it must not call back
+    /// into the cache.
+    pub(crate) fn with_on_evict(mut self, on_evict: EvictionCallback<K, V>) -> Self {
+        self.on_evict = Some(on_evict);
+        self
+    }
+
+    /// Makes the cache call `on_evict_batch` once with all entries evicted by
+    /// a single operation, after `on_evict` was called for each of them. Like
+    /// `on_evict`, it isn't called for expired entries dropped by lookups.
+    pub(crate) fn with_on_evict_batch(
+        mut self,
+        on_evict_batch: EvictionBatchCallback<K, V>,
+    ) -> Self {
+        self.on_evict_batch = Some(on_evict_batch);
+        self
+    }
+
+    /// Makes entries computed by `get_or_insert` expire early with a
+    /// probability growing as they approach their expiry time, scaled by how
+    /// long their key takes to compute (see `load_latency`) and by `beta`
+    /// (1.0 is a good default; higher values expire earlier). `rng` makes the
+    /// decisions, which lets tests seed it.
+    pub fn with_early_expiration<R: RngCore + Send + 'static>(self, beta: f64, rng: R) -> Self {
+        assert!(beta > 0.0);
+        self.lock().early_expiration = Some(EarlyExpiration { beta, rng: Box::new(rng) });
+        self
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.lock().capacity
+    }
+
+    /// Changes the capacity, evicting the least recently used entries if the
+    /// cache holds more than `capacity` of them.
+    pub fn resize(&self, capacity: usize) {
+        assert!(capacity > 0);
+        let mut inner = self.lock();
+        inner.capacity = capacity;
+        let evicted = self.evict_overflow(&mut inner);
+        self.notify_evicted(evicted);
+    }
+
+    /// Number of entries, including expired ones which weren't dropped yet.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let now = Clock::instant();
+        self.lock().lookup(key, now, &self.stats).map(|entry| entry.value.clone())
+    }
+
+    /// Returns the value together with the time left until it expires.
+    pub fn get_with_ttl(&self, key: &K) -> Option<(V, Duration)> {
+        let now = Clock::instant();
+        self.lock()
+            .lookup(key, now, &self.stats)
+            .map(|entry| (entry.value.clone(), entry.expires_at.saturating_duration_since(now)))
+    }
+
+    /// Returns copies of the entries whose TTL has passed but which weren't
+    /// dropped yet, from the most to the least recently used. The entries are
+    /// left in the cache and their recency isn't changed.
+    pub fn expired_entries(&self) -> Vec<(K, V)> {
+        let now = Clock::instant();
+        self.lock()
+            .entries
+            .iter()
+            .filter(|(_, entry)| now >= entry.expires_at)
+            .map(|(key, entry)| (key.clone(), entry.value.clone()))
+            .collect()
+    }
+
+    /// Returns all entries with the instants at which they expire, the soonest
+    /// first, e.g. for scheduling timers of them. Entries expiring at the same
+    /// instant are ordered by their inserts, like in `evict_expired`. Expired
+    /// entries which weren't dropped yet come first, and pinned ones are
+    /// listed as well. Doesn't change the recency or hit counts of the entries.
+    pub fn entries_by_expiry(&self) -> Vec<(K, V, Instant)> {
+        let inner = self.lock();
+        let mut entries: Vec<_> = inner.entries.iter().collect();
+        entries.sort_unstable_by_key(|(_, entry)| (entry.expires_at, entry.seq));
+        entries
+            .into_iter()
+            .map(|(key, entry)| (key.clone(), entry.value.clone(), entry.expires_at))
+            .collect()
+    }
+
+    /// Exponentially weighted moving average of the times `get_or_insert` took
+    /// to compute values of the key, as measured by `Clock::instant()`, which
+    /// sets how early `with_early_expiration` refreshes the key. It is kept for
+    /// as many keys as the cache holds, the most recently computed ones, also
+    /// after their entries expired or were evicted.
+    pub fn load_latency(&self, key: &K) -> Option<Duration> {
+        self.lock().load_latencies.peek(key).copied()
+    }
+
+    /// Returns metadata of the entry without changing its recency, hit count or
+    /// the statistics. Expired entries are reported as missing.
+    pub fn entry_info(&self, key: &K) -> Option<EntryInfo> {
+        let now = Clock::instant();
+        let inner = self.lock();
+        let entry = inner.entries.peek(key).filter(|entry| now < entry.expires_at)?;
+        Some(EntryInfo {
+            inserted_at: entry.inserted_at,
+            hits: entry.hits,
+            ttl_remaining: entry.expires_at - now,
+        })
+    }
+
+    /// Postpones the expiry of each of the given keys which is cached and not
+    /// expired yet by `additional`, e.g. to renew a batch of leases. Returns
+    /// the number of extended entries; a key listed twice is extended twice.
+    /// The recency of the entries isn't changed. Expiry times past the latest
+    /// representable instant are clamped to it, so `Duration::MAX` keeps the
+    /// entries from ever expiring.
+    pub fn extend_ttl(&self, keys: &[K], additional: Duration) -> usize {
+        let now = Clock::instant();
+        let mut inner = self.lock();
+        let mut extended = 0;
+        for key in keys {
+            if let Some(entry) = inner.entries.peek_mut(key) {
+                if now < entry.expires_at {
+                    entry.expires_at = saturating_add(entry.expires_at, additional);
+                    extended += 1;
+                }
+            }
+        }
+        extended
+    }
+
+    /// Inserts the value, which expires `ttl` from now unless it overwrites a
+    /// live entry of a non-sliding cache. Evicts the least recently used entry
+    /// if the cache is over capacity. If all entries are pinned, the value is
+    /// dropped instead, see `pin`.
+    pub fn insert(&self, key: K, value: V) {
+        self.insert_at(key, value, Clock::instant(), Duration::ZERO);
+    }
+
+    /// Returns the cached value, computing and inserting it with `f` on a miss.
+    /// The time `f` takes is recorded for early expiration.
+    ///
+    /// The lock is not held while `f` runs, so concurrent misses for the same
+    /// key may all call `f`.
+    pub fn get_or_insert<F>(&self, key: K, f: F) -> V
+    where
+        F: FnOnce(&K) -> V,
+    {
+        if let Some(value) = self.get(&key) {
+            return value;
+        }
+        let started = Clock::instant();
+        let value = f(&key);
+        let now = Clock::instant();
+        self.insert_at(key, value.clone(), now, now.saturating_duration_since(started));
+        value
+    }
+
+    fn insert_at(&self, key: K, value: V, now: Instant, mut compute_time: Duration) {
+        let mut expires_at = now + self.ttl;
+        let mut inner = self.lock();
+        if inner.is_full_of_pinned(&key) {
+            return;
+        }
+        if compute_time > Duration::ZERO {
+            compute_time = inner.record_load_latency(&key, compute_time);
+        }
+        if !self.sliding {
+            if let Some(entry) = inner.entries.peek(&key) {
+                if now < entry.expires_at {
+                    expires_at = entry.expires_at;
+                }
+            }
+        }
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        inner
+            .entries
+            .put(key, Entry { value, inserted_at: now, hits: 0, expires_at, compute_time, seq });
+        let evicted = self.evict_overflow(&mut inner);
+        self.notify_evicted(evicted);
+    }
+
+    /// Removes unpinned entries, least recently used first, until the cache
+    /// fits its capacity or only pinned entries are left.
+    fn evict_overflow(&self, inner: &mut Inner<K, V>) -> Vec<(K, V)> {
+        let mut evicted = Vec::new();
+        // Keys the callback chose to keep, which are evicted if they come up
+        // again.
+        let mut kept = HashSet::new();
+        while inner.entries.len() > inner.capacity {
+            let pinned = &inner.pinned;
+            let (key, entry) = match inner.entries.pop_lru_except(|key| pinned.contains_key(key)) {
+                Some(entry) => entry,
+                None => break,
+            };
+            if let Some(on_evict) = &self.on_evict {
+                if !kept.contains(&key) && on_evict(&key, &entry.value) == EvictDecision::Keep {
+                    kept.insert(key.clone());
+                    inner.entries.put(key, entry);
+                    continue;
+                }
+            }
+            self.stats.record_eviction();
+            evicted.push((key, entry.value));
+        }
+        evicted
+    }
+
+    /// Removes and returns the entries whose keys match `f`, from the most to
+    /// the least recently used, without counting them as evictions.
+    pub(crate) fn take_where(&self, f: impl Fn(&K) -> bool) -> Vec<(K, Entry<V>)> {
+        self.lock().entries.drain_where(|key, _| f(key))
+    }
+
+    /// Inserts an entry taken from another cache with `take_where`, keeping
+    /// its expiry and metadata. Evicts like `insert`.
+    pub(crate) fn insert_entry(&self, key: K, entry: Entry<V>) {
+        let mut inner = self.lock();
+        inner.entries.put(key, entry);
+        let evicted = self.evict_overflow(&mut inner);
+        self.notify_evicted(evicted);
+    }
+
+    /// Drops all expired unpinned entries, calling the eviction callbacks for
+    /// them, and returns their number. The entries are evicted in the order in which
+    /// they expired, and entries which expired at the same instant in the
+    /// order of their inserts (the last one, if a key was inserted several
+    /// times), so that the order of the callbacks is deterministic. Swept
+    /// entries aren't counted in `CacheStats::evictions`.
+    pub fn evict_expired(&self) -> usize {
+        let now = Clock::instant();
+        let mut inner = self.lock();
+        let pinned = &inner.pinned;
+        let mut expired: Vec<_> = inner
+            .entries
+            .iter()
+            .filter(|(key, entry)| now >= entry.expires_at && !pinned.contains_key(key))
+            .map(|(key, entry)| (entry.expires_at, entry.seq, key.clone()))
+            .collect();
+        expired.sort_unstable_by_key(|&(expires_at, seq, _)| (expires_at, seq));
+        let evicted: Vec<_> = expired
+            .into_iter()
+            .map(|(_, _, key)| {
+                let entry = inner.entries.remove(&key).unwrap();
+                if let Some(on_evict) = &self.on_evict {
+                    on_evict(&key, &entry.value);
+                }
+                (key, entry.value)
+            })
+            .collect();
+        let count = evicted.len();
+        self.notify_evicted(evicted);
+        count
+    }
+
+    /// Calls the batch eviction callback with the entries, which `on_evict`
+    /// was already called for.
+    fn notify_evicted(&self, evicted: Vec<(K, V)>) {
+        if let Some(on_evict_batch) = &self.on_evict_batch {
+            if !evicted.is_empty() {
+                on_evict_batch(evicted);
+            }
+        }
+    }
+
+    /// Keeps the entry from expiring or being evicted until `unpin` is called
+    /// for it as many times as `pin`, e.g. while a computation depends on it.
+    /// Returns whether the key is cached and not expired; other keys aren't
+    /// pinned.
+    ///
+    /// Pinned entries still count towards the capacity, and evictions pass
+    /// over them to the least recently used unpinned entry. Once all entries
+    /// are pinned, inserts of new keys are rejected: `insert` drops the value
+    /// instead of evicting. The pin belongs to the key, so it isn't undone by
+    /// removing the entry, and applies to values inserted under the key later.
+    pub fn pin(&self, key: &K) -> bool {
+        let now = Clock::instant();
+        let mut inner = self.lock();
+        match inner.entries.peek(key) {
+            Some(entry) if now < entry.expires_at || inner.pinned.contains_key(key) => {}
+            _ => return false,
+        }
+        *inner.pinned.entry(key.clone()).or_default() += 1;
+        true
+    }
+
+    /// Undoes one `pin` of the key. Once it isn't pinned anymore, the entry
+    /// expires as usual, at its original expiry time even if that has passed,
+    /// and it is evicted right away if the cache is over capacity. Keys which
+    /// aren't pinned are ignored.
+    pub fn unpin(&self, key: &K) {
+        let mut inner = self.lock();
+        if let Some(pins) = inner.pinned.get_mut(key) {
+            *pins -= 1;
+            if *pins == 0 {
+                inner.pinned.remove(key);
+                let evicted = self.evict_overflow(&mut inner);
+                self.notify_evicted(evicted);
+            }
+        }
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.lock().entries.remove(key).map(|entry| entry.value)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats.snapshot()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<K, V>> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EntryInfo, TtlCache};
+    use near_primitives::time::{Clock, MockClockGuard};
+    use std::time::Duration;
+
+    #[test]
+    fn test_expiry() {
+        let _mock_clock_guard = MockClockGuard::default();
+        let cache = TtlCache::new(10, Duration::from_secs(10));
+        Clock::add_instant(Duration::from_secs(0));
+        cache.insert(1, "a");
+        Clock::add_instant(Duration::from_secs(9));
+        assert_eq!(cache.get(&1), Some("a"));
+        Clock::add_instant(Duration::from_secs(10));
+        assert_eq!(cache.get(&1), None);
+        assert!(cache.is_empty());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+    }
+
+    #[test]
+    fn test_sliding_expiry() {
+        let _mock_clock_guard = MockClockGuard::default();
+        let fixed = TtlCache::new(10, Duration::from_secs(10));
+        let sliding = TtlCache::new_sliding(10, Duration::from_secs(10));
+        for cache in [&fixed, &sliding] {
+            Clock::add_instant(Duration::from_secs(0));
+            cache.insert(1, "a");
+            Clock::add_instant(Duration::from_secs(6));
+            cache.insert(1, "b");
+        }
+
+        Clock::add_instant(Duration::from_secs(12));
+        assert_eq!(fixed.get(&1), None);
+        Clock::add_instant(Duration::from_secs(12));
+        assert_eq!(sliding.get_with_ttl(&1), Some(("b", Duration::from_secs(4))));
+        Clock::add_instant(Duration::from_secs(16));
+        assert_eq!(sliding.get(&1), None);
+    }
+
+    #[test]
+    fn test_get_with_ttl() {
+        let _mock_clock_guard = MockClockGuard::default();
+        let cache = TtlCache::new(10, Duration::from_secs(10));
+        Clock::add_instant(Duration::from_secs(2));
+        cache.insert(1, "a");
+        Clock::add_instant(Duration::from_millis(5500));
+        assert_eq!(cache.get_with_ttl(&1), Some(("a", Duration::from_millis(6500))));
+        Clock::add_instant(Duration::from_secs(12));
+        assert_eq!(cache.get_with_ttl(&1), None);
+        Clock::add_instant(Duration::from_secs(12));
+        assert_eq!(cache.get_with_ttl(&2), None);
+    }
+
+    #[test]
+    fn test_early_expiration() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        const KEYS: u32 = 100;
+        let _mock_clock_guard = MockClockGuard::default();
+        let new_cache = |early: bool| {
+            let cache = TtlCache::new(KEYS as usize, Duration::from_secs(10));
+            if early {
+                cache.with_early_expiration(1.0, StdRng::seed_from_u64(42))
+            } else {
+                cache
+            }
+        };
+        let caches = [new_cache(true), new_cache(true), new_cache(false)];
+        for cache in &caches {
+            for key in 0..KEYS {
+                // Miss and start of the computation at 0s, end at 1s: expires
+                // at 11s and took 1s to compute.
+                Clock::add_instant(Duration::from_secs(0));
+                Clock::add_instant(Duration::from_secs(0));
+                Clock::add_instant(Duration::from_secs(1));
+                cache.get_or_insert(key, |key| *key);
+            }
+        }
+
+        let live_keys = |cache: &TtlCache<u32, u32>, at: Duration| {
+            (0..KEYS)
+                .filter(|key| {
+                    Clock::add_instant(at);
+                    cache.get(key).is_some()
+                })
+                .collect::<Vec<_>>()
+        };
+        let live = live_keys(&caches[0], Duration::from_secs(10));
+        // With 1s left and 1s to recompute, about a third expire early.
+        assert!(!live.is_empty() && live.len() < KEYS as usize, "{:?}", live);
+        // Same seed, same decisions.
+        assert_eq!(live_keys(&caches[1], Duration::from_secs(10)), live);
+        assert_eq!(live_keys(&caches[2], Duration::from_secs(10)).len(), KEYS as usize);
+        // The remaining entries expire on time at the latest.
+        assert!(live_keys(&caches[0], Duration::from_secs(11)).is_empty());
+    }
+
+    #[test]
+    fn test_entry_info() {
+        let _mock_clock_guard = MockClockGuard::default();
+        let cache = TtlCache::new(2, Duration::from_secs(10));
+        Clock::add_instant(Duration::from_secs(1));
+        cache.insert(1, "a");
+        Clock::add_instant(Duration::from_secs(1));
+        let inserted_at = cache.entry_info(&1).unwrap().inserted_at;
+        for _ in 0..3 {
+            Clock::add_instant(Duration::from_secs(2));
+            assert_eq!(cache.get(&1), Some("a"));
+        }
+        Clock::add_instant(Duration::from_secs(4));
+        let info = cache.entry_info(&1).unwrap();
+        assert_eq!(info, EntryInfo { inserted_at, hits: 3, ttl_remaining: Duration::from_secs(7) });
+        assert_eq!(cache.stats().hits, 3);
+
+        // `entry_info` doesn't make the key recently used.
+        Clock::add_instant(Duration::from_secs(4));
+        cache.insert(2, "b");
+        Clock::add_instant(Duration::from_secs(4));
+        assert_eq!(cache.get(&1), Some("a"));
+        Clock::add_instant(Duration::from_secs(4));
+        cache.insert(3, "c");
+        Clock::add_instant(Duration::from_secs(4));
+        assert_eq!(cache.entry_info(&2), None);
+        Clock::add_instant(Duration::from_secs(11));
+        assert_eq!(cache.entry_info(&1), None);
+    }
+
+    #[test]
+    fn test_expired_entries() {
+        let _mock_clock_guard = MockClockGuard::default();
+        let cache = TtlCache::new(10, Duration::from_secs(10));
+        for key in 0..4 {
+            Clock::add_instant(Duration::from_secs(key));
+            cache.insert(key, key * 10);
+        }
+        Clock::add_instant(Duration::from_secs(5));
+        assert_eq!(cache.expired_entries(), vec![]);
+        Clock::add_instant(Duration::from_secs(12));
+        assert_eq!(cache.expired_entries(), vec![(2, 20), (1, 10), (0, 0)]);
+        assert_eq!(cache.len(), 4);
+        Clock::add_instant(Duration::from_secs(12));
+        assert_eq!(cache.get(&3), Some(30));
+    }
+
+    #[test]
+    fn test_extend_ttl() {
+        let _mock_clock_guard = MockClockGuard::default();
+        let cache = TtlCache::new(10, Duration::from_secs(10));
+        Clock::add_instant(Duration::from_secs(0));
+        cache.insert(1, "a");
+        Clock::add_instant(Duration::from_secs(0));
+        cache.insert(2, "b");
+        Clock::add_instant(Duration::from_secs(5));
+        cache.insert(3, "c");
+
+        Clock::add_instant(Duration::from_secs(8));
+        assert_eq!(cache.extend_ttl(&[1, 3, 4], Duration::from_secs(20)), 2);
+        // Expired entries can't be renewed.
+        Clock::add_instant(Duration::from_secs(12));
+        assert_eq!(cache.extend_ttl(&[2], Duration::from_secs(20)), 0);
+
+        Clock::add_instant(Duration::from_secs(25));
+        assert_eq!(cache.get_with_ttl(&1), Some(("a", Duration::from_secs(5))));
+        Clock::add_instant(Duration::from_secs(25));
+        assert_eq!(cache.get(&2), None);
+        Clock::add_instant(Duration::from_secs(34));
+        assert_eq!(cache.get(&3), Some("c"));
+        Clock::add_instant(Duration::from_secs(35));
+        assert_eq!(cache.get(&3), None);
+    }
+
+    #[test]
+    fn test_extend_ttl_saturates() {
+        let _mock_clock_guard = MockClockGuard::default();
+        let cache = TtlCache::new(10, Duration::from_secs(10));
+        Clock::add_instant(Duration::from_secs(0));
+        cache.insert(1, "a");
+        Clock::add_instant(Duration::from_secs(1));
+        assert_eq!(cache.extend_ttl(&[1, 1], Duration::MAX), 2);
+        Clock::add_instant(Duration::from_secs(365 * 24 * 3600));
+        let (value, ttl) = cache.get_with_ttl(&1).unwrap();
+        assert_eq!(value, "a");
+        assert!(ttl > Duration::from_secs(100 * 365 * 24 * 3600));
+    }
+
+    #[test]
+    fn test_entries_by_expiry() {
+        let start = Clock::instant();
+        let mock_clock_guard = MockClockGuard::default();
+        mock_clock_guard.set_instant(start);
+        let cache = TtlCache::new(10, Duration::from_secs(10));
+        for (key, inserted_at) in [(1, 3), (2, 0), (3, 1), (4, 1)] {
+            mock_clock_guard.set_instant(start + Duration::from_secs(inserted_at));
+            cache.insert(key, key * 10);
+        }
+        // Stagger the TTLs as well: 2 now expires last, at 15s.
+        mock_clock_guard.set_instant(start + Duration::from_secs(5));
+        assert_eq!(cache.extend_ttl(&[2], Duration::from_secs(5)), 1);
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(
+            cache.entries_by_expiry(),
+            vec![(3, 30, at(11)), (4, 40, at(11)), (1, 10, at(13)), (2, 20, at(15))]
+        );
+
+        // Expired entries come first until they are dropped.
+        mock_clock_guard.set_instant(start + Duration::from_secs(12));
+        cache.insert(5, 50);
+        let keys: Vec<_> = cache.entries_by_expiry().into_iter().map(|(key, ..)| key).collect();
+        assert_eq!(keys, vec![3, 4, 1, 2, 5]);
+    }
+
+    #[test]
+    fn test_load_latency() {
+        let _mock_clock_guard = MockClockGuard::default();
+        let cache = TtlCache::new(10, Duration::from_secs(10));
+        let load = |duration: Duration| {
+            // Miss, start of the computation and its end.
+            Clock::add_instant(Duration::from_secs(0));
+            Clock::add_instant(Duration::from_secs(0));
+            Clock::add_instant(duration);
+            cache.get_or_insert(1, |key| *key);
+        };
+        load(Duration::from_millis(100));
+        assert_eq!(cache.load_latency(&1), Some(Duration::from_millis(100)));
+        assert_eq!(cache.load_latency(&2), None);
+
+        // The latency outlives the entry, and further loads are averaged in.
+        assert_eq!(cache.remove(&1), Some(1));
+        load(Duration::from_millis(500));
+        assert_eq!(cache.load_latency(&1), Some(Duration::from_millis(200)));
+        // Plain inserts aren't loads.
+        Clock::add_instant(Duration::from_secs(1));
+        cache.insert(1, 1);
+        assert_eq!(cache.load_latency(&1), Some(Duration::from_millis(200)));
+    }
+
+    #[test]
+    fn test_evict_expired_order() {
+        use crate::EvictDecision;
+        use std::sync::{Arc, Mutex};
+
+        let _mock_clock_guard = MockClockGuard::default();
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let cache = {
+            let (evicted, batches) = (evicted.clone(), batches.clone());
+            TtlCache::new(10, Duration::from_secs(10))
+                .with_on_evict(Arc::new(move |&key, _| {
+                    evicted.lock().unwrap().push(key);
+                    EvictDecision::Evict
+                }))
+                .with_on_evict_batch(Arc::new(move |batch| batches.lock().unwrap().push(batch)))
+        };
+        for key in [3, 1, 4, 2] {
+            Clock::add_instant(Duration::from_secs(0));
+            cache.insert(key, key * 10);
+        }
+        Clock::add_instant(Duration::from_secs(1));
+        cache.insert(0, 0);
+        // Neither recency nor overwrites of live entries change the expiry, but
+        // the overwrite of 3 comes after the inserts of the others.
+        Clock::add_instant(Duration::from_secs(5));
+        assert_eq!(cache.get(&4), Some(40));
+        Clock::add_instant(Duration::from_secs(5));
+        cache.insert(3, 30);
+
+        Clock::add_instant(Duration::from_secs(10));
+        assert_eq!(cache.evict_expired(), 4);
+        assert_eq!(*evicted.lock().unwrap(), vec![1, 4, 2, 3]);
+        assert_eq!(*batches.lock().unwrap(), vec![vec![(1, 10), (4, 40), (2, 20), (3, 30)]]);
+        assert_eq!(cache.len(), 1);
+
+        Clock::add_instant(Duration::from_secs(11));
+        assert_eq!(cache.evict_expired(), 1);
+        assert_eq!(*evicted.lock().unwrap(), vec![1, 4, 2, 3, 0]);
+        assert_eq!(cache.stats().evictions, 0);
+        Clock::add_instant(Duration::from_secs(12));
+        assert_eq!(cache.evict_expired(), 0);
+        assert_eq!(batches.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_pinned_entry_survives() {
+        let start = Clock::instant();
+        let mock_clock_guard = MockClockGuard::default();
+        mock_clock_guard.set_instant(start);
+        let cache = TtlCache::new(2, Duration::from_secs(10));
+        cache.insert(1, "dependency");
+        assert!(cache.pin(&1));
+        for key in 2..5 {
+            cache.insert(key, "filler");
+        }
+        assert_eq!(
+            (cache.get(&1), cache.get(&3), cache.get(&4)),
+            (Some("dependency"), None, Some("filler"))
+        );
+
+        // Pinned entries don't expire either.
+        mock_clock_guard.set_instant(start + Duration::from_secs(20));
+        assert_eq!(cache.evict_expired(), 1);
+        assert_eq!(cache.get_with_ttl(&1), Some(("dependency", Duration::ZERO)));
+        assert!(!cache.pin(&4));
+        cache.unpin(&1);
+        assert_eq!(cache.get(&1), None);
+        assert!(cache.is_empty());
+    }
+}
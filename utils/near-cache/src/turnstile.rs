@@ -0,0 +1,54 @@
+use std::sync::{Condvar, Mutex, PoisonError};
+
+/// Queue letting threads take turns in the order they arrived.
+///
+/// Unlike `Mutex`, which lets whichever thread is fastest grab it after it was
+/// released, threads are served strictly in the order they called
+/// `wait_turn`. Once a thread is waiting, only the threads which were already
+/// waiting get their turn before it.
+#[derive(Default)]
+pub(crate) struct Turnstile {
+    state: Mutex<State>,
+    turn_changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    next_ticket: u64,
+    serving: u64,
+}
+
+impl Turnstile {
+    /// Blocks until it's the caller's turn, which lasts until the returned
+    /// `Turn` is dropped.
+    pub fn wait_turn(&self) -> Turn<'_> {
+        let mut state = self.lock();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        while state.serving != ticket {
+            state = self.turn_changed.wait(state).unwrap_or_else(PoisonError::into_inner);
+        }
+        Turn { turnstile: self }
+    }
+
+    /// Number of `wait_turn` calls so far, including the waiting ones.
+    #[cfg(test)]
+    pub fn next_ticket(&self) -> u64 {
+        self.lock().next_ticket
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+pub(crate) struct Turn<'a> {
+    turnstile: &'a Turnstile,
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        self.turnstile.lock().serving += 1;
+        self.turnstile.turn_changed.notify_all();
+    }
+}
@@ -0,0 +1,190 @@
+use crate::lru::LruCache;
+use crate::stats::{AtomicCacheStats, CacheStats};
+use std::hash::Hash;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Thread-safe scan-resistant cache using the 2Q replacement scheme.
+///
+/// New entries go to a small FIFO queue of `recent_capacity` entries. An entry
+/// which is used again while there (or which is inserted again soon after it
+/// dropped out of the FIFO, as remembered by a ghost list of the last
+/// `ghost_capacity` keys) is promoted to the main LRU queue of `main_capacity`
+/// entries. A scan over many keys which are only used once therefore only
+/// churns the FIFO and leaves the entries in the main queue alone.
+pub struct TwoQueueCache<K, V> {
+    inner: Mutex<Inner<K, V>>,
+    stats: AtomicCacheStats,
+}
+
+struct Inner<K, V> {
+    recent_capacity: usize,
+    main_capacity: usize,
+    ghost_capacity: usize,
+    /// Entries used once, in insertion order (their recency is never updated).
+    recent: LruCache<K, V>,
+    /// Entries used at least twice.
+    main: LruCache<K, V>,
+    /// Keys recently dropped from `recent`.
+    ghosts: LruCache<K, ()>,
+}
+
+impl<K, V> Inner<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    fn lookup(&mut self, key: &K, stats: &AtomicCacheStats) -> Option<V> {
+        if let Some(value) = self.main.get(key) {
+            stats.record_hit();
+            return Some(value.clone());
+        }
+        if let Some(value) = self.recent.remove(key) {
+            stats.record_hit();
+            self.main.put(key.clone(), value.clone());
+            self.evict_overflow(stats);
+            return Some(value);
+        }
+        stats.record_miss(self.ghosts.contains_key(key));
+        None
+    }
+
+    fn insert(&mut self, key: K, value: V, stats: &AtomicCacheStats) {
+        if self.main.contains_key(&key)
+            || self.recent.remove(&key).is_some()
+            || self.ghosts.remove(&key).is_some()
+        {
+            self.main.put(key, value);
+        } else {
+            self.recent.put(key, value);
+        }
+        self.evict_overflow(stats);
+    }
+
+    fn evict_overflow(&mut self, stats: &AtomicCacheStats) {
+        while self.recent.len() > self.recent_capacity {
+            let (key, _) = self.recent.pop_lru().unwrap();
+            stats.record_eviction();
+            self.ghosts.put(key, ());
+            if self.ghosts.len() > self.ghost_capacity {
+                self.ghosts.pop_lru();
+            }
+        }
+        while self.main.len() > self.main_capacity {
+            self.main.pop_lru();
+            stats.record_eviction();
+        }
+    }
+}
+
+impl<K, V> TwoQueueCache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    /// Creates a cache holding at most `recent_capacity + main_capacity`
+    /// entries. A `recent_capacity` of about a quarter of the total and a
+    /// `ghost_capacity` of about half of it are common choices.
+    pub fn new(recent_capacity: usize, main_capacity: usize, ghost_capacity: usize) -> Self {
+        assert!(recent_capacity > 0 && main_capacity > 0);
+        Self {
+            inner: Mutex::new(Inner {
+                recent_capacity,
+                main_capacity,
+                ghost_capacity,
+                recent: LruCache::new(),
+                main: LruCache::new(),
+                ghosts: LruCache::new(),
+            }),
+            stats: AtomicCacheStats::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        let inner = self.lock();
+        inner.recent.len() + inner.main.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the cached value. A hit on an entry in the FIFO queue promotes
+    /// it to the main queue.
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.lock().lookup(key, &self.stats)
+    }
+
+    /// Inserts the value, into the main queue if the key is cached or was
+    /// recently dropped from the FIFO queue, otherwise into the FIFO queue.
+    pub fn insert(&self, key: K, value: V) {
+        self.lock().insert(key, value, &self.stats);
+    }
+
+    /// Returns the cached value, computing and inserting it with `f` on a miss.
+    ///
+    /// The lock is not held while `f` runs, so concurrent misses for the same
+    /// key may all call `f`.
+    pub fn get_or_insert<F>(&self, key: K, f: F) -> V
+    where
+        F: FnOnce(&K) -> V,
+    {
+        if let Some(value) = self.get(&key) {
+            return value;
+        }
+        let value = f(&key);
+        self.insert(key, value.clone());
+        value
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let mut inner = self.lock();
+        inner.main.remove(key).or_else(|| inner.recent.remove(key))
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats.snapshot()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<K, V>> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TwoQueueCache;
+
+    #[test]
+    fn test_scan_resistance() {
+        let cache = TwoQueueCache::new(2, 3, 4);
+        for key in 0..3 {
+            cache.insert(key, key);
+            assert_eq!(cache.get(&key), Some(key));
+        }
+        // A scan over many keys which are never used again.
+        for key in 100..200 {
+            assert_eq!(cache.get_or_insert(key, |key| *key), key);
+        }
+        for key in 0..3 {
+            assert_eq!(cache.get(&key), Some(key));
+        }
+        assert_eq!(cache.len(), 5);
+    }
+
+    #[test]
+    fn test_ghost_promotion() {
+        let cache = TwoQueueCache::new(1, 2, 1);
+        cache.insert(1, "a");
+        cache.insert(2, "b");
+        // Key 1 dropped out of the FIFO queue, but is remembered as a ghost.
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.stats().capacity_misses, 1);
+        cache.insert(1, "a");
+        cache.insert(3, "c");
+        cache.insert(4, "d");
+        assert_eq!(cache.get(&1), Some("a"));
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.remove(&4), Some("d"));
+        assert_eq!(cache.len(), 1);
+    }
+}
@@ -0,0 +1,107 @@
+use crate::SyncLruCache;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+
+/// Key of type `K` which can only be used with caches of keys tagged with
+/// `Tag`, typically an empty enum naming one logical cache. This keeps caches
+/// with structurally identical keys (e.g. several caches of `u64` ids) from
+/// being mixed up.
+///
+/// ```
+/// use near_cache::{TypedCache, TypedKey};
+///
+/// enum Blocks {}
+///
+/// let blocks = TypedCache::<Blocks, u64, &str>::new(10);
+/// blocks.insert(TypedKey::new(1), "block");
+/// assert_eq!(blocks.get(&TypedKey::new(1)), Some("block"));
+/// ```
+///
+/// Keys of one cache are rejected by another:
+///
+/// ```compile_fail
+/// use near_cache::{TypedCache, TypedKey};
+///
+/// enum Blocks {}
+/// enum Chunks {}
+///
+/// let chunks = TypedCache::<Chunks, u64, &str>::new(10);
+/// let block: TypedKey<Blocks, u64> = TypedKey::new(1);
+/// chunks.get(&block);
+/// ```
+pub struct TypedKey<Tag, K> {
+    key: K,
+    // `fn() -> Tag` keeps the key `Send` and `Sync` whatever the tag is.
+    tag: PhantomData<fn() -> Tag>,
+}
+
+/// `SyncLruCache` whose keys are tagged with `Tag`, see `TypedKey`.
+pub type TypedCache<Tag, K, V> = SyncLruCache<TypedKey<Tag, K>, V>;
+
+impl<Tag, K> TypedKey<Tag, K> {
+    pub fn new(key: K) -> Self {
+        Self { key, tag: PhantomData }
+    }
+
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn into_inner(self) -> K {
+        self.key
+    }
+}
+
+// Implemented by hand, since deriving would require the tag to implement the
+// traits as well.
+
+impl<Tag, K: Clone> Clone for TypedKey<Tag, K> {
+    fn clone(&self) -> Self {
+        Self::new(self.key.clone())
+    }
+}
+
+impl<Tag, K: Copy> Copy for TypedKey<Tag, K> {}
+
+impl<Tag, K: PartialEq> PartialEq for TypedKey<Tag, K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl<Tag, K: Eq> Eq for TypedKey<Tag, K> {}
+
+impl<Tag, K: Hash> Hash for TypedKey<Tag, K> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key.hash(state);
+    }
+}
+
+impl<Tag, K: fmt::Debug> fmt::Debug for TypedKey<Tag, K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.key.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TypedCache, TypedKey};
+
+    #[test]
+    fn test_typed_cache() {
+        enum Blocks {}
+        enum Chunks {}
+
+        let blocks = TypedCache::<Blocks, u64, &str>::new(10);
+        let chunks = TypedCache::<Chunks, u64, &str>::new(10);
+        let block = TypedKey::new(1);
+        blocks.insert(block, "block");
+        chunks.insert(TypedKey::new(1), "chunk");
+        assert_eq!(blocks.get(&block), Some("block"));
+        assert_eq!(chunks.get(&TypedKey::new(1)), Some("chunk"));
+        assert_eq!(*block.key(), 1);
+        assert_eq!(format!("{:?}", block), "1");
+        assert_eq!(blocks.order().into_iter().map(TypedKey::into_inner).collect::<Vec<_>>(), [1]);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::lru::LruCache;
+use crate::stats::{AtomicCacheStats, CacheStats};
+use std::hash::Hash;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Thread-safe LRU cache whose entries carry a `u64` version, e.g. the height
+/// at which a value was read, so that readers can reject values older than
+/// the ones they already saw elsewhere with `get_at_least`.
+///
+/// Inserts never replace a value with an older version of it, so that a
+/// delayed write can't undo a more recent one.
+pub struct VersionedCache<K, V> {
+    inner: Mutex<Inner<K, V>>,
+    stats: AtomicCacheStats,
+}
+
+struct Inner<K, V> {
+    capacity: usize,
+    entries: LruCache<K, (V, u64)>,
+}
+
+impl<K, V> VersionedCache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0);
+        Self {
+            inner: Mutex::new(Inner { capacity, entries: LruCache::new() }),
+            stats: AtomicCacheStats::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the cached value, whatever its version.
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.get_at_least(key, 0)
+    }
+
+    /// Returns the cached value if its version is at least `min_version`.
+    /// Older values are counted as misses, but stay cached for readers with
+    /// lower requirements.
+    pub fn get_at_least(&self, key: &K, min_version: u64) -> Option<V> {
+        let mut inner = self.lock();
+        let is_fresh = match inner.entries.peek(key) {
+            Some(&(_, version)) => version >= min_version,
+            None => false,
+        };
+        if !is_fresh {
+            self.stats.record_miss(false);
+            return None;
+        }
+        self.stats.record_hit();
+        inner.entries.get(key).map(|(value, _)| value.clone())
+    }
+
+    /// Version of the cached value, without changing its recency or the
+    /// statistics.
+    pub fn version(&self, key: &K) -> Option<u64> {
+        self.lock().entries.peek(key).map(|&(_, version)| version)
+    }
+
+    /// Caches the value unless a newer version of it is cached already, and
+    /// returns whether it did. Evicts the least recently used entry if the
+    /// cache is over capacity.
+    pub fn insert(&self, key: K, value: V, version: u64) -> bool {
+        let mut inner = self.lock();
+        if let Some(&(_, cached)) = inner.entries.peek(&key) {
+            if cached > version {
+                return false;
+            }
+        }
+        inner.entries.put(key, (value, version));
+        while inner.entries.len() > inner.capacity {
+            inner.entries.pop_lru();
+            self.stats.record_eviction();
+        }
+        true
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.lock().entries.remove(key).map(|(value, _)| value)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats.snapshot()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<K, V>> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VersionedCache;
+
+    #[test]
+    fn test_get_at_least() {
+        let cache = VersionedCache::new(10);
+        assert!(cache.insert(1, "a", 5));
+        assert_eq!(cache.get_at_least(&1, 3), Some("a"));
+        assert_eq!(cache.get_at_least(&1, 5), Some("a"));
+        assert_eq!(cache.get_at_least(&1, 7), None);
+        // The stale value is still there for less demanding readers.
+        assert_eq!(cache.get(&1), Some("a"));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (3, 1));
+    }
+
+    #[test]
+    fn test_older_versions_are_ignored() {
+        let cache = VersionedCache::new(10);
+        assert!(cache.insert(1, "b", 7));
+        assert!(!cache.insert(1, "a", 5));
+        assert_eq!(cache.version(&1), Some(7));
+        assert!(cache.insert(1, "c", 7));
+        assert_eq!(cache.remove(&1), Some("c"));
+        assert!(cache.insert(1, "a", 5));
+        assert_eq!(cache.get(&1), Some("a"));
+    }
+}
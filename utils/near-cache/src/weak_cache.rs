@@ -0,0 +1,132 @@
+use crate::lru::LruCache;
+use crate::stats::{AtomicCacheStats, CacheStats};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+
+/// Thread-safe LRU cache of `Arc`s which holds only weak references to them,
+/// so that caching a value doesn't keep it alive: once everyone else dropped
+/// their `Arc`s the value is freed, and its entry is treated as missing.
+///
+/// Entries of freed values are dropped lazily when they are looked up, or all
+/// at once by `prune`. Until then they count towards the capacity.
+pub struct WeakCache<K, T> {
+    inner: Mutex<Inner<K, T>>,
+    stats: AtomicCacheStats,
+}
+
+struct Inner<K, T> {
+    capacity: usize,
+    entries: LruCache<K, Weak<T>>,
+}
+
+impl<K, T> WeakCache<K, T>
+where
+    K: Hash + Eq + Clone,
+{
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0);
+        Self {
+            inner: Mutex::new(Inner { capacity, entries: LruCache::new() }),
+            stats: AtomicCacheStats::default(),
+        }
+    }
+
+    /// Number of entries, including those of freed values which weren't
+    /// dropped yet.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the value if it is cached and still alive, dropping its entry
+    /// if it was freed.
+    pub fn get(&self, key: &K) -> Option<Arc<T>> {
+        let mut inner = self.lock();
+        let value = inner.entries.get(key).map(Weak::upgrade);
+        match value {
+            Some(Some(value)) => {
+                self.stats.record_hit();
+                Some(value)
+            }
+            Some(None) => {
+                inner.entries.remove(key);
+                self.stats.record_miss(false);
+                None
+            }
+            None => {
+                self.stats.record_miss(false);
+                None
+            }
+        }
+    }
+
+    /// Caches a weak reference to the value, evicting the least recently used
+    /// entry if the cache is over capacity.
+    pub fn insert(&self, key: K, value: &Arc<T>) {
+        let mut inner = self.lock();
+        inner.entries.put(key, Arc::downgrade(value));
+        while inner.entries.len() > inner.capacity {
+            inner.entries.pop_lru();
+            self.stats.record_eviction();
+        }
+    }
+
+    pub fn remove(&self, key: &K) -> Option<Arc<T>> {
+        self.lock().entries.remove(key).and_then(|value| value.upgrade())
+    }
+
+    /// Drops the entries of all freed values and returns their number.
+    pub fn prune(&self) -> usize {
+        self.lock().entries.drain_where(|_, value| value.strong_count() == 0).len()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats.snapshot()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<K, T>> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WeakCache;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_dropped_values_miss() {
+        let cache = WeakCache::new(10);
+        let block = Arc::new("block".to_string());
+        cache.insert(1, &block);
+        let cached = cache.get(&1).unwrap();
+        assert!(Arc::ptr_eq(&cached, &block));
+
+        // The cache doesn't keep the value alive.
+        drop((block, cached));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&1), None);
+        assert!(cache.is_empty());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+    }
+
+    #[test]
+    fn test_prune() {
+        let cache = WeakCache::new(10);
+        let values: Vec<_> = (0..4).map(Arc::new).collect();
+        for (key, value) in values.iter().enumerate() {
+            cache.insert(key, value);
+        }
+        let mut values = values.into_iter();
+        let kept: Vec<_> = values.by_ref().take(2).collect();
+        drop(values);
+        assert_eq!(cache.prune(), 2);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&1), Some(kept[1].clone()));
+        assert_eq!(cache.remove(&0), Some(kept[0].clone()));
+    }
+}
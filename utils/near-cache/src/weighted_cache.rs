@@ -0,0 +1,260 @@
+use crate::lru::LruCache;
+use crate::stats::{AtomicCacheStats, CacheStats};
+use std::hash::Hash;
+use std::mem::size_of;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::{fmt, io};
+
+/// Error returned when an entry can't be inserted into a `WeightedCache`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InsertError {
+    /// The entry alone weighs more than the whole cache may hold.
+    TooLarge { weight: usize, max_weight: usize },
+}
+
+impl fmt::Display for InsertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InsertError::TooLarge { weight, max_weight } => {
+                write!(f, "entry weighs {} which exceeds the cache limit of {}", weight, max_weight)
+            }
+        }
+    }
+}
+
+impl std::error::Error for InsertError {}
+
+/// Values which know how much memory they take, see
+/// `WeightedCache::with_memory_fraction`.
+pub trait MemSized {
+    /// Approximate number of bytes the value occupies, including the memory
+    /// it owns on the heap.
+    fn mem_size(&self) -> usize;
+}
+
+impl MemSized for Vec<u8> {
+    fn mem_size(&self) -> usize {
+        size_of::<Self>() + self.capacity()
+    }
+}
+
+impl MemSized for String {
+    fn mem_size(&self) -> usize {
+        size_of::<Self>() + self.capacity()
+    }
+}
+
+impl MemSized for Box<[u8]> {
+    fn mem_size(&self) -> usize {
+        size_of::<Self>() + self.len()
+    }
+}
+
+/// Thread-safe LRU cache bounded by the total weight of its values rather than
+/// by their number.
+///
+/// The weight of a value is computed by the `weigher` passed to `new` (e.g. its
+/// size in bytes). Least recently used entries are evicted until the total
+/// weight fits into `max_weight`. An entry which weighs more than `max_weight`
+/// on its own is rejected without evicting anything, since it would never fit.
+pub struct WeightedCache<K, V> {
+    inner: Mutex<Inner<K, V>>,
+    max_weight: usize,
+    weigher: Box<dyn Fn(&V) -> usize + Send + Sync>,
+    stats: AtomicCacheStats,
+}
+
+struct Inner<K, V> {
+    entries: LruCache<K, Entry<V>>,
+    total_weight: usize,
+}
+
+struct Entry<V> {
+    value: V,
+    weight: usize,
+}
+
+impl<K, V> WeightedCache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub fn new(max_weight: usize, weigher: impl Fn(&V) -> usize + Send + Sync + 'static) -> Self {
+        Self {
+            inner: Mutex::new(Inner { entries: LruCache::new(), total_weight: 0 }),
+            max_weight,
+            weigher: Box::new(weigher),
+            stats: AtomicCacheStats::default(),
+        }
+    }
+
+    /// Creates a cache which may hold values taking up to `fraction` of the
+    /// total memory of the system, weighing them by `MemSized::mem_size`. The
+    /// budget is computed once, later changes of the memory aren't followed.
+    /// Fails if the total memory can't be determined, which is only supported
+    /// on Linux.
+    pub fn with_memory_fraction(fraction: f64) -> io::Result<Self>
+    where
+        V: MemSized + 'static,
+    {
+        Ok(Self::with_memory_fraction_of(fraction, total_memory()?))
+    }
+
+    fn with_memory_fraction_of(fraction: f64, total_memory: u64) -> Self
+    where
+        V: MemSized + 'static,
+    {
+        assert!(fraction > 0.0 && fraction <= 1.0);
+        let max_weight = (total_memory as f64 * fraction) as usize;
+        Self::new(max_weight, V::mem_size)
+    }
+
+    pub fn max_weight(&self) -> usize {
+        self.max_weight
+    }
+
+    /// Total weight of the cached values.
+    pub fn weight(&self) -> usize {
+        self.lock().total_weight
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut inner = self.lock();
+        match inner.entries.get(key) {
+            Some(entry) => {
+                self.stats.record_hit();
+                Some(entry.value.clone())
+            }
+            None => {
+                self.stats.record_miss(false);
+                None
+            }
+        }
+    }
+
+    /// Inserts the value, evicting least recently used entries until the total
+    /// weight fits. Fails, leaving the cache untouched, if the value alone
+    /// weighs more than `max_weight`.
+    pub fn try_insert_weighted(&self, key: K, value: V) -> Result<(), InsertError> {
+        let weight = (self.weigher)(&value);
+        if weight > self.max_weight {
+            return Err(InsertError::TooLarge { weight, max_weight: self.max_weight });
+        }
+        let mut inner = self.lock();
+        if let Some(previous) = inner.entries.put(key, Entry { value, weight }) {
+            inner.total_weight -= previous.weight;
+        }
+        inner.total_weight += weight;
+        while inner.total_weight > self.max_weight {
+            let (_, evicted) = inner.entries.pop_lru().unwrap();
+            inner.total_weight -= evicted.weight;
+            self.stats.record_eviction();
+        }
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let mut inner = self.lock();
+        let entry = inner.entries.remove(key)?;
+        inner.total_weight -= entry.weight;
+        Some(entry.value)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats.snapshot()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<K, V>> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Total memory of the system in bytes, as reported by `/proc/meminfo`.
+#[cfg(target_os = "linux")]
+fn total_memory() -> io::Result<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo")?;
+    parse_mem_total(&meminfo)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no MemTotal in /proc/meminfo"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn total_memory() -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "total memory is only known on Linux"))
+}
+
+/// Parses the `MemTotal:       16318412 kB` line of `/proc/meminfo`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_mem_total(meminfo: &str) -> Option<u64> {
+    let line = meminfo.lines().find_map(|line| line.strip_prefix("MemTotal:"))?;
+    let kib = line.trim().strip_suffix("kB")?.trim().parse::<u64>().ok()?;
+    kib.checked_mul(1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_mem_total, InsertError, MemSized, WeightedCache};
+
+    fn cache() -> WeightedCache<u32, Vec<u8>> {
+        WeightedCache::new(10, |value: &Vec<u8>| value.len())
+    }
+
+    #[test]
+    fn test_evicts_by_weight() {
+        let cache = cache();
+        cache.try_insert_weighted(1, vec![0; 4]).unwrap();
+        cache.try_insert_weighted(2, vec![0; 4]).unwrap();
+        cache.get(&1);
+        cache.try_insert_weighted(3, vec![0; 5]).unwrap();
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.weight(), 9);
+
+        cache.try_insert_weighted(1, vec![0; 1]).unwrap();
+        assert_eq!(cache.weight(), 6);
+    }
+
+    #[test]
+    fn test_rejects_oversized_value() {
+        let cache = cache();
+        cache.try_insert_weighted(1, vec![0; 4]).unwrap();
+        cache.try_insert_weighted(2, vec![0; 4]).unwrap();
+        assert_eq!(
+            cache.try_insert_weighted(3, vec![0; 11]),
+            Err(InsertError::TooLarge { weight: 11, max_weight: 10 })
+        );
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.weight(), 8);
+        assert_eq!(cache.get(&1), Some(vec![0; 4]));
+        assert_eq!(cache.stats().evictions, 0);
+    }
+
+    #[test]
+    fn test_memory_fraction() {
+        let meminfo = "MemTotal:       16318412 kB\nMemFree:         1245152 kB\n";
+        assert_eq!(parse_mem_total(meminfo), Some(16318412 * 1024));
+        assert_eq!(parse_mem_total("MemFree: 1 kB\n"), None);
+
+        // Values of 50 bytes, two of which fit into 1% of 10000 bytes.
+        let value = || vec![0u8; 50 - std::mem::size_of::<Vec<u8>>()];
+        assert_eq!(value().mem_size(), 50);
+        let cache = WeightedCache::<u32, Vec<u8>>::with_memory_fraction_of(0.01, 10_000);
+        assert_eq!(cache.max_weight(), 100);
+        for key in 0..3 {
+            cache.try_insert_weighted(key, value()).unwrap();
+        }
+        assert_eq!(cache.get(&0), None);
+        assert_eq!((cache.len(), cache.weight()), (2, 100));
+        assert!(matches!(
+            cache.try_insert_weighted(3, vec![0; 100]),
+            Err(InsertError::TooLarge { max_weight: 100, .. })
+        ));
+    }
+}
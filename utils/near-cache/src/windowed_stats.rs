@@ -0,0 +1,66 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Lookups of a cache within one time window, see
+/// `SyncLruCache::with_windowed_stats`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WindowStats {
+    /// Beginning of the window; it ends where the next one begins.
+    pub start: Instant,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Hit and miss counts bucketed into consecutive fixed-size windows, of which
+/// only the last `max_windows` ones are kept.
+pub(crate) struct WindowedStats {
+    origin: Instant,
+    window: Duration,
+    max_windows: usize,
+    /// Consecutive windows, oldest first, together with their index counted
+    /// from `origin`.
+    windows: VecDeque<(u64, WindowStats)>,
+}
+
+impl WindowedStats {
+    pub fn new(origin: Instant, window: Duration, max_windows: usize) -> Self {
+        assert!(window > Duration::ZERO && max_windows > 0);
+        Self { origin, window, max_windows, windows: VecDeque::new() }
+    }
+
+    pub fn record(&mut self, now: Instant, hit: bool) {
+        let current = self.advance(now);
+        if hit {
+            current.hits += 1;
+        } else {
+            current.misses += 1;
+        }
+    }
+
+    /// Returns up to `n` of the most recent windows, oldest first, ending with
+    /// the (possibly empty) window containing `now`.
+    pub fn recent(&mut self, now: Instant, n: usize) -> Vec<WindowStats> {
+        self.advance(now);
+        let skip = self.windows.len().saturating_sub(n);
+        self.windows.iter().skip(skip).map(|(_, stats)| *stats).collect()
+    }
+
+    /// Appends empty windows up to the one containing `now` and returns it.
+    fn advance(&mut self, now: Instant) -> &mut WindowStats {
+        let index =
+            (now.saturating_duration_since(self.origin).as_nanos() / self.window.as_nanos()) as u64;
+        let next = match self.windows.back() {
+            Some(&(last, _)) if last >= index => return &mut self.windows.back_mut().unwrap().1,
+            Some(&(last, _)) => (last + 1).max(index.saturating_sub(self.max_windows as u64 - 1)),
+            None => index,
+        };
+        for index in next..=index {
+            let start = self.origin + Duration::from_nanos(self.window.as_nanos() as u64 * index);
+            self.windows.push_back((index, WindowStats { start, hits: 0, misses: 0 }));
+            if self.windows.len() > self.max_windows {
+                self.windows.pop_front();
+            }
+        }
+        &mut self.windows.back_mut().unwrap().1
+    }
+}
@@ -0,0 +1,225 @@
+use crate::lru::LruCache;
+use std::hash::Hash;
+use std::io;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Storage behind a `WriteBackCache`.
+pub trait WriteBackStore<K, V> {
+    /// Persists the entries, overwriting previous values of the same keys.
+    fn write(&self, entries: Vec<(K, V)>) -> io::Result<()>;
+}
+
+/// LRU cache which buffers writes and sends them to a `WriteBackStore` in
+/// batches: when `flush` is called, when a modified ("dirty") entry is evicted
+/// and, as a last resort, when the cache is dropped.
+///
+/// The final flush on drop is synchronous and its failure is only logged
+/// (with the `tracing` feature), since `Drop` can't report errors. Callers
+/// which need to handle the error (or whose store is asynchronous and can't
+/// be written from `drop`) should call `flush` explicitly before dropping the
+/// cache.
+pub struct WriteBackCache<K, V, S>
+where
+    K: Hash + Eq + Clone,
+    S: WriteBackStore<K, V>,
+{
+    inner: Mutex<Inner<K, V>>,
+    store: S,
+}
+
+struct Inner<K, V> {
+    capacity: usize,
+    entries: LruCache<K, Entry<V>>,
+}
+
+struct Entry<V> {
+    value: V,
+    dirty: bool,
+}
+
+impl<K, V, S> WriteBackCache<K, V, S>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+    S: WriteBackStore<K, V>,
+{
+    pub fn new(capacity: usize, store: S) -> Self {
+        assert!(capacity > 0);
+        Self { inner: Mutex::new(Inner { capacity, entries: LruCache::new() }), store }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.lock().entries.get(key).map(|entry| entry.value.clone())
+    }
+
+    /// Inserts the value, to be written to the store later. If there is no
+    /// room for a new key, the least recently used entry is evicted first,
+    /// and written to the store right away if it is dirty. If that write
+    /// fails, the error is returned and the value isn't inserted, while the
+    /// entry stays cached as the least recently used one, so that the cache
+    /// never exceeds its capacity and the next insert tries to write it again.
+    pub fn insert(&self, key: K, value: V) -> io::Result<()> {
+        let mut inner = self.lock();
+        if inner.entries.len() >= inner.capacity && !inner.entries.contains_key(&key) {
+            let (lru_key, entry) = inner.entries.peek_lru().unwrap();
+            if entry.dirty {
+                self.store.write(vec![(lru_key.clone(), entry.value.clone())])?;
+            }
+            inner.entries.pop_lru();
+        }
+        inner.entries.put(key, Entry { value, dirty: true });
+        Ok(())
+    }
+
+    /// Writes all dirty entries to the store in a single batch. The entries
+    /// stay dirty if the write fails.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut inner = self.lock();
+        let dirty: Vec<_> = inner
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(key, entry)| (key.clone(), entry.value.clone()))
+            .collect();
+        if dirty.is_empty() {
+            return Ok(());
+        }
+        let keys: Vec<_> = dirty.iter().map(|(key, _)| key.clone()).collect();
+        self.store.write(dirty)?;
+        for key in keys {
+            inner.entries.peek_mut(&key).unwrap().dirty = false;
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<K, V>> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<K, V, S> Drop for WriteBackCache<K, V, S>
+where
+    K: Hash + Eq + Clone,
+    S: WriteBackStore<K, V>,
+{
+    fn drop(&mut self) {
+        let inner = self.inner.get_mut().unwrap_or_else(PoisonError::into_inner);
+        let mut dirty = Vec::new();
+        while let Some((key, entry)) = inner.entries.pop_lru() {
+            if entry.dirty {
+                dirty.push((key, entry.value));
+            }
+        }
+        if dirty.is_empty() {
+            return;
+        }
+        if let Err(err) = self.store.write(dirty) {
+            #[cfg(feature = "tracing")]
+            tracing::error!(target: "near_cache", %err, "Failed to flush write-back cache on drop");
+            // Without the `tracing` feature there is nowhere to report it.
+            #[cfg(not(feature = "tracing"))]
+            let _ = err;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WriteBackCache, WriteBackStore};
+    use std::collections::HashMap;
+    use std::io;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct TestStore {
+        entries: Arc<Mutex<HashMap<u32, u32>>>,
+        writes: Arc<Mutex<usize>>,
+        fail: Arc<AtomicBool>,
+    }
+
+    impl WriteBackStore<u32, u32> for TestStore {
+        fn write(&self, entries: Vec<(u32, u32)>) -> io::Result<()> {
+            if self.fail.load(Ordering::Relaxed) {
+                return Err(io::Error::new(io::ErrorKind::Other, "store is down"));
+            }
+            *self.writes.lock().unwrap() += 1;
+            self.entries.lock().unwrap().extend(entries);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_flush_and_eviction() {
+        let store = TestStore::default();
+        let cache = WriteBackCache::new(2, store.clone());
+        cache.insert(1, 10).unwrap();
+        cache.insert(2, 20).unwrap();
+        assert!(store.entries.lock().unwrap().is_empty());
+        cache.flush().unwrap();
+        cache.flush().unwrap();
+        assert_eq!(*store.writes.lock().unwrap(), 1);
+
+        // Evicting clean entries doesn't write them again.
+        cache.insert(3, 30).unwrap();
+        cache.insert(4, 40).unwrap();
+        assert_eq!(*store.writes.lock().unwrap(), 1);
+        // Evicting a dirty one does.
+        cache.insert(5, 50).unwrap();
+        assert_eq!(*store.writes.lock().unwrap(), 2);
+        assert_eq!(store.entries.lock().unwrap().get(&3), Some(&30));
+    }
+
+    #[test]
+    fn test_failed_eviction_rejects_insert() {
+        let store = TestStore::default();
+        let cache = WriteBackCache::new(2, store.clone());
+        cache.insert(1, 10).unwrap();
+        cache.insert(2, 20).unwrap();
+        store.fail.store(true, Ordering::Relaxed);
+        for _ in 0..10 {
+            assert!(cache.insert(3, 30).is_err());
+        }
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&3), None);
+        // Keys which are cached already don't need room.
+        cache.insert(2, 21).unwrap();
+
+        // Key 1 is still the first to be evicted once the store is back.
+        store.fail.store(false, Ordering::Relaxed);
+        cache.insert(3, 30).unwrap();
+        let written = store.entries.lock().unwrap().clone();
+        assert_eq!(written, [(1, 10)].into_iter().collect());
+        assert_eq!(cache.get(&2), Some(21));
+        assert_eq!(cache.get(&3), Some(30));
+    }
+
+    #[test]
+    fn test_flush_on_drop() {
+        let store = TestStore::default();
+        let cache = WriteBackCache::new(10, store.clone());
+        cache.insert(1, 10).unwrap();
+        cache.insert(2, 20).unwrap();
+        drop(cache);
+        assert_eq!(*store.entries.lock().unwrap(), [(1, 10), (2, 20)].into_iter().collect());
+        assert_eq!(*store.writes.lock().unwrap(), 1);
+
+        let failing = TestStore { fail: Arc::new(AtomicBool::new(true)), ..TestStore::default() };
+        let cache = WriteBackCache::new(10, failing);
+        cache.insert(1, 10).unwrap();
+        // Doesn't panic.
+        drop(cache);
+    }
+}